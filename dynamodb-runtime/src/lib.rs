@@ -1,6 +1,6 @@
 use anyhow::Result;
 use log::{debug, info};
-use metashrew_runtime::{BatchLike, KeyValueStoreLike};
+use metashrew_runtime::{BatchLike, KeyValuePairs, KeyValueStoreLike};
 use redis::Commands;
 use std::sync::{Arc, Mutex};
 
@@ -43,6 +43,20 @@ pub fn to_labeled_key(key: &Vec<u8>) -> Vec<u8> {
     }
 }
 
+/// SCAN MATCH pattern selecting every key that starts with `prefix`, with
+/// glob metacharacters in the prefix escaped.
+pub fn scan_pattern(prefix: &[u8]) -> Vec<u8> {
+    let mut pattern: Vec<u8> = vec![];
+    for byte in prefix {
+        if matches!(*byte, b'*' | b'?' | b'[' | b']' | b'\\') {
+            pattern.push(b'\\');
+        }
+        pattern.push(*byte);
+    }
+    pattern.push(b'*');
+    pattern
+}
+
 pub async fn query_height(connection: &mut redis::Connection, start_block: u32) -> Result<u32> {
     let height_key = TIP_HEIGHT_KEY.as_bytes().to_vec();
     let bytes: Vec<u8> = match connection.get(&to_labeled_key(&height_key)) {
@@ -119,6 +133,9 @@ impl BatchLike for RedisBatch {
             .arg(to_redis_args(v))
             .ignore();
     }
+    fn delete<K: AsRef<[u8]>>(&mut self, k: K) {
+        self.0.cmd("DEL").arg(to_redis_key(k)).ignore();
+    }
 }
 
 impl Clone for RedisRuntimeAdapter {
@@ -132,7 +149,7 @@ impl KeyValueStoreLike for RedisRuntimeAdapter {
     type Error = redis::RedisError;
     fn write(&mut self, mut batch: RedisBatch) -> Result<(), Self::Error> {
         let key_bytes: Vec<u8> = TIP_HEIGHT_KEY.as_bytes().to_vec();
-        let height_bytes: Vec<u8> = (self.2 + 1).to_le_bytes().to_vec();
+        let height_bytes: Vec<u8> = self.2.wrapping_add(1).to_le_bytes().to_vec();
         /*
         let _ok: bool = connection
             .set(to_redis_args(&key_bytes), to_redis_args(&height_bytes))
@@ -213,4 +230,49 @@ impl KeyValueStoreLike for RedisRuntimeAdapter {
             self.reset_connection();
         }
     }
+    fn scan_prefix<K: AsRef<[u8]>>(&mut self, prefix: K) -> Result<KeyValuePairs, Self::Error> {
+        let pattern = scan_pattern(&to_labeled_key(&prefix.as_ref().to_vec()));
+        let label_len = to_labeled_key(&vec![]).len();
+        loop {
+            {
+                let mut connection = self.1.lock().unwrap();
+                let keys: Result<Vec<Vec<u8>>, _> = connection
+                    .scan_match::<Vec<u8>, Vec<u8>>(pattern.clone())
+                    .map(|iter| iter.collect());
+                match keys {
+                    Ok(mut keys) => {
+                        keys.sort();
+                        let mut result = Vec::with_capacity(keys.len());
+                        for key in keys {
+                            match connection.get::<Vec<u8>, Option<Vec<u8>>>(key.clone()) {
+                                Ok(Some(v)) => result.push((key[label_len..].to_vec(), v)),
+                                Ok(None) => {}
+                                Err(e) => return Err(e),
+                            }
+                        }
+                        return Ok(result);
+                    }
+                    Err(e) => {
+                        debug!("{:?}", e);
+                    }
+                }
+            }
+            self.reset_connection();
+        }
+    }
+    fn set_height(&mut self, height: u32) {
+        self.2 = height;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_glob_characters_in_scan_patterns() {
+        assert_eq!(scan_pattern(b"/balances/"), b"/balances/*".to_vec());
+        assert_eq!(scan_pattern(b"a*b?[c]\\"), b"a\\*b\\?\\[c\\]\\\\*".to_vec());
+        assert_eq!(scan_pattern(b""), b"*".to_vec());
+    }
 }
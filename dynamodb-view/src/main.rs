@@ -8,6 +8,7 @@ use metashrew_runtime::MetashrewRuntime;
 use std::fmt;
 //use rlp::Rlp;
 use anyhow;
+use futures::future::join_all;
 use log::{debug, info};
 use serde::{Deserialize, Serialize};
 use serde_json;
@@ -66,7 +67,8 @@ struct JsonRpcRequest {
 }
 #[derive(Serialize)]
 struct JsonRpcError {
-    id: u32,
+    /// `None` when the request's own id could not be read
+    id: Option<u32>,
     error: String,
     jsonrpc: String,
 }
@@ -84,6 +86,7 @@ struct Context {
     #[allow(dead_code)]
     program: Vec<u8>,
     runtime: MetashrewRuntime<RedisRuntimeAdapter>,
+    max_batch_size: usize,
 }
 
 static mut _HEIGHT: u32 = 0;
@@ -108,19 +111,70 @@ pub async fn fetch_and_set_height(internal_db: &RedisRuntimeAdapter) -> Result<u
 
 #[post("/")]
 async fn view(
-    body: web::Json<JsonRpcRequest>,
+    body: web::Json<serde_json::Value>,
     context: web::Data<Context>,
 ) -> Result<impl Responder> {
+    match body.into_inner() {
+        serde_json::Value::Array(requests) => {
+            if requests.is_empty() || requests.len() > context.max_batch_size {
+                let resp = JsonRpcError {
+                    id: None,
+                    error: format!(
+                        "Invalid request: batch must hold between 1 and {} requests, got {}",
+                        context.max_batch_size,
+                        requests.len()
+                    ),
+                    jsonrpc: "2.0".to_string(),
+                };
+                return Ok(HttpResponse::Ok().json(resp));
+            }
+            let responses =
+                join_all(requests.into_iter().map(|request| dispatch_value(request, &context))).await;
+            Ok(HttpResponse::Ok().json(responses))
+        }
+        request => Ok(HttpResponse::Ok().json(dispatch_value(request, &context).await)),
+    }
+}
+
+/// Runs one request of a single or batch call, reporting any failure as a
+/// JSON-RPC error.
+async fn dispatch_value(request: serde_json::Value, context: &Context) -> serde_json::Value {
+    let body: JsonRpcRequest = match serde_json::from_value(request) {
+        Ok(body) => body,
+        Err(e) => {
+            return rpc_value(JsonRpcError {
+                id: None,
+                error: format!("Invalid request: {}", e),
+                jsonrpc: "2.0".to_string(),
+            });
+        }
+    };
+    let id = body.id;
+    match dispatch(body, context).await {
+        Ok(response) => response,
+        Err(e) => rpc_value(JsonRpcError {
+            id: Some(id),
+            error: e.to_string(),
+            jsonrpc: "2.0".to_string(),
+        }),
+    }
+}
+
+fn rpc_value<T: Serialize>(response: T) -> serde_json::Value {
+    serde_json::to_value(response).unwrap_or(serde_json::Value::Null)
+}
+
+async fn dispatch(body: JsonRpcRequest, context: &Context) -> Result<serde_json::Value> {
     {
         debug!("{}", serde_json::to_string(&body).unwrap());
     }
     if body.method != "metashrew_view" {
         let resp = JsonRpcError {
-            id: body.id,
+            id: Some(body.id),
             error: "Unsupported method".to_string(),
             jsonrpc: "2.0".to_string(),
         };
-        return Ok(HttpResponse::Ok().json(resp));
+        return Ok(rpc_value(resp));
     } else {
         let height: u32 = if body.params[2] == "latest" {
             fetch_and_set_height(&context.runtime.context.lock().unwrap().db).await?
@@ -154,7 +208,7 @@ async fn view(
             error: err,
             jsonrpc: "2.0".to_string(),
         };
-        return Ok(HttpResponse::Ok().json(result));
+        return Ok(rpc_value(result));
     }
 }
 
@@ -187,6 +241,18 @@ async fn main() -> std::io::Result<()> {
         Ok(v) => v,
         Err(_) => "redis://localhost:7777".into(),
     };
+    let max_batch_size: usize = match env::var("MAX_BATCH_SIZE") {
+        Ok(v) => match v.parse::<usize>() {
+            Ok(size) if size > 0 => size,
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("MAX_BATCH_SIZE must be a positive integer, got {:?}", v),
+                ))
+            }
+        },
+        Err(_) => 100,
+    };
 
     HttpServer::new(move || {
         App::new()
@@ -214,6 +280,7 @@ async fn main() -> std::io::Result<()> {
                     ),
                 )
                 .unwrap(),
+                max_batch_size,
             }))
             .service(view)
     })
@@ -5,18 +5,14 @@ use aws_sdk_dynamodb::model::{AttributeValue, PutRequest, WriteRequest};
 use clap::{command, Parser};
 use env_logger;
 use hex;
-use itertools::Itertools;
 use log::debug;
+use metashrew_blocksource::{BlockSource, BlockSourceArgs};
 use metashrew_dynamodb_runtime::{query_height, set_label, DynamoDBRuntimeAdapter}; 
 use metashrew_runtime::KeyValueStoreLike;
 use metashrew_runtime::MetashrewRuntime;
-use reqwest::{Response, Url};
 use retry::{delay::Fixed, retry, OperationResult};
-use serde::{Deserialize, Serialize};
-use serde_json;
-use serde_json::{Number, Value};
 use std::path::PathBuf;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
 use task;
 use tokio;
 use tokio::time::{sleep, Duration};
@@ -36,146 +32,24 @@ struct Args {
     auth: Option<String>,
     #[arg(long)]
     label: Option<String>,
+    #[command(flatten)]
+    source: BlockSourceArgs,
 }
 
 const HEIGHT_TO_HASH: &'static str = "/__INTERNAL/height-to-hash/";
 
 static mut _HEIGHT: u32 = 0;
 
-#[derive(Serialize)]
-pub struct JsonRpcRequest<T> {
-    pub id: u32,
-    pub jsonrpc: String,
-    pub method: String,
-    pub params: Vec<T>,
-}
-
-#[derive(Deserialize)]
-pub struct JsonRpcResponse {
-    pub id: u32,
-    pub result: Option<String>,
-    pub error: Option<Value>,
-}
-
-#[derive(Deserialize)]
-pub struct BlockCountResponse {
-    pub id: u32,
-    pub result: Option<u32>,
-    pub error: Option<Value>,
-}
-
 pub struct MetashrewKeyDBSync {
     runtime: MetashrewRuntime<RedisRuntimeAdapter>,
     args: Args,
     start_block: u32,
+    source: Arc<dyn BlockSource>,
 }
 
 impl MetashrewKeyDBSync {
-    async fn post_once(&self, body: String) -> Result<Response, reqwest::Error> {
-        debug!("Making RPC call to node - {}", body);
-        let response = reqwest::Client::new()
-            .post(match self.args.auth.clone() {
-                Some(v) => {
-                    let mut url = Url::parse(self.args.daemon_rpc_url.as_str()).unwrap();
-                    let (username, password) = v.split(":").next_tuple().unwrap();
-                    url.set_username(username).unwrap();
-                    url.set_password(Some(password)).unwrap();
-                    println!("auth URL: {:?}", url);
-                    url
-                }
-                None => {
-                    println!(
-                        "no auth URL: {:?}",
-                        Url::parse(self.args.daemon_rpc_url.as_str()).unwrap()
-                    );
-                    Url::parse(self.args.daemon_rpc_url.as_str()).unwrap()
-                }
-            })
-            .header("Content-Type", "application/json")
-            .body(body)
-            .send()
-            .await;
-        return response;
-    }
-    async fn post(&self, body: String) -> Result<Response> {
-        let mut count = 0;
-        let mut response: Option<Response> = None;
-        loop {
-            match self.post_once(body.clone()).await {
-                Ok(v) => {
-                    response = Some(v);
-                    break;
-                }
-                Err(e) => {
-                    if count > 10 {
-                        return Err(e.into());
-                    } else {
-                        count = count + 1;
-                    }
-                    debug!("err: retrying POST");
-                    sleep(Duration::from_millis(3000)).await;
-                }
-            }
-        }
-        Ok(response.unwrap())
-    }
-    /*
-    async fn post_get_text(&self, body: String) -> Result<String, reqwest::Error> {
-        let response = reqwest::Client::new()
-            .post(match self.args.auth.clone() {
-                Some(v) => {
-                    let mut url = Url::parse((self.args.daemon_rpc_url.as_str())).unwrap();
-                    let (username, password) = self.args.auth.as_ref().unwrap().split(":").next_tuple().unwrap();
-                    url.set_username(username);
-                    url.set_password(Some(password));
-                    info!("url: {}", url);
-                    url
-                }
-                None => Url::parse(self.args.daemon_rpc_url.as_str()).unwrap(),
-            })
-            .body(body)
-            .send()
-            .await;
-        return response.unwrap().text().await;
-    }
-    */
-    /*
-    async fn fetch_blockcount_text(&self) {
-        let response = self
-            .post_get_text(serde_json::to_string(&JsonRpcRequest::<u32> {
-                id: SystemTime::now()
-                    .duration_since(UNIX_EPOCH).unwrap()
-                    .as_secs()
-                    .try_into().unwrap(),
-                jsonrpc: String::from("2.0"),
-                method: String::from("getblockcount"),
-                params: vec![],
-            }).unwrap())
-            .await.unwrap();
-          info!("blockcount response: {}", response);
-    }
-    */
     async fn fetch_blockcount(&self) -> Result<u32> {
-        let response = self
-            .post(serde_json::to_string(
-                &(JsonRpcRequest::<u32> {
-                    id: SystemTime::now()
-                        .duration_since(UNIX_EPOCH)?
-                        .as_secs()
-                        .try_into()?,
-                    jsonrpc: String::from("2.0"),
-                    method: String::from("getblockcount"),
-                    params: vec![],
-                }),
-            )?)
-            .await?;
-
-        Ok(response
-            .json::<BlockCountResponse>()
-            .await?
-            .result
-            .ok_or("")
-            .map_err(|_| anyhow!("missing result from JSON-RPC response"))?)
+        self.source.tip().await
     }
 
     pub async fn poll_connection(&self) -> redis::Connection {
@@ -207,25 +81,7 @@ impl MetashrewKeyDBSync {
     }
     async fn best_height(&self, block_number: u32) -> Result<u32> {
         let mut best: u32 = block_number;
-        let response = self
-            .post(serde_json::to_string(
-                &(JsonRpcRequest::<u32> {
-                    id: SystemTime::now()
-                        .duration_since(UNIX_EPOCH)?
-                        .as_secs()
-                        .try_into()?,
-                    jsonrpc: String::from("2.0"),
-                    method: String::from("getblockcount"),
-                    params: vec![],
-                }),
-            )?)
-            .await?;
-        let tip = response
-            .json::<BlockCountResponse>()
-            .await?
-            .result
-            .ok_or("")
-            .map_err(|_| anyhow!("missing result from JSON-RPC response"))?;
+        let tip = self.fetch_blockcount().await?;
         if best >= tip - std::cmp::min(6, tip) {
             loop {
                 if best == 0 {
@@ -282,26 +138,7 @@ impl MetashrewKeyDBSync {
     }
 
     async fn fetch_blockhash(&self, block_number: u32) -> Result<Vec<u8>, anyhow::Error> {
-        let response = self
-            .post(serde_json::to_string(
-                &(JsonRpcRequest::<u32> {
-                    id: SystemTime::now()
-                        .duration_since(UNIX_EPOCH)?
-                        .as_secs()
-                        .try_into()?,
-                    jsonrpc: String::from("2.0"),
-                    method: String::from("getblockhash"),
-                    params: vec![block_number],
-                }),
-            )?)
-            .await?;
-        let blockhash = response
-            .json::<JsonRpcResponse>()
-            .await?
-            .result
-            .ok_or("")
-            .map_err(|_| anyhow!("missing result from JSON-RPC response"))?;
-        Ok(hex::decode(&blockhash)?)
+        self.source.blockhash(block_number).await
     }
     fn put_once(&self, k: &Vec<u8>, v: &Vec<u8>) -> Result<()> {
         self.runtime
@@ -340,35 +177,14 @@ impl MetashrewKeyDBSync {
                 break;
             }
         }
-        let blockhash = self.fetch_blockhash(block_number).await.unwrap();
+        let (blockhash, block) = self.source.block_at(block_number).await?;
         self.poll_connection().await;
         self.put(
             &(String::from(HEIGHT_TO_HASH) + block_number.to_string().as_str()).into_bytes(),
             &blockhash,
         )
         .unwrap();
-        Ok(hex::decode(
-            self.post(serde_json::to_string(
-                &(JsonRpcRequest::<Value> {
-                    id: (<u64 as TryInto<i32>>::try_into(
-                        SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
-                    )? + 1)
-                        .try_into()?,
-                    jsonrpc: String::from("2.0"),
-                    method: String::from("getblock"),
-                    params: vec![
-                        Value::String(hex::encode(&blockhash)),
-                        Value::Number(Number::from(0)),
-                    ],
-                }),
-            )?)
-            .await?
-            .json::<JsonRpcResponse>()
-            .await?
-            .result
-            .ok_or("")
-            .map_err(|e| anyhow!("missing result from JSON-RPC response"))?,
-        )?)
+        Ok(block)
     }
     async fn run(&mut self) -> Result<()> {
         let mut i: u32 = self.query_height().await?;
@@ -405,11 +221,16 @@ async fn main() {
     let start_block = args.start_block.unwrap_or_else(|| 0);
     let indexer: PathBuf = args.indexer.clone().into();
     let redis_uri: String = args.redis.clone();
+    let source = args
+        .source
+        .build(&args.daemon_rpc_url, args.auth.as_deref())
+        .unwrap();
     let mut sync = MetashrewKeyDBSync {
         runtime: MetashrewRuntime::load(indexer, RedisRuntimeAdapter::open(redis_uri).unwrap())
             .unwrap(),
         args,
         start_block,
+        source,
     };
     //    sync.fetch_blockcount_text().await;
     sync.run().await.unwrap();
@@ -0,0 +1,377 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bitcoin::hashes::{sha256d, Hash};
+use log::{debug, info};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::BlockSource;
+
+const HEADER_SIZE: usize = 80;
+const XOR_KEY_FILE: &str = "xor.dat";
+
+type XorKey = [u8; 8];
+
+type Hash32 = [u8; 32];
+
+struct Location {
+    file: PathBuf,
+    offset: u64,
+    len: u32,
+}
+
+struct Entry {
+    prev: Hash32,
+    location: Location,
+}
+
+/// Everything learned from the blk files so far. Hashes are kept in internal
+/// byte order, as they appear in block headers.
+#[derive(Default)]
+struct Index {
+    /// Bytes of each file that have been scanned into `entries`
+    scanned: HashMap<PathBuf, u64>,
+    /// Obfuscation key from `xor.dat`, all zeros when the files are plain
+    xor: XorKey,
+    entries: HashMap<Hash32, Entry>,
+    /// Best chain, indexed by height
+    chain: Vec<Hash32>,
+}
+
+/// Reads blocks straight from a Bitcoin Core `blocks` directory.
+///
+/// Bitcoin Core writes blocks to `blkNNNNN.dat` in the order they were
+/// downloaded rather than by height, and keeps stale blocks alongside the
+/// main chain. The source therefore indexes every record header it finds and
+/// links them into a header chain, choosing the longest branch from genesis.
+/// Files are rescanned incrementally as the node appends to them.
+///
+/// Bitcoin Core 28 and later obfuscate block files with the 8 byte key in
+/// `xor.dat`, applied by file position. The key is read on every scan and
+/// removed from everything read back.
+pub struct BlkFileSource {
+    dir: PathBuf,
+    index: Arc<Mutex<Index>>,
+}
+
+impl BlkFileSource {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            index: Arc::new(Mutex::new(Index::default())),
+        }
+    }
+
+    /// Scans any blk file data appended since the last call and rebuilds the
+    /// best chain if new blocks were found.
+    async fn refresh(&self) -> Result<()> {
+        let dir = self.dir.clone();
+        let index = self.index.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut index = index.lock().map_err(|_| anyhow!("blk index lock poisoned"))?;
+            let before = index.entries.len();
+            index.xor = read_xor_key(&dir)?;
+            let xor = index.xor;
+            for file in blk_files(&dir)? {
+                let from = index.scanned.get(&file).copied().unwrap_or(0);
+                let to = scan_file(&file, from, &xor, &mut index.entries)?;
+                index.scanned.insert(file, to);
+            }
+            if index.entries.len() != before {
+                index.chain = best_chain(&index.entries);
+                info!(
+                    "indexed {} blocks from {:?}, best chain height {}",
+                    index.entries.len(),
+                    dir,
+                    index.chain.len() as i64 - 1
+                );
+            }
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Height of the best chain indexed so far, without rescanning.
+    pub fn indexed_height(&self) -> Option<u32> {
+        match self.index.lock().unwrap().chain.len() {
+            0 => None,
+            len => Some(len as u32 - 1),
+        }
+    }
+
+    /// Scans the blocks directory and returns the height of the best chain.
+    pub async fn scan(&self) -> Result<Option<u32>> {
+        self.refresh().await?;
+        Ok(self.indexed_height())
+    }
+
+    /// The block with the given display order hash if it has already been
+    /// indexed, without rescanning.
+    pub fn indexed_block(&self, blockhash: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.read(&internal_order(blockhash)?)
+    }
+
+    fn hash_at(&self, height: u32) -> Option<Hash32> {
+        self.index.lock().unwrap().chain.get(height as usize).copied()
+    }
+
+    fn read(&self, hash: &Hash32) -> Result<Option<Vec<u8>>> {
+        let index = self.index.lock().unwrap();
+        let location = match index.entries.get(hash) {
+            Some(entry) => &entry.location,
+            None => return Ok(None),
+        };
+        let mut file = File::open(&location.file)?;
+        file.seek(SeekFrom::Start(location.offset))?;
+        let mut block = vec![0u8; location.len as usize];
+        file.read_exact(&mut block)?;
+        deobfuscate(&mut block, location.offset, &index.xor);
+        Ok(Some(block))
+    }
+}
+
+fn blk_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| name.starts_with("blk") && name.ends_with(".dat"))
+                .unwrap_or(false)
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Reads the obfuscation key Bitcoin Core keeps in the blocks directory.
+/// Older nodes have no `xor.dat` and write plain files.
+fn read_xor_key(dir: &Path) -> Result<XorKey> {
+    match fs::read(dir.join(XOR_KEY_FILE)) {
+        Ok(bytes) => bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| anyhow!("{} must be 8 bytes, found {}", XOR_KEY_FILE, bytes.len())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok([0u8; 8]),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Removes the obfuscation from `buf`, which was read at `offset` in its file.
+fn deobfuscate(buf: &mut [u8], offset: u64, xor: &XorKey) {
+    if *xor == [0u8; 8] {
+        return;
+    }
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte ^= xor[((offset + i as u64) % 8) as usize];
+    }
+}
+
+/// Indexes the records of `path` starting at byte `from`. Each record is the
+/// network magic, a little endian length and the block. Returns the offset
+/// of the first record not yet fully written, where the next scan resumes.
+fn scan_file(
+    path: &Path,
+    from: u64,
+    xor: &XorKey,
+    entries: &mut HashMap<Hash32, Entry>,
+) -> Result<u64> {
+    let len = fs::metadata(path)?.len();
+    let mut reader = BufReader::new(File::open(path)?);
+    reader.seek(SeekFrom::Start(from))?;
+    let mut offset = from;
+    let mut prefix = [0u8; 8];
+    let mut header = [0u8; HEADER_SIZE];
+    while offset + 8 <= len {
+        reader.read_exact(&mut prefix)?;
+        // Bitcoin Core preallocates files with zeros ahead of the last block.
+        // The padding is never obfuscated, so check before removing the key.
+        if prefix[..4] == [0u8; 4] {
+            break;
+        }
+        deobfuscate(&mut prefix, offset, xor);
+        let size = u32::from_le_bytes(prefix[4..].try_into().unwrap());
+        if (size as usize) < HEADER_SIZE || offset + 8 + size as u64 > len {
+            break;
+        }
+        reader.read_exact(&mut header)?;
+        deobfuscate(&mut header, offset + 8, xor);
+        let hash = sha256d::Hash::hash(&header).to_byte_array();
+        entries.entry(hash).or_insert(Entry {
+            prev: header[4..36].try_into().unwrap(),
+            location: Location {
+                file: path.to_path_buf(),
+                offset: offset + 8,
+                len: size,
+            },
+        });
+        offset += 8 + size as u64;
+        reader.seek(SeekFrom::Start(offset))?;
+    }
+    debug!("scanned {:?} from {} to {}", path, from, offset);
+    Ok(offset)
+}
+
+/// Links the indexed headers and returns the longest chain from genesis.
+/// Stale branches in blk files are short, so length is a sound proxy for work.
+fn best_chain(entries: &HashMap<Hash32, Entry>) -> Vec<Hash32> {
+    let mut children: HashMap<Hash32, Vec<Hash32>> = HashMap::new();
+    for (hash, entry) in entries {
+        children.entry(entry.prev).or_default().push(*hash);
+    }
+    let mut best: Option<(usize, Hash32)> = None;
+    let mut stack: Vec<(usize, Hash32)> = children
+        .get(&[0u8; 32])
+        .map(|genesis| genesis.iter().map(|h| (0, *h)).collect())
+        .unwrap_or_default();
+    while let Some((height, hash)) = stack.pop() {
+        if best.map(|(h, _)| height > h).unwrap_or(true) {
+            best = Some((height, hash));
+        }
+        if let Some(next) = children.get(&hash) {
+            stack.extend(next.iter().map(|h| (height + 1, *h)));
+        }
+    }
+    let mut chain = Vec::new();
+    let mut cursor = best.map(|(_, hash)| hash);
+    while let Some(hash) = cursor {
+        chain.push(hash);
+        cursor = entries
+            .get(&hash)
+            .map(|entry| entry.prev)
+            .filter(|prev| *prev != [0u8; 32]);
+    }
+    chain.reverse();
+    chain
+}
+
+fn display_order(mut hash: Hash32) -> Vec<u8> {
+    hash.reverse();
+    hash.to_vec()
+}
+
+fn internal_order(blockhash: &[u8]) -> Result<Hash32> {
+    let mut hash: Hash32 = blockhash
+        .try_into()
+        .map_err(|_| anyhow!("invalid blockhash length {}", blockhash.len()))?;
+    hash.reverse();
+    Ok(hash)
+}
+
+#[async_trait]
+impl BlockSource for BlkFileSource {
+    async fn tip(&self) -> Result<u32> {
+        self.refresh().await?;
+        match self.index.lock().unwrap().chain.len() {
+            0 => Err(anyhow!("no blocks found in {:?}", self.dir)),
+            len => Ok(len as u32 - 1),
+        }
+    }
+
+    async fn blockhash(&self, height: u32) -> Result<Vec<u8>> {
+        if let Some(hash) = self.hash_at(height) {
+            return Ok(display_order(hash));
+        }
+        self.refresh().await?;
+        self.hash_at(height)
+            .map(display_order)
+            .ok_or_else(|| anyhow!("no block at height {} in {:?}", height, self.dir))
+    }
+
+    async fn block(&self, blockhash: &[u8]) -> Result<Vec<u8>> {
+        let hash = internal_order(blockhash)?;
+        if let Some(block) = self.read(&hash)? {
+            return Ok(block);
+        }
+        self.refresh().await?;
+        self.read(&hash)?
+            .ok_or_else(|| anyhow!("block {} not found in {:?}", hex::encode(blockhash), self.dir))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    const MAGIC: [u8; 4] = [0xf9, 0xbe, 0xb4, 0xd9];
+
+    /// A block with a minimal header over `prev`, distinguished by `nonce`,
+    /// and a few body bytes.
+    fn block(prev: &Hash32, nonce: u8) -> (Hash32, Vec<u8>) {
+        let mut data = vec![1, 0, 0, 0];
+        data.extend(prev);
+        data.extend([0u8; 44]);
+        data[76] = nonce;
+        let hash = sha256d::Hash::hash(&data[..HEADER_SIZE]).to_byte_array();
+        data.extend([nonce; 3]);
+        (hash, data)
+    }
+
+    fn write_records(path: &Path, blocks: &[&Vec<u8>], padding: usize) {
+        write_obfuscated(path, blocks, padding, &[0u8; 8]);
+    }
+
+    fn write_obfuscated(path: &Path, blocks: &[&Vec<u8>], padding: usize, xor: &XorKey) {
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(path).unwrap();
+        let mut records = Vec::new();
+        for data in blocks {
+            records.extend(MAGIC);
+            records.extend((data.len() as u32).to_le_bytes());
+            records.extend(data.iter());
+        }
+        deobfuscate(&mut records, file.metadata().unwrap().len(), xor);
+        file.write_all(&records).unwrap();
+        file.write_all(&vec![0u8; padding]).unwrap();
+    }
+
+    #[tokio::test]
+    async fn follows_longest_chain_across_out_of_order_files() {
+        let dir = TempDir::new("blk").unwrap();
+        let (g, genesis) = block(&[0u8; 32], 0);
+        let (a1, block_a1) = block(&g, 1);
+        let (a2, block_a2) = block(&a1, 2);
+        let (b1, block_b1) = block(&g, 3);
+        let (a3, block_a3) = block(&a2, 4);
+        write_records(&dir.path().join("blk00000.dat"), &[&genesis, &block_a2, &block_b1], 16);
+        write_records(&dir.path().join("blk00001.dat"), &[&block_a1], 0);
+
+        let source = BlkFileSource::new(dir.path().to_path_buf());
+        assert_eq!(source.tip().await.unwrap(), 2);
+        assert_eq!(source.blockhash(1).await.unwrap(), display_order(a1));
+        let (hash, data) = source.block_at(2).await.unwrap();
+        assert_eq!(hash, display_order(a2));
+        assert_eq!(data, block_a2);
+        assert_eq!(
+            source.block(&display_order(b1)).await.unwrap(),
+            block_b1
+        );
+
+        // Blocks appended by the node are picked up on the next lookup
+        write_records(&dir.path().join("blk00001.dat"), &[&block_a3], 0);
+        assert_eq!(source.blockhash(3).await.unwrap(), display_order(a3));
+        assert_eq!(source.tip().await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn reads_xor_obfuscated_files() {
+        let dir = TempDir::new("blk").unwrap();
+        let xor: XorKey = [0x5a, 0x01, 0xff, 0x80, 0x13, 0x37, 0x00, 0xc4];
+        fs::write(dir.path().join(XOR_KEY_FILE), xor).unwrap();
+        let (g, genesis) = block(&[0u8; 32], 0);
+        let (a1, block_a1) = block(&g, 1);
+        let (a2, block_a2) = block(&a1, 2);
+        let path = dir.path().join("blk00000.dat");
+        write_obfuscated(&path, &[&genesis, &block_a1], 0, &xor);
+        write_obfuscated(&path, &[&block_a2], 32, &xor);
+
+        let source = BlkFileSource::new(dir.path().to_path_buf());
+        assert_eq!(source.tip().await.unwrap(), 2);
+        assert_eq!(source.block_at(1).await.unwrap(), (display_order(a1), block_a1));
+        assert_eq!(source.block_at(2).await.unwrap(), (display_order(a2), block_a2));
+    }
+}
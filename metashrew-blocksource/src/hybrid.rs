@@ -0,0 +1,117 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use log::info;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::{BlkFileSource, BlockSource};
+
+/// Blocks this far below the tip of the blk file chain are served from disk.
+/// Anything closer may still be on a stale branch and goes to the node.
+pub const BLK_CONFIRMATIONS: u32 = 6;
+
+/// Initial sync from `blk*.dat` files, then the node's RPC at the tip.
+///
+/// Heights with at least [`BLK_CONFIRMATIONS`] blocks on top of them in the
+/// blk files are read from disk. The first height that is not switches the
+/// source over to `node` for good, so the files are no longer rescanned once
+/// the indexer has caught up. The tip always comes from `node`.
+pub struct HybridSource {
+    blk: BlkFileSource,
+    node: Arc<dyn BlockSource>,
+    switched: AtomicBool,
+}
+
+impl HybridSource {
+    pub fn new(blk: BlkFileSource, node: Arc<dyn BlockSource>) -> Self {
+        Self {
+            blk,
+            node,
+            switched: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether `height` should be read from the blk files, rescanning them
+    /// when the indexed chain is too short.
+    async fn serve_from_blk(&self, height: u32) -> Result<bool> {
+        if self.switched.load(Ordering::Relaxed) {
+            return Ok(false);
+        }
+        let covered = |tip: Option<u32>| {
+            tip.map(|tip| height.saturating_add(BLK_CONFIRMATIONS) <= tip)
+                .unwrap_or(false)
+        };
+        if covered(self.blk.indexed_height()) || covered(self.blk.scan().await?) {
+            return Ok(true);
+        }
+        if !self.switched.swap(true, Ordering::Relaxed) {
+            info!("reached the tip of the blk files at height {}, switching to the node", height);
+        }
+        Ok(false)
+    }
+}
+
+#[async_trait]
+impl BlockSource for HybridSource {
+    async fn tip(&self) -> Result<u32> {
+        self.node.tip().await
+    }
+
+    async fn blockhash(&self, height: u32) -> Result<Vec<u8>> {
+        if self.serve_from_blk(height).await? {
+            self.blk.blockhash(height).await
+        } else {
+            self.node.blockhash(height).await
+        }
+    }
+
+    async fn block(&self, blockhash: &[u8]) -> Result<Vec<u8>> {
+        if !self.switched.load(Ordering::Relaxed) {
+            if let Some(block) = self.blk.indexed_block(blockhash)? {
+                return Ok(block);
+            }
+        }
+        self.node.block(blockhash).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryBlockSource;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[tokio::test]
+    async fn switches_to_node_near_blk_tip() {
+        let dir = TempDir::new("hybrid").unwrap();
+        let node = Arc::new(MemoryBlockSource::new());
+        let mut records = Vec::new();
+        let mut prev = [0u8; 32];
+        for nonce in 0..10u8 {
+            let mut header = vec![1, 0, 0, 0];
+            header.extend(prev);
+            header.extend([nonce; 44]);
+            let mut hash = sha256d::Hash::hash(&header).to_byte_array();
+            prev = hash;
+            hash.reverse();
+            // The node's copy differs so the test can tell where it came from
+            node.push(hash.to_vec(), [header.clone(), vec![1]].concat());
+            records.extend([0xf9, 0xbe, 0xb4, 0xd9]);
+            records.extend((header.len() as u32).to_le_bytes());
+            records.extend(header);
+        }
+        fs::write(dir.path().join("blk00000.dat"), records).unwrap();
+
+        let source = HybridSource::new(BlkFileSource::new(dir.path().to_path_buf()), node.clone());
+        assert_eq!(source.tip().await.unwrap(), 9);
+        let (_, block) = source.block_at(3).await.unwrap();
+        assert_eq!(block.len(), 80);
+        let (hash, block) = source.block_at(4).await.unwrap();
+        assert_eq!(block.len(), 81);
+        assert_eq!(hash, node.blockhash(4).await.unwrap());
+        // Heights already served from disk now come from the node as well
+        assert_eq!(source.block_at(3).await.unwrap().1.len(), 81);
+    }
+}
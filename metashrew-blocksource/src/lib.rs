@@ -0,0 +1,108 @@
+//! Sources of raw blocks for Metashrew indexers.
+//!
+//! Every indexer binary fetches blocks through the [`BlockSource`] trait, so the
+//! same pipeline can follow Bitcoin Core over JSON-RPC or REST, read the node's
+//! `blk*.dat` files directly, sync from the files and then follow the node,
+//! or be driven by an in-memory chain in tests.
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use clap::ValueEnum;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+pub mod blk;
+pub mod hybrid;
+pub mod memory;
+pub mod notify;
+pub mod rest;
+pub mod rpc;
+
+pub use blk::BlkFileSource;
+pub use hybrid::HybridSource;
+pub use memory::MemoryBlockSource;
+pub use notify::BlockNotifier;
+pub use rest::RestSource;
+pub use rpc::JsonRpcSource;
+
+static RETRIES: AtomicU64 = AtomicU64::new(0);
+
+/// Requests retried by the network sources after a transport failure, across
+/// every source in the process.
+pub fn retries() -> u64 {
+    RETRIES.load(Ordering::Relaxed)
+}
+
+fn count_retry() {
+    RETRIES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// A view of a block chain that can be indexed by height.
+///
+/// Blockhashes are in RPC display order, the same byte order they are stored
+/// under `/__INTERNAL/height-to-hash/`. Blocks are consensus serialized.
+#[async_trait]
+pub trait BlockSource: Send + Sync {
+    /// Height of the best block known to the source.
+    async fn tip(&self) -> Result<u32>;
+    /// Hash of the block at `height` on the source's best chain.
+    async fn blockhash(&self, height: u32) -> Result<Vec<u8>>;
+    /// The serialized block with the given hash.
+    async fn block(&self, blockhash: &[u8]) -> Result<Vec<u8>>;
+    /// Hash and serialized block at `height`.
+    async fn block_at(&self, height: u32) -> Result<(Vec<u8>, Vec<u8>)> {
+        let blockhash = self.blockhash(height).await?;
+        let block = self.block(&blockhash).await?;
+        Ok((blockhash, block))
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockSourceKind {
+    /// Bitcoin Core JSON-RPC at --daemon-rpc-url
+    JsonRpc,
+    /// Bitcoin Core REST interface (bitcoind -rest)
+    Rest,
+    /// blk*.dat files in --blocks-dir
+    BlkFiles,
+}
+
+/// Command line options shared by every binary that pulls blocks.
+#[derive(clap::Args, Debug, Clone)]
+pub struct BlockSourceArgs {
+    #[arg(long, value_enum, default_value_t = BlockSourceKind::JsonRpc, help = "Where to fetch blocks from")]
+    pub block_source: BlockSourceKind,
+    #[arg(long, help = "Base URL of the Bitcoin Core REST interface (default: --daemon-rpc-url)")]
+    pub rest_url: Option<String>,
+    #[arg(
+        long,
+        help = "Bitcoin Core blocks directory containing blk*.dat files. With json-rpc or rest, blocks are read from here until the indexer nears the tip"
+    )]
+    pub blocks_dir: Option<PathBuf>,
+}
+
+impl BlockSourceArgs {
+    /// Builds the configured source. `daemon_rpc_url` and `auth` are the
+    /// binary's existing node connection options. A node source given
+    /// `--blocks-dir` syncs from the blk files first, see [`HybridSource`].
+    pub fn build(&self, daemon_rpc_url: &str, auth: Option<&str>) -> Result<Arc<dyn BlockSource>> {
+        let node: Arc<dyn BlockSource> = match self.block_source {
+            BlockSourceKind::JsonRpc => Arc::new(JsonRpcSource::new(daemon_rpc_url, auth)?),
+            BlockSourceKind::Rest => Arc::new(RestSource::new(
+                self.rest_url.as_deref().unwrap_or(daemon_rpc_url),
+            )?),
+            BlockSourceKind::BlkFiles => Arc::new(BlkFileSource::new(
+                self.blocks_dir
+                    .clone()
+                    .ok_or_else(|| anyhow!("--block-source blk-files requires --blocks-dir"))?,
+            )),
+        };
+        Ok(match (&self.blocks_dir, self.block_source) {
+            (Some(dir), BlockSourceKind::JsonRpc | BlockSourceKind::Rest) => {
+                Arc::new(HybridSource::new(BlkFileSource::new(dir.clone()), node))
+            }
+            _ => node,
+        })
+    }
+}
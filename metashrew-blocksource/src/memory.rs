@@ -0,0 +1,57 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::sync::Mutex;
+
+use crate::BlockSource;
+
+/// A chain held in memory, for tests. Blocks are `(hash, block)` pairs
+/// indexed by height, and can be truncated to simulate a reorg.
+#[derive(Default)]
+pub struct MemoryBlockSource {
+    chain: Mutex<Vec<(Vec<u8>, Vec<u8>)>>,
+}
+
+impl MemoryBlockSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a block at the next height.
+    pub fn push(&self, blockhash: Vec<u8>, block: Vec<u8>) {
+        self.chain.lock().unwrap().push((blockhash, block));
+    }
+
+    /// Drops every block above `height`.
+    pub fn truncate(&self, height: u32) {
+        self.chain.lock().unwrap().truncate(height as usize + 1);
+    }
+}
+
+#[async_trait]
+impl BlockSource for MemoryBlockSource {
+    async fn tip(&self) -> Result<u32> {
+        match self.chain.lock().unwrap().len() {
+            0 => Err(anyhow!("no blocks")),
+            len => Ok(len as u32 - 1),
+        }
+    }
+
+    async fn blockhash(&self, height: u32) -> Result<Vec<u8>> {
+        self.chain
+            .lock()
+            .unwrap()
+            .get(height as usize)
+            .map(|(blockhash, _)| blockhash.clone())
+            .ok_or_else(|| anyhow!("no block at height {}", height))
+    }
+
+    async fn block(&self, blockhash: &[u8]) -> Result<Vec<u8>> {
+        self.chain
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(h, _)| h == blockhash)
+            .map(|(_, block)| block.clone())
+            .ok_or_else(|| anyhow!("unknown block {}", hex::encode(blockhash)))
+    }
+}
@@ -0,0 +1,129 @@
+use log::{debug, info, warn};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use zeromq::{Socket, SocketRecv, SubSocket};
+
+const HASHBLOCK_TOPIC: &str = "hashblock";
+
+/// Reconnect when the subscription has been silent this long, in case the
+/// node restarted and the connection went stale without an error.
+const RESUBSCRIBE_AFTER: Duration = Duration::from_secs(30 * 60);
+
+/// Wakes a caught-up indexer when the node announces a new block.
+///
+/// Waiting always falls back to polling, so a notifier with no stream
+/// attached, or one whose stream has dropped, behaves like the plain poll
+/// loop. Any stream of new-block events can feed it through [`notify`];
+/// [`zmq`] subscribes to Bitcoin Core's `zmqpubhashblock`.
+///
+/// [`notify`]: BlockNotifier::notify
+/// [`zmq`]: BlockNotifier::zmq
+#[derive(Clone, Default)]
+pub struct BlockNotifier {
+    notify: Arc<Notify>,
+}
+
+impl BlockNotifier {
+    /// A notifier that only polls.
+    pub fn polling() -> Self {
+        Self::default()
+    }
+
+    /// Subscribes to `hashblock` messages published at `endpoint`, such as
+    /// `tcp://127.0.0.1:28332`. The subscription runs in the background and
+    /// reconnects on its own.
+    pub fn zmq(endpoint: String) -> Self {
+        let notifier = Self::default();
+        let feed = notifier.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = feed.subscribe(&endpoint).await {
+                    warn!("ZMQ subscription to {} failed: {}, polling until it recovers", endpoint, e);
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+            }
+        });
+        notifier
+    }
+
+    async fn subscribe(&self, endpoint: &str) -> anyhow::Result<()> {
+        let mut socket = SubSocket::new();
+        socket.connect(endpoint).await?;
+        socket.subscribe(HASHBLOCK_TOPIC).await?;
+        info!("subscribed to {} at {}", HASHBLOCK_TOPIC, endpoint);
+        loop {
+            let message = match tokio::time::timeout(RESUBSCRIBE_AFTER, socket.recv()).await {
+                Ok(message) => message?,
+                Err(_) => {
+                    debug!("no {} from {} in {:?}, reconnecting", HASHBLOCK_TOPIC, endpoint, RESUBSCRIBE_AFTER);
+                    return Ok(());
+                }
+            };
+            // Frames are the topic, the blockhash and a sequence number
+            if message.get(0).map(|topic| topic.as_ref()) == Some(HASHBLOCK_TOPIC.as_bytes()) {
+                if let Some(blockhash) = message.get(1) {
+                    debug!("notified of block {}", hex::encode(blockhash));
+                }
+                self.notify();
+            }
+        }
+    }
+
+    /// Signals that a new block is available, waking every waiter. A signal
+    /// sent while nobody is waiting is kept for the next
+    /// [`wait`](BlockNotifier::wait).
+    pub fn notify(&self) {
+        self.notify.notify_waiters();
+        self.notify.notify_one();
+    }
+
+    /// Returns on the next notification, or after `poll` at the latest.
+    pub async fn wait(&self, poll: Duration) {
+        let _ = tokio::time::timeout(poll, self.notify.notified()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use zeromq::{PubSocket, SocketSend, ZmqMessage};
+
+    #[tokio::test]
+    async fn wakes_on_published_hashblock() {
+        let mut publisher = PubSocket::new();
+        let endpoint = publisher.bind("tcp://127.0.0.1:0").await.unwrap();
+        let notifier = BlockNotifier::zmq(endpoint.to_string());
+
+        // Subscriptions propagate asynchronously, so publish until one lands
+        let start = Instant::now();
+        let mut woke = false;
+        while start.elapsed() < Duration::from_secs(10) {
+            let mut message = ZmqMessage::from(HASHBLOCK_TOPIC);
+            message.push_back(vec![0u8; 32].into());
+            message.push_back(1u32.to_le_bytes().to_vec().into());
+            publisher.send(message).await.unwrap();
+            let waited = Instant::now();
+            notifier.wait(Duration::from_secs(1)).await;
+            if waited.elapsed() < Duration::from_millis(900) {
+                woke = true;
+                break;
+            }
+        }
+        assert!(woke, "no notification within 10s");
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_polling() {
+        let notifier = BlockNotifier::polling();
+        let start = Instant::now();
+        notifier.wait(Duration::from_millis(50)).await;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        notifier.notify();
+        let start = Instant::now();
+        notifier.wait(Duration::from_secs(10)).await;
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
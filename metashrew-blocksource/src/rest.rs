@@ -0,0 +1,84 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::debug;
+use reqwest::{Client, Response, Url};
+use serde_json::Value;
+use std::time::Duration;
+
+use crate::BlockSource;
+
+const MAX_RETRIES: u32 = 10;
+
+/// Bitcoin Core's unauthenticated REST interface, enabled with `-rest`.
+/// Blocks are fetched in binary, avoiding the hex round trip of `getblock`.
+pub struct RestSource {
+    client: Client,
+    base: Url,
+}
+
+impl RestSource {
+    pub fn new(url: &str) -> Result<Self> {
+        let mut base = Url::parse(url).map_err(|e| anyhow!("Invalid URL: {}", e))?;
+        // REST ignores credentials, and a leftover RPC path would break joins
+        let _ = base.set_username("");
+        let _ = base.set_password(None);
+        base.set_path("/rest/");
+        Ok(Self {
+            client: Client::new(),
+            base,
+        })
+    }
+
+    async fn get(&self, path: &str) -> Result<Response> {
+        let url = self.base.join(path)?;
+        let mut attempt = 0;
+        loop {
+            match self.client.get(url.clone()).send().await {
+                Ok(response) => {
+                    let status = response.status();
+                    if !status.is_success() {
+                        return Err(anyhow!("GET {} returned {}", url, status));
+                    }
+                    return Ok(response);
+                }
+                Err(e) => {
+                    if attempt == MAX_RETRIES {
+                        return Err(anyhow!("Max retries exceeded: {}", e));
+                    }
+                    debug!("GET {} failed (attempt {}): {}", url, attempt + 1, e);
+                    tokio::time::sleep(Duration::from_millis(100 << attempt.min(8))).await;
+                    attempt += 1;
+                    crate::count_retry();
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl BlockSource for RestSource {
+    async fn tip(&self) -> Result<u32> {
+        let info: Value = self.get("chaininfo.json").await?.json().await?;
+        Ok(info["blocks"]
+            .as_u64()
+            .ok_or_else(|| anyhow!("missing blocks in chaininfo"))? as u32)
+    }
+
+    async fn blockhash(&self, height: u32) -> Result<Vec<u8>> {
+        let text = self
+            .get(&format!("blockhashbyheight/{}.hex", height))
+            .await?
+            .text()
+            .await?;
+        Ok(hex::decode(text.trim())?)
+    }
+
+    async fn block(&self, blockhash: &[u8]) -> Result<Vec<u8>> {
+        Ok(self
+            .get(&format!("block/{}.bin", hex::encode(blockhash)))
+            .await?
+            .bytes()
+            .await?
+            .to_vec())
+    }
+}
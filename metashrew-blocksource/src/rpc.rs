@@ -0,0 +1,116 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use itertools::Itertools;
+use log::debug;
+use rand::Rng;
+use reqwest::{Client, Url};
+use serde_json::{json, Value};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::BlockSource;
+
+const MAX_RETRIES: u32 = 10;
+
+/// Bitcoin Core JSON-RPC, using `getblockcount`, `getblockhash` and
+/// `getblock` with verbosity 0.
+pub struct JsonRpcSource {
+    client: Client,
+    url: Url,
+}
+
+impl JsonRpcSource {
+    /// `auth` is `username:password`, applied as URL credentials.
+    pub fn new(url: &str, auth: Option<&str>) -> Result<Self> {
+        let mut url = Url::parse(url).map_err(|e| anyhow!("Invalid URL: {}", e))?;
+        if let Some(auth) = auth {
+            let (username, password) = auth
+                .split(':')
+                .next_tuple()
+                .ok_or_else(|| anyhow!("Invalid auth format, expected username:password"))?;
+            url.set_username(username)
+                .map_err(|_| anyhow!("Failed to set username"))?;
+            url.set_password(Some(password))
+                .map_err(|_| anyhow!("Failed to set password"))?;
+        }
+        Ok(Self {
+            client: Client::new(),
+            url,
+        })
+    }
+
+    /// Calls `method`, retrying transport failures with exponential backoff
+    /// and jitter, and returns the `result` field.
+    pub async fn call(&self, method: &str, params: Value) -> Result<Value> {
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u32,
+            "method": method,
+            "params": params,
+        });
+        let mut retry_delay = Duration::from_millis(100);
+        let max_delay = Duration::from_secs(30);
+        let mut attempt = 0;
+        let response: Value = loop {
+            let sent = self
+                .client
+                .post(self.url.clone())
+                .header("Content-Type", "application/json")
+                .json(&body)
+                .send()
+                .await;
+            match sent {
+                Ok(response) => break response.json().await?,
+                Err(e) => {
+                    if attempt == MAX_RETRIES {
+                        return Err(anyhow!("Max retries exceeded: {}", e));
+                    }
+                    let jitter = rand::thread_rng().gen_range(0..=100) as u64;
+                    retry_delay =
+                        std::cmp::min(max_delay, retry_delay * 2 + Duration::from_millis(jitter));
+                    debug!(
+                        "Request failed (attempt {}): {}, retrying in {:?}",
+                        attempt + 1,
+                        e,
+                        retry_delay
+                    );
+                    tokio::time::sleep(retry_delay).await;
+                    attempt += 1;
+                    crate::count_retry();
+                }
+            }
+        };
+        if !response["error"].is_null() {
+            return Err(anyhow!("{} failed: {}", method, response["error"]));
+        }
+        Ok(response["result"].clone())
+    }
+}
+
+#[async_trait]
+impl BlockSource for JsonRpcSource {
+    async fn tip(&self) -> Result<u32> {
+        Ok(self
+            .call("getblockcount", json!([]))
+            .await?
+            .as_u64()
+            .ok_or_else(|| anyhow!("missing result from JSON-RPC response"))? as u32)
+    }
+
+    async fn blockhash(&self, height: u32) -> Result<Vec<u8>> {
+        let result = self.call("getblockhash", json!([height])).await?;
+        let blockhash = result
+            .as_str()
+            .ok_or_else(|| anyhow!("missing result from JSON-RPC response"))?;
+        Ok(hex::decode(blockhash)?)
+    }
+
+    async fn block(&self, blockhash: &[u8]) -> Result<Vec<u8>> {
+        let result = self
+            .call("getblock", json!([hex::encode(blockhash), 0]))
+            .await?;
+        let block_hex = result
+            .as_str()
+            .ok_or_else(|| anyhow!("missing result from JSON-RPC response"))?;
+        Ok(hex::decode(block_hex)?)
+    }
+}
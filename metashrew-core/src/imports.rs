@@ -22,6 +22,9 @@ extern "C" {
     pub fn __get_len(ptr: i32) -> i32;
     pub fn __load_input(ptr: i32);
     pub fn __log(ptr: i32);
+    pub fn __scan_prefix(ptr: i32) -> i32;
+    pub fn __iter_next_len(handle: i32) -> i32;
+    pub fn __iter_next(handle: i32, v: i32);
 }
 
 #[allow(static_mut_refs)]
@@ -58,6 +61,19 @@ pub fn __flush(_ptr: i32) -> () {}
 #[cfg(feature = "test-utils")]
 pub fn __get(_ptr: i32, _result: i32) -> () {}
 
+#[cfg(feature = "test-utils")]
+pub fn __scan_prefix(_ptr: i32) -> i32 {
+    0
+}
+
+#[cfg(feature = "test-utils")]
+pub fn __iter_next_len(_handle: i32) -> i32 {
+    0
+}
+
+#[cfg(feature = "test-utils")]
+pub fn __iter_next(_handle: i32, _result: i32) -> () {}
+
 #[cfg(feature = "test-utils")]
 #[wasm_bindgen(js_namespace = Date)]
 extern "C" {
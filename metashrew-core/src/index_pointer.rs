@@ -1,4 +1,4 @@
-use crate::{get, set};
+use crate::{get, scan_prefix, set, PrefixIterator};
 use metashrew_support::index_pointer::KeyValuePointer;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
@@ -23,6 +23,13 @@ impl KeyValuePointer for IndexPointer {
     }
 }
 
+impl IndexPointer {
+    /// Iterates every key/value pair stored under this pointer's key.
+    pub fn scan_prefix(&self) -> PrefixIterator {
+        scan_prefix(self.unwrap())
+    }
+}
+
 #[derive(Clone, Default, Debug)]
 pub struct IndexCheckpoint(pub HashMap<Arc<Vec<u8>>, Arc<Vec<u8>>>);
 
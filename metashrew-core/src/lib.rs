@@ -18,7 +18,10 @@ pub mod tests;
 
 #[cfg(feature = "panic-hook")]
 use crate::compat::panic_hook;
-use crate::imports::{__flush, __get, __get_len, __host_len, __load_input};
+use crate::imports::{
+    __flush, __get, __get_len, __host_len, __iter_next, __iter_next_len, __load_input,
+    __scan_prefix,
+};
 pub use crate::stdio::stdout;
 #[allow(unused_imports)]
 use metashrew_support::{proto::metashrew::{KeyValueFlush, IndexerMetadata, ViewFunction}, compat::{to_arraybuffer_layout, to_passback_ptr, to_ptr}};
@@ -61,6 +64,105 @@ pub fn set(k: Arc<Vec<u8>>, v: Arc<Vec<u8>>) {
     }
 }
 
+pub type ScanEntry = (Arc<Vec<u8>>, Arc<Vec<u8>>);
+
+/// Iterates the key/value pairs stored under a prefix as of the current height.
+///
+/// Entries come from the host in key order, with values shadowed by anything
+/// set in this block but not yet flushed. Keys that only exist in the local
+/// cache are merged in at their place in key order.
+pub struct PrefixIterator {
+    handle: i32,
+    host_done: bool,
+    /// Next host entry, held back while cached keys sort before it
+    host_next: Option<ScanEntry>,
+    /// Keys under the prefix set in this block, in reverse key order
+    local: Vec<Arc<Vec<u8>>>,
+}
+
+#[allow(static_mut_refs)]
+pub fn scan_prefix(prefix: Arc<Vec<u8>>) -> PrefixIterator {
+    initialize();
+    let handle = unsafe { __scan_prefix(to_passback_ptr(&mut to_arraybuffer_layout(prefix.as_ref()))) };
+    let mut local: Vec<Arc<Vec<u8>>> = unsafe {
+        TO_FLUSH
+            .as_ref()
+            .unwrap()
+            .iter()
+            .filter(|k| k.starts_with(prefix.as_ref()))
+            .cloned()
+            .collect()
+    };
+    local.sort();
+    local.dedup();
+    local.reverse();
+    PrefixIterator {
+        handle,
+        host_done: handle == i32::MAX,
+        host_next: None,
+        local,
+    }
+}
+
+impl PrefixIterator {
+    #[allow(static_mut_refs)]
+    fn next_from_host(&mut self) -> Option<ScanEntry> {
+        let length: i32 = unsafe { __iter_next_len(self.handle) };
+        if length == 0 || length == i32::MAX {
+            self.host_done = true;
+            return None;
+        }
+        let mut buffer = Vec::<u8>::new();
+        buffer.extend_from_slice(&length.to_le_bytes());
+        buffer.resize((length as usize) + 4, 0);
+        unsafe { __iter_next(self.handle, to_passback_ptr(&mut buffer)) };
+        let entry = &buffer[4..];
+        let key_len = u32::from_le_bytes(entry[0..4].try_into().unwrap()) as usize;
+        let key = Arc::new(entry[4..4 + key_len].to_vec());
+        let value = unsafe {
+            match CACHE.as_ref().unwrap().get(&key) {
+                Some(cached) => cached.clone(),
+                None => Arc::new(entry[4 + key_len..].to_vec()),
+            }
+        };
+        Some((key, value))
+    }
+
+    fn next_from_cache(&mut self) -> Option<ScanEntry> {
+        let key = self.local.pop()?;
+        let value = get_cache().get(&key).unwrap().clone();
+        Some((key, value))
+    }
+}
+
+impl Iterator for PrefixIterator {
+    type Item = ScanEntry;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.host_next.is_none() && !self.host_done {
+                self.host_next = self.next_from_host();
+            }
+            let item = match (&self.host_next, self.local.last()) {
+                (Some((host_key, _)), Some(local_key)) if local_key < host_key => self.next_from_cache(),
+                (Some((host_key, _)), Some(local_key)) => {
+                    // The host entry already carries the cached value
+                    if local_key == host_key {
+                        self.local.pop();
+                    }
+                    self.host_next.take()
+                }
+                (Some(_), None) => self.host_next.take(),
+                (None, _) => self.next_from_cache(),
+            };
+            match item {
+                // Values cleared in this block are treated as absent, matching the host
+                Some((_, ref value)) if value.is_empty() => continue,
+                other => return other,
+            }
+        }
+    }
+}
+
 #[allow(static_mut_refs)]
 pub fn flush() {
     unsafe {
@@ -3,5 +3,6 @@ pub mod block;
 pub mod byte_view;
 pub mod compat;
 pub mod index_pointer;
+pub mod proof;
 pub mod utils;
 pub mod proto;
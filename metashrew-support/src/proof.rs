@@ -0,0 +1,95 @@
+//! Verification of key proofs against a metashrew state tree root.
+//!
+//! The state tree is a sparse Merkle tree over `sha256(key)`, read from the
+//! most significant bit down. An empty subtree hashes to [`EMPTY`] and a
+//! subtree holding a single key is replaced by that key's leaf, so a path
+//! ends at the first empty subtree or leaf it reaches.
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+
+/// Hash of an empty subtree.
+pub const EMPTY: [u8; 32] = [0; 32];
+
+/// Depth of the tree, in bits of the key path.
+pub const DEPTH: usize = 256;
+
+const LEAF_TAG: u8 = 0x00;
+const NODE_TAG: u8 = 0x01;
+
+/// Path of `key` through the tree.
+pub fn key_path(key: &[u8]) -> [u8; 32] {
+    sha256::Hash::hash(key).to_byte_array()
+}
+
+/// Hash committed to for a value.
+pub fn value_hash(value: &[u8]) -> [u8; 32] {
+    sha256::Hash::hash(value).to_byte_array()
+}
+
+/// Hash of the leaf for the key at `path` holding a value with `value_hash`.
+pub fn leaf_hash(path: &[u8; 32], value_hash: &[u8; 32]) -> [u8; 32] {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&[LEAF_TAG]);
+    engine.input(path);
+    engine.input(value_hash);
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+/// Hash of an internal node over its two children.
+pub fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&[NODE_TAG]);
+    engine.input(left);
+    engine.input(right);
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+/// Whether `path` takes the right branch below the node at `depth`.
+pub fn path_bit(path: &[u8; 32], depth: usize) -> bool {
+    path[depth / 8] & (0x80 >> (depth % 8)) != 0
+}
+
+/// Proof that a key holds a value, or holds none, under some root.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyProof {
+    /// Hashes of the siblings along the key's path, from the root down to
+    /// where the path ends.
+    pub siblings: Vec<[u8; 32]>,
+    /// Leaf the path ends at, as its key path and value hash, or `None` when
+    /// it ends at an empty subtree. For an absent key this is the leaf of
+    /// another key sharing the path so far.
+    pub leaf: Option<([u8; 32], [u8; 32])>,
+}
+
+/// Checks that `proof` shows `key` holding `value` under `root`, or holding
+/// nothing when `value` is `None`.
+pub fn verify_proof(root: &[u8; 32], key: &[u8], value: Option<&[u8]>, proof: &KeyProof) -> bool {
+    let depth = proof.siblings.len();
+    if depth > DEPTH {
+        return false;
+    }
+    let path = key_path(key);
+    let mut hash = match (value, &proof.leaf) {
+        (Some(value), Some((leaf_path, leaf_value))) => {
+            if *leaf_path != path || *leaf_value != value_hash(value) {
+                return false;
+            }
+            leaf_hash(leaf_path, leaf_value)
+        }
+        (Some(_), None) => return false,
+        (None, None) => EMPTY,
+        (None, Some((leaf_path, leaf_value))) => {
+            if *leaf_path == path || (0..depth).any(|d| path_bit(leaf_path, d) != path_bit(&path, d)) {
+                return false;
+            }
+            leaf_hash(leaf_path, leaf_value)
+        }
+    };
+    for (d, sibling) in proof.siblings.iter().enumerate().rev() {
+        hash = if path_bit(&path, d) {
+            node_hash(sibling, &hash)
+        } else {
+            node_hash(&hash, sibling)
+        };
+    }
+    hash == *root
+}
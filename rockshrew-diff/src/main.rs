@@ -2,17 +2,17 @@ use anyhow::{anyhow, Result};
 use clap::{command, Parser};
 use env_logger;
 use hex;
-use itertools::Itertools;
 use log::{debug, error, info};
-use metashrew_runtime::MetashrewRuntime;
-use rand::Rng;
-use rockshrew_runtime::{set_label, RocksDBRuntimeAdapter};
+use metashrew_blocksource::{BlockSource, BlockSourceArgs};
+use metashrew_runtime::{KeyValueStoreLike, MetashrewRuntime};
+use rockshrew_runtime::{check_storage_version, migrate_storage, set_label, RocksDBRuntimeAdapter};
 use rocksdb::Options;
 use std::collections::HashSet;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU32, Ordering};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio;
 use tokio::sync::{mpsc, Mutex};
 use tokio::time::sleep;
@@ -50,6 +50,14 @@ struct Args {
     // Pipeline configuration
     #[arg(long, default_value_t = 5)]
     pipeline_size: usize,
+    
+    #[command(flatten)]
+    source: BlockSourceArgs,
+
+    /// Only warn when either indexer differs from the WASM its database
+    /// was indexed with
+    #[arg(long)]
+    allow_wasm_mismatch: bool,
 }
 
 const HEIGHT_TO_HASH: &'static str = "/__INTERNAL/height-to-hash/";
@@ -101,6 +109,7 @@ struct RockshrewDiffRuntime {
     prefix: Vec<u8>,
     primary_tracker: KeyTracker,
     compare_tracker: KeyTracker,
+    source: Arc<dyn BlockSource>,
 }
 
 impl RockshrewDiffRuntime {
@@ -110,6 +119,7 @@ impl RockshrewDiffRuntime {
         args: Args,
         start_block: u32,
         prefix: Vec<u8>,
+        source: Arc<dyn BlockSource>,
     ) -> Self {
         Self {
             primary_runtime,
@@ -119,6 +129,7 @@ impl RockshrewDiffRuntime {
             primary_tracker: KeyTracker::new(prefix.clone()),
             compare_tracker: KeyTracker::new(prefix.clone()),
             prefix,
+            source,
         }
     }
 
@@ -294,148 +305,11 @@ impl RockshrewDiffRuntime {
         Ok(keys)
     }
 
-    // Helper function to send a request with exponential backoff and jitter
-    async fn send_request(&self, url: reqwest::Url, request_body: serde_json::Value) -> Result<serde_json::Value> {
-        use serde_json::Value;
-        use reqwest::Client;
-        
-        let client = Client::new();
-        let mut retry_delay = Duration::from_millis(100);
-        let max_delay = Duration::from_secs(30);
-        let max_retries = 10;
-        
-        for attempt in 0..=max_retries {
-            match client.post(url.clone())
-                .header("Content-Type", "application/json")
-                .json(&request_body)
-                .send()
-                .await {
-                    Ok(response) => {
-                        match response.json::<Value>().await {
-                            Ok(json) => return Ok(json),
-                            Err(e) => {
-                                if attempt == max_retries {
-                                    return Err(anyhow!("JSON parse error: {}", e));
-                                }
-                            }
-                        }
-                    },
-                    Err(e) => {
-                        if attempt == max_retries {
-                            return Err(anyhow!("Request error: {}", e));
-                        }
-                    }
-                }
-            
-            // Calculate exponential backoff with jitter
-            let jitter = rand::thread_rng().gen_range(0..=100) as u64;
-            retry_delay = std::cmp::min(
-                max_delay,
-                retry_delay * 2 + Duration::from_millis(jitter)
-            );
-            
-            debug!("Request failed (attempt {}), retrying in {:?}",
-                   attempt + 1, retry_delay);
-            sleep(retry_delay).await;
-        }
-        
-        Err(anyhow!("Unreachable: max retries exceeded"))
-    }
-    
-    // Helper function to get the URL with auth
-    fn get_url_with_auth(&self) -> Result<reqwest::Url> {
-        use reqwest::Url;
-        
-        match self.args.auth.clone() {
-            Some(auth) => {
-                let mut url = Url::parse(&self.args.daemon_rpc_url)
-                    .map_err(|e| anyhow!("Invalid URL: {}", e))?;
-                let (username, password) = auth.split(':').next_tuple()
-                    .ok_or_else(|| anyhow!("Invalid auth format, expected username:password"))?;
-                url.set_username(username)
-                    .map_err(|_| anyhow!("Failed to set username"))?;
-                url.set_password(Some(password))
-                    .map_err(|_| anyhow!("Failed to set password"))?;
-                Ok(url)
-            },
-            None => Ok(Url::parse(&self.args.daemon_rpc_url)
-                .map_err(|e| anyhow!("Invalid URL: {}", e))?)
-        }
-    }
-    
-    // Helper function to fetch a block from the Bitcoin node
+    // Helper function to fetch a block from the configured block source
     async fn fetch_block(&self, height: u32) -> Result<Vec<u8>> {
-        use serde_json::{json, Value, Number};
-        
-        // Create the JSON-RPC request
-        let blockhash = self.fetch_blockhash(height).await?;
-        
-        // Get URL with auth
-        let url = self.get_url_with_auth()?;
-        
-        let request_id = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .map_err(|e| anyhow!("Time error: {}", e))?
-            .as_secs() as u32;
-            
-        let request_body = json!({
-            "jsonrpc": "2.0",
-            "id": request_id,
-            "method": "getblock",
-            "params": [
-                hex::encode(&blockhash),
-                Number::from(0)
-            ]
-        });
-        
-        // Send the request with retry logic
-        let response_json = self.send_request(url, request_body).await?;
-            
-        // Extract the block data
-        let block_hex = response_json["result"]
-            .as_str()
-            .ok_or_else(|| anyhow!("Missing result in response"))?;
-            
-        // Decode the hex
-        let block_data = hex::decode(block_hex)
-            .map_err(|e| anyhow!("Hex decode error: {}", e))?;
-            
+        let (_blockhash, block_data) = self.source.block_at(height).await?;
         Ok(block_data)
     }
-    
-    // Helper function to fetch a blockhash from the Bitcoin node
-    async fn fetch_blockhash(&self, height: u32) -> Result<Vec<u8>> {
-        use serde_json::{json, Value};
-        
-        // Get URL with auth
-        let url = self.get_url_with_auth()?;
-        
-        let request_id = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .map_err(|e| anyhow!("Time error: {}", e))?
-            .as_secs() as u32;
-            
-        let request_body = json!({
-            "jsonrpc": "2.0",
-            "id": request_id,
-            "method": "getblockhash",
-            "params": [height]
-        });
-        
-        // Send the request with retry logic
-        let response_json = self.send_request(url, request_body).await?;
-            
-        // Extract the blockhash
-        let blockhash_hex = response_json["result"]
-            .as_str()
-            .ok_or_else(|| anyhow!("Missing result in response"))?;
-            
-        // Decode the hex
-        let blockhash = hex::decode(blockhash_hex)
-            .map_err(|e| anyhow!("Hex decode error: {}", e))?;
-            
-        Ok(blockhash)
-    }
 
     
     // Parallel processing with pipeline
@@ -640,6 +514,7 @@ impl Clone for RockshrewDiffRuntime {
             prefix: self.prefix.clone(),
             primary_tracker: KeyTracker::new(self.prefix.clone()),
             compare_tracker: KeyTracker::new(self.prefix.clone()),
+            source: self.source.clone(),
         }
     }
 }
@@ -707,6 +582,12 @@ async fn main() -> Result<()> {
     // Open primary and compare RocksDB instances
     let primary_db = RocksDBRuntimeAdapter::open(primary_path.to_string_lossy().to_string(), opts.clone())?;
     let compare_db = RocksDBRuntimeAdapter::open(compare_path.to_string_lossy().to_string(), opts)?;
+    // Refuse databases an older release left unmigrated, and stamp fresh
+    // ones with the current storage version
+    for db in [&primary_db, &compare_db] {
+        check_storage_version(&db.db)?;
+        migrate_storage(&db.db)?;
+    }
     
     // Load primary and compare WASM modules
     let primary_indexer: PathBuf = args.indexer.clone().into();
@@ -721,10 +602,14 @@ async fn main() -> Result<()> {
         compare_indexer,
         compare_db,
     )?;
+    primary_runtime.verify_wasm(args.allow_wasm_mismatch)?;
+    compare_runtime.verify_wasm(args.allow_wasm_mismatch)?;
     
     // Get start block
     let start_block = args.start_block.unwrap_or(0);
     
+    let source = args.source.build(&args.daemon_rpc_url, args.auth.as_deref())?;
+    
     // Create and run the diff runtime
     let mut diff_runtime = RockshrewDiffRuntime::new(
         primary_runtime,
@@ -732,6 +617,7 @@ async fn main() -> Result<()> {
         args,
         start_block,
         prefix,
+        source,
     );
     
     diff_runtime.run_pipeline().await?;
@@ -0,0 +1,110 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::{AppState, CURRENT_HEIGHT};
+
+/// Best height reported by the block source, `u32::MAX` until one is seen.
+pub static NODE_TIP: AtomicU32 = AtomicU32::new(u32::MAX);
+/// Set once shutdown starts so load balancers drain the instance first.
+pub static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Readiness {
+    pub ready: bool,
+    /// Condition that failed: `shutting_down`, `node_tip_unknown` or `lagging`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failing: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// Last committed block, absent before the first one
+    pub indexed_height: Option<u32>,
+    pub node_tip: Option<u32>,
+    pub lag: Option<u32>,
+    pub max_lag: u32,
+}
+
+/// Whether the indexer is close enough to the node tip to serve queries.
+/// `next_height` is the next block to process, as in `CURRENT_HEIGHT`.
+pub fn readiness(next_height: u32, node_tip: Option<u32>, max_lag: u32, shutting_down: bool) -> Readiness {
+    let indexed_height = next_height.checked_sub(1);
+    let lag = node_tip.map(|tip| tip.saturating_add(1).saturating_sub(next_height));
+    let (failing, message) = if shutting_down {
+        (Some("shutting_down"), Some("indexer is shutting down".to_string()))
+    } else if let Some(lag) = lag {
+        if lag > max_lag {
+            (
+                Some("lagging"),
+                Some(format!("indexer is {} blocks behind the node tip (max {})", lag, max_lag)),
+            )
+        } else {
+            (None, None)
+        }
+    } else {
+        (Some("node_tip_unknown"), Some("no tip received from the block source yet".to_string()))
+    };
+    Readiness {
+        ready: failing.is_none(),
+        failing,
+        message,
+        indexed_height,
+        node_tip,
+        lag,
+        max_lag,
+    }
+}
+
+/// Liveness: the process is up and serving HTTP.
+#[get("/health")]
+pub async fn handle_health() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "ok",
+        "indexed_height": CURRENT_HEIGHT.load(Ordering::SeqCst).checked_sub(1),
+    }))
+}
+
+/// Readiness: 200 while within `--max-lag` blocks of the node tip, 503 with
+/// the failing condition otherwise.
+#[get("/ready")]
+pub async fn handle_ready(state: web::Data<AppState>) -> impl Responder {
+    let tip = match NODE_TIP.load(Ordering::SeqCst) {
+        u32::MAX => None,
+        tip => Some(tip),
+    };
+    let status = readiness(
+        CURRENT_HEIGHT.load(Ordering::SeqCst),
+        tip,
+        state.max_lag,
+        SHUTTING_DOWN.load(Ordering::SeqCst),
+    );
+    if status.ready {
+        HttpResponse::Ok().json(status)
+    } else {
+        HttpResponse::ServiceUnavailable().json(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ready_within_max_lag() {
+        let status = readiness(100, Some(101), 2, false);
+        assert!(status.ready);
+        assert_eq!(status.indexed_height, Some(99));
+        assert_eq!(status.lag, Some(2));
+        assert_eq!(status.failing, None);
+    }
+
+    #[test]
+    fn reports_failing_condition() {
+        assert_eq!(readiness(100, Some(103), 2, false).failing, Some("lagging"));
+        assert_eq!(readiness(100, None, 2, false).failing, Some("node_tip_unknown"));
+        assert_eq!(readiness(104, Some(103), 2, true).failing, Some("shutting_down"));
+        let json = serde_json::to_value(readiness(0, Some(10), 2, false)).unwrap();
+        assert_eq!(json["ready"], false);
+        assert_eq!(json["indexed_height"], serde_json::Value::Null);
+        assert_eq!(json["lag"], 11);
+    }
+}
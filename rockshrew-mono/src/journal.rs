@@ -0,0 +1,225 @@
+use anyhow::{anyhow, Result};
+use log::warn;
+use metashrew_runtime::{
+    block_digest, chain_state_root, previous_state_root, state_root_key, BatchLike, KeyValueStoreLike,
+    MetashrewRuntime, MetashrewRuntimeContext,
+};
+use rockshrew_runtime::{RocksDBBatch, RocksDBRuntimeAdapter};
+use std::sync::{Arc, Mutex};
+
+use crate::reorg::{height_to_hash_key, stored_blockhash};
+
+/// Height of the last block the processor started applying. The block is
+/// committed once the tip-height moves past it, which `__flush` does in the
+/// same write as the block's data.
+pub const JOURNAL_KEY: &str = "/__INTERNAL/journal";
+
+type Context = Arc<Mutex<MetashrewRuntimeContext<RocksDBRuntimeAdapter>>>;
+
+/// Records that block `height` is about to be applied, together with its
+/// blockhash, without touching the tip.
+pub fn begin(db: &RocksDBRuntimeAdapter, height: u32, blockhash: &[u8]) -> Result<()> {
+    let mut batch = RocksDBBatch::default();
+    batch.put(height_to_hash_key(height), blockhash);
+    batch.put(JOURNAL_KEY, height.to_le_bytes());
+    db.db.write(batch.0)?;
+    Ok(())
+}
+
+/// Commits block `height` without any index changes, for a block the
+/// indexer failed on. Anything a failed run already flushed for it is rolled
+/// back, its stored blockhash is kept so reorg detection still sees the
+/// block, the state root chain records it as an empty block, and the tip
+/// moves past it, all in one write.
+pub fn skip(context: Context, height: u32) -> Result<()> {
+    let mut batch = RocksDBBatch::default();
+    MetashrewRuntime::db_rollback_blocks(context.clone(), &mut batch, height, height)?;
+    let mut guard = context
+        .lock()
+        .map_err(|e| anyhow!("failed to lock context: {}", e))?;
+    let db = &mut guard.db;
+    let root = previous_state_root(db, height)?;
+    batch.put(state_root_key(height), chain_state_root(&root, &block_digest(&[])));
+    let previous = db.height;
+    db.set_height(height);
+    let result = db.write(batch);
+    db.set_height(previous);
+    Ok(result?)
+}
+
+fn journaled_height(db: &mut RocksDBRuntimeAdapter) -> Result<Option<u32>> {
+    match db.get(JOURNAL_KEY)? {
+        Some(v) => Ok(Some(u32::from_le_bytes(
+            v.as_slice()
+                .try_into()
+                .map_err(|_| anyhow!("malformed journal entry"))?,
+        ))),
+        None => Ok(None),
+    }
+}
+
+/// Brings the database back to a state consistent with `tip`, the next block
+/// to process. Blocks at or above the tip may have left a stored hash or an
+/// update list behind if the process died before they committed; anything
+/// they wrote is rolled back in a single write that leaves the tip in place.
+/// Returns whether a repair was needed.
+pub(crate) fn recover(context: Context, tip: u32) -> Result<bool> {
+    let mut db = context
+        .lock()
+        .map_err(|e| anyhow!("failed to lock context: {}", e))?
+        .db
+        .clone();
+    let mut end = MetashrewRuntime::check_latest_block_for_reorg(context.clone(), tip)?;
+    while stored_blockhash(&mut db, end)?.is_some() {
+        end += 1;
+    }
+    let journaled = journaled_height(&mut db)?;
+    if let Some(height) = journaled {
+        if height >= tip {
+            end = end.max(height + 1);
+        }
+    }
+    if end == tip {
+        return Ok(false);
+    }
+
+    warn!(
+        "found uncommitted state for blocks {}..{} (journal: {:?}), rolling back",
+        tip, end, journaled
+    );
+    let mut batch = RocksDBBatch::default();
+    MetashrewRuntime::db_rollback_blocks(context.clone(), &mut batch, tip, end - 1)?;
+    for height in tip..end {
+        batch.delete(height_to_hash_key(height));
+    }
+    batch.delete(JOURNAL_KEY);
+    let mut guard = context
+        .lock()
+        .map_err(|e| anyhow!("failed to lock context: {}", e))?;
+    let height = guard.db.height;
+    guard.db.set_height(tip.wrapping_sub(1));
+    let result = guard.db.write(batch);
+    guard.db.set_height(height);
+    result?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metashrew_runtime::{
+        db_annotate_value, db_make_length_key, db_make_list_key, state_root, u32_to_vec,
+    };
+    use rocksdb::Options;
+    use tempdir::TempDir;
+
+    fn open(dir: &TempDir) -> Context {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        let db = RocksDBRuntimeAdapter::open(dir.path().to_str().unwrap().to_string(), opts)
+            .unwrap();
+        Arc::new(Mutex::new(MetashrewRuntimeContext {
+            db,
+            height: 0,
+            block: vec![],
+            state: 0,
+        }))
+    }
+
+    fn get(context: &Context, key: &[u8]) -> Option<Vec<u8>> {
+        context.lock().unwrap().db.get(key).unwrap()
+    }
+
+    /// Applies block `height` the way `__flush` does, setting `key` to the
+    /// height, recording a state root of the height and optionally advancing
+    /// the tip.
+    fn apply(context: &Context, key: &Vec<u8>, height: u32, commit: bool) {
+        let mut guard = context.lock().unwrap();
+        begin(&guard.db, height, &[height as u8]).unwrap();
+        let mut batch = RocksDBBatch::default();
+        let value = db_annotate_value(&vec![height as u8], height).unwrap();
+        batch.put(db_make_list_key(key, height).unwrap(), value);
+        batch.put(db_make_length_key(key).unwrap(), u32_to_vec(height + 1).unwrap());
+        let updated = u32_to_vec(height).unwrap();
+        batch.put(db_make_list_key(&updated, 0).unwrap(), key);
+        batch.put(db_make_length_key(&updated).unwrap(), u32_to_vec(1).unwrap());
+        batch.put(state_root_key(height), [height as u8; 32]);
+        if commit {
+            guard.db.set_height(height);
+            guard.db.write(batch).unwrap();
+        } else {
+            // Simulate the update list landing without the tip advancing
+            guard.db.db.write(batch.0).unwrap();
+        }
+    }
+
+    #[test]
+    fn committed_blocks_need_no_repair() {
+        let dir = TempDir::new("journal").unwrap();
+        let context = open(&dir);
+        let key = b"/k".to_vec();
+        for height in 0..3 {
+            apply(&context, &key, height, true);
+        }
+        assert!(!recover(context.clone(), 3).unwrap());
+        assert!(get(&context, &height_to_hash_key(2)).is_some());
+    }
+
+    #[test]
+    fn repairs_block_with_hash_but_no_data() {
+        let dir = TempDir::new("journal").unwrap();
+        let context = open(&dir);
+        let key = b"/k".to_vec();
+        apply(&context, &key, 0, true);
+        begin(&context.lock().unwrap().db, 1, &[1]).unwrap();
+        assert!(recover(context.clone(), 1).unwrap());
+        assert!(get(&context, &height_to_hash_key(1)).is_none());
+        assert!(get(&context, JOURNAL_KEY.as_bytes()).is_none());
+        assert!(!recover(context.clone(), 1).unwrap());
+    }
+
+    #[test]
+    fn skipped_block_is_committed_without_data() {
+        let dir = TempDir::new("journal").unwrap();
+        let context = open(&dir);
+        let key = b"/k".to_vec();
+        apply(&context, &key, 0, true);
+        begin(&context.lock().unwrap().db, 1, &[1]).unwrap();
+        // A failed run had already flushed part of the block
+        apply(&context, &key, 1, false);
+        skip(context.clone(), 1).unwrap();
+        assert_eq!(get(&context, b"/__INTERNAL/tip-height").unwrap(), u32_to_vec(2).unwrap());
+        assert!(!recover(context.clone(), 2).unwrap());
+        assert_eq!(get(&context, &height_to_hash_key(1)).unwrap(), vec![1]);
+        let updated = u32_to_vec(1).unwrap();
+        assert!(get(&context, &db_make_length_key(&updated).unwrap()).is_none());
+        let length = get(&context, &db_make_length_key(&key).unwrap()).unwrap();
+        assert_eq!(length, u32_to_vec(1).unwrap());
+        assert!(get(&context, &db_make_list_key(&key, 1).unwrap()).is_none());
+        let root = state_root(&mut context.lock().unwrap().db, 1).unwrap();
+        assert_eq!(root, Some(chain_state_root(&[0; 32], &block_digest(&[]))));
+    }
+
+    #[test]
+    fn repairs_update_list_without_tip_advance() {
+        let dir = TempDir::new("journal").unwrap();
+        let context = open(&dir);
+        let key = b"/k".to_vec();
+        apply(&context, &key, 0, true);
+        apply(&context, &key, 1, true);
+        apply(&context, &key, 2, false);
+        let tip = get(&context, b"/__INTERNAL/tip-height").unwrap();
+        assert_eq!(tip, u32_to_vec(2).unwrap());
+
+        assert!(recover(context.clone(), 2).unwrap());
+        let length = get(&context, &db_make_length_key(&key).unwrap()).unwrap();
+        assert_eq!(length, u32_to_vec(2).unwrap());
+        assert!(get(&context, &db_make_list_key(&key, 2).unwrap()).is_none());
+        let updated = u32_to_vec(2).unwrap();
+        assert!(get(&context, &db_make_length_key(&updated).unwrap()).is_none());
+        assert!(get(&context, &height_to_hash_key(2)).is_none());
+        assert!(get(&context, &state_root_key(2)).is_none());
+        assert!(get(&context, &state_root_key(1)).is_some());
+        assert_eq!(get(&context, b"/__INTERNAL/tip-height").unwrap(), tip);
+    }
+}
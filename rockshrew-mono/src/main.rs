@@ -1,28 +1,36 @@
 use actix_cors::Cors;
-use actix_web::error;
 use actix_web::{post, web, App, HttpResponse, HttpServer, Responder, Result as ActixResult};
 use anyhow::{anyhow, Result};
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use env_logger;
+use futures::future::join_all;
 use hex;
-use itertools::Itertools;
-use log::{debug, info, error};
-use rand::Rng;
-use metashrew_runtime::{KeyValueStoreLike, MetashrewRuntime};
+use log::{debug, info, error, warn};
+use metashrew_blocksource::{BlockNotifier, BlockSource, BlockSourceArgs};
+use metashrew_runtime::{
+    state_root, CallLimits, KeyValueStoreLike, MetashrewRuntime, RuntimeOptions, Upgrade,
+    ViewError, ViewHandle,
+};
 use num_cpus;
-use reqwest::{Response, Url};
 use rocksdb::Options;
-use rockshrew_runtime::{query_height, set_label, RocksDBRuntimeAdapter};
+use rockshrew_runtime::{migrate_storage, query_height, set_label, RocksDBRuntimeAdapter};
 use serde::{Deserialize, Serialize};
-use serde_json::{self, Number, Value};
+use serde_json::{self, Value};
+use std::ops::RangeBounds;
 use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::Duration;
 use tokio;
-use tokio::sync::{RwLock, mpsc};
+use tokio::sync::{RwLock, mpsc, watch};
 use tokio::time::sleep;
 
-const HEIGHT_TO_HASH: &'static str = "/__INTERNAL/height-to-hash/";
+mod health;
+mod journal;
+mod metrics;
+mod prefetch;
+mod reorg;
+use prefetch::Prefetcher;
+use reorg::HEIGHT_TO_HASH;
 use std::sync::atomic::{AtomicU32, Ordering};
 static CURRENT_HEIGHT: AtomicU32 = AtomicU32::new(0);
 
@@ -30,7 +38,20 @@ static CURRENT_HEIGHT: AtomicU32 = AtomicU32::new(0);
 #[derive(Debug)]
 enum BlockResult {
     Success(u32),  // Block height that was successfully processed
+    Skipped(u32),  // Block height committed without its index changes
     Error(u32, anyhow::Error),  // Block height and error
+    Halt(u32, anyhow::Error),  // Block height and error that stops the indexer
+}
+
+// What to do when the indexer fails to process a block
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum FailurePolicy {
+    /// Stop indexing and exit with an error
+    Halt,
+    /// Run the block again up to --block-retries times, then halt
+    Retry,
+    /// Commit the block without its index changes and log an alert
+    Skip,
 }
 
 #[derive(Parser, Debug)]
@@ -58,13 +79,81 @@ struct Args {
     #[arg(long, help = "CORS allowed origins (e.g., '*' for all origins, or specific domains)")]
     cors: Option<String>,
     // Pipeline configuration
-    #[arg(long, help = "Size of the processing pipeline (default: auto-determined based on CPU cores)")]
+    #[arg(long, help = "Number of blocks fetched concurrently (default: auto-determined based on CPU cores)")]
     pipeline_size: Option<usize>,
+    #[arg(long, default_value_t = 256, help = "Memory for blocks fetched ahead of the processor, in MiB")]
+    prefetch_buffer_mb: usize,
+    #[arg(long, value_enum, default_value_t = FailurePolicy::Halt, help = "What to do when the indexer fails on a block")]
+    on_block_failure: FailurePolicy,
+    #[arg(long, default_value_t = 3, help = "Retries per block with --on-block-failure retry")]
+    block_retries: u32,
+    #[command(flatten)]
+    source: BlockSourceArgs,
+    #[arg(long, help = "Bitcoin Core zmqpubhashblock endpoint (e.g. tcp://127.0.0.1:28332) to fetch new blocks as soon as they are announced")]
+    zmq_hashblock: Option<String>,
+    #[arg(long, default_value_t = 3, help = "Blocks behind the node tip at which /ready reports not ready")]
+    max_lag: u32,
+    #[arg(long, default_value_t = 100, help = "Most requests accepted in one JSON-RPC batch")]
+    max_batch_size: usize,
+    #[arg(long, help = "Fuel each metashrew_view call may consume (unlimited when unset)")]
+    view_fuel: Option<u64>,
+    #[arg(long, help = "Milliseconds each metashrew_view call may run (unlimited when unset)")]
+    view_timeout_ms: Option<u64>,
+    #[arg(long, help = "Fuel each metashrew_preview call may consume (unlimited when unset)")]
+    preview_fuel: Option<u64>,
+    #[arg(long, help = "Milliseconds each metashrew_preview call may run (unlimited when unset)")]
+    preview_timeout_ms: Option<u64>,
+    #[arg(long, help = "MiB of WASM memory each metashrew_view call may use (unlimited when unset)")]
+    view_memory_mb: Option<usize>,
+    #[arg(long, help = "MiB of WASM memory each metashrew_preview call may use (unlimited when unset)")]
+    preview_memory_mb: Option<usize>,
+    #[arg(long, help = "MiB of WASM memory the indexer may use while processing blocks (unlimited when unset)")]
+    indexer_memory_mb: Option<usize>,
+    #[arg(long, help = "Preallocate this many WASM instances for views and previews (allocated on demand when unset)")]
+    view_instance_pool: Option<u32>,
+    #[arg(long, help = "Directory caching the compiled indexer so restarts skip recompiling it")]
+    module_cache_dir: Option<PathBuf>,
+    #[arg(long = "upgrade", value_name = "HEIGHT:PATH", help = "Switch to the indexer at PATH from block HEIGHT on (repeatable)")]
+    upgrades: Vec<Upgrade>,
+    #[arg(long, help = "Only warn when the indexer WASM differs from the one the database was indexed with")]
+    allow_wasm_mismatch: bool,
+    #[arg(long, help = "Maintain the state tree behind metashrew_getproof (must be set from the first block indexed)")]
+    enable_state_proofs: bool,
+    #[arg(long, help = "Serve metashrew_getkey and metashrew_getkeyhistory, which read raw keys")]
+    enable_debug_rpc: bool,
+}
+
+impl Args {
+    fn runtime_options(&self) -> RuntimeOptions {
+        RuntimeOptions {
+            view: CallLimits {
+                fuel: self.view_fuel,
+                timeout: self.view_timeout_ms.map(Duration::from_millis),
+                max_memory: self.view_memory_mb.map(|mb| mb * 1024 * 1024),
+            },
+            preview: CallLimits {
+                fuel: self.preview_fuel,
+                timeout: self.preview_timeout_ms.map(Duration::from_millis),
+                max_memory: self.preview_memory_mb.map(|mb| mb * 1024 * 1024),
+            },
+            indexer_max_memory: self.indexer_memory_mb.map(|mb| mb * 1024 * 1024),
+            instance_pool: self.view_instance_pool,
+            module_cache: self.module_cache_dir.clone(),
+            upgrades: self.upgrades.clone(),
+            state_proofs: self.enable_state_proofs,
+        }
+    }
 }
 
 #[derive(Clone)]
 struct AppState {
-    runtime: Arc<RwLock<MetashrewRuntime<RocksDBRuntimeAdapter>>>,
+    // Views and previews run apart from the indexer's runtime, so they
+    // neither wait for nor hold up block processing
+    views: ViewHandle<RocksDBRuntimeAdapter>,
+    max_lag: u32,
+    max_batch_size: usize,
+    // Whether raw key reads are served
+    debug_rpc: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -76,15 +165,40 @@ struct JsonRpcRequest {
 }
 
 #[derive(Serialize)]
-struct JsonRpcResult {
+struct JsonRpcResult<T = String> {
     id: u32,
-    result: String,
+    result: T,
     jsonrpc: String,
 }
 
+/// `metashrew_view` result when the request passes `{"debug": true}` as a
+/// fourth param
+#[derive(Serialize)]
+struct ViewDebugResult {
+    data: String,
+    log: Vec<String>,
+}
+
+/// `metashrew_getproof` result. `value` is null when the key was never
+/// written, and `leaf` is null when its path ends at an empty subtree.
+#[derive(Serialize)]
+struct ProofResult {
+    height: u32,
+    root: String,
+    value: Option<String>,
+    siblings: Vec<String>,
+    leaf: Option<ProofLeaf>,
+}
+
+#[derive(Serialize)]
+struct ProofLeaf {
+    path: String,
+    value_hash: String,
+}
+
 #[derive(Serialize)]
 struct JsonRpcError {
-    id: u32,
+    id: Option<u32>,
     error: JsonRpcErrorObject,
     jsonrpc: String,
 }
@@ -93,37 +207,19 @@ struct JsonRpcError {
 struct JsonRpcErrorObject {
     code: i32,
     message: String,
-    data: Option<String>,
+    data: Option<Value>,
 }
 
-#[derive(Debug)]
-struct IndexerError(anyhow::Error);
-
-impl std::fmt::Display for IndexerError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.0)
-    }
-}
-
-impl From<anyhow::Error> for IndexerError {
-    fn from(err: anyhow::Error) -> Self {
-        IndexerError(err)
+impl From<ViewError> for JsonRpcErrorObject {
+    fn from(err: ViewError) -> Self {
+        Self {
+            code: err.code(),
+            message: err.to_string(),
+            data: Some(err.data()),
+        }
     }
 }
 
-impl error::ResponseError for IndexerError {
-    fn error_response(&self) -> HttpResponse {
-        HttpResponse::Ok().json(JsonRpcError {
-            id: 0, // Generic ID since we lost context
-            error: JsonRpcErrorObject {
-                code: -32000,
-                message: self.0.to_string(),
-                data: None,
-            },
-            jsonrpc: "2.0".to_string(),
-        })
-    }
-}
 struct IndexerState {
     runtime: Arc<RwLock<MetashrewRuntime<RocksDBRuntimeAdapter>>>,
     args: Arc<Args>,
@@ -132,81 +228,12 @@ struct IndexerState {
     processor_thread_id_tx: Option<tokio::sync::mpsc::Sender<(String, std::thread::ThreadId)>>,
     fetcher_thread_id: std::sync::Mutex<Option<std::thread::ThreadId>>,
     processor_thread_id: std::sync::Mutex<Option<std::thread::ThreadId>>,
+    source: Arc<dyn BlockSource>,
+    // Wakes pull_block at the tip; polls when no notification stream is configured
+    notifier: BlockNotifier,
 }
 
 impl IndexerState {
-    async fn post_once(&self, body: String) -> Result<Response, reqwest::Error> {
-        let response = reqwest::Client::new()
-            .post(match self.args.auth.clone() {
-                Some(v) => {
-                    let mut url = Url::parse(self.args.daemon_rpc_url.as_str()).unwrap();
-                    let (username, password) = v.split(":").next_tuple().unwrap();
-                    url.set_username(username).unwrap();
-                    url.set_password(Some(password)).unwrap();
-                    url
-                }
-                None => Url::parse(self.args.daemon_rpc_url.as_str()).unwrap(),
-            })
-            .header("Content-Type", "application/json")
-            .body(body)
-            .send()
-            .await;
-        return response;
-    }
-#[allow(unused_assignments)]
-async fn post(&self, body: String) -> Result<Response> {
-    let mut retry_delay = Duration::from_millis(100);
-    let max_delay = Duration::from_secs(30);
-    let max_retries = 10;
-    let mut response = None;
-    
-    for attempt in 0..=max_retries {
-        match self.post_once(body.clone()).await {
-            Ok(v) => {
-                response = Some(v);
-                return Ok(response.unwrap());
-            },
-            Err(e) => {
-                if attempt == max_retries {
-                    return Err(anyhow!("Max retries exceeded: {}", e));
-                }
-                
-                // Calculate exponential backoff with jitter
-                let jitter = rand::thread_rng().gen_range(0..=100) as u64;
-                retry_delay = std::cmp::min(
-                    max_delay,
-                    retry_delay * 2 + Duration::from_millis(jitter)
-                );
-                
-                debug!("Request failed (attempt {}): {}, retrying in {:?}",
-                       attempt + 1, e, retry_delay);
-                tokio::time::sleep(retry_delay).await;
-            }
-        }
-    }
-    
-    Err(anyhow!("Unreachable: max retries exceeded"))
-    }
-
-    async fn fetch_blockcount(&self) -> Result<u32> {
-        let response = self
-            .post(serde_json::to_string(&JsonRpcRequest {
-                id: SystemTime::now()
-                    .duration_since(UNIX_EPOCH)?
-                    .as_secs()
-                    .try_into()?,
-                jsonrpc: String::from("2.0"),
-                method: String::from("getblockcount"),
-                params: vec![],
-            })?)
-            .await?;
-
-        let result: Value = response.json().await?;
-        Ok(result["result"]
-            .as_u64()
-            .ok_or_else(|| anyhow!("missing result from JSON-RPC response"))? as u32)
-    }
-
     async fn query_height(&self) -> Result<u32> {
         let (db, start_block) = {
             let runtime = self.runtime.read().await;
@@ -216,132 +243,58 @@ async fn post(&self, body: String) -> Result<Response> {
         query_height(db, start_block).await
     }
 
-    async fn best_height(&self, block_number: u32) -> Result<u32> {
-        let mut best: u32 = block_number;
-        let tip = self.fetch_blockcount().await?;
-
-        if best >= tip - std::cmp::min(6, tip) {
-            loop {
-                if best == 0 {
-                    break;
-                }
-                
-                // Get local blockhash with better error handling
-                let blockhash = match self.get_blockhash(best).await {
-                    Some(hash) => hash,
-                    None => {
-                        // If we can't get the local blockhash, try to get it from the remote
-                        debug!("Local blockhash not found for block {}, fetching from remote", best);
-                        let remote_hash = self.fetch_blockhash(best).await?;
-                        
-                        // Store the remote hash locally for future reference
-                        let runtime = self.runtime.write().await;
-                        if let Ok(mut context) = runtime.context.lock() {
-                            let key = (String::from(HEIGHT_TO_HASH) + &best.to_string()).into_bytes();
-                            if let Err(e) = context.db.put(&key, &remote_hash) {
-                                debug!("Failed to store blockhash for block {}: {}", best, e);
-                            }
-                        }
-                        
-                        remote_hash
-                    }
-                };
-                
-                let remote_blockhash = self.fetch_blockhash(best).await?;
-                if blockhash == remote_blockhash {
-                    break;
-                } else {
-                    best = best - 1;
-                }
-            }
+    // Compares the parent of the block about to be processed at `height` with
+    // the hash we stored for `height - 1`. On a mismatch, returns the fork point
+    // found by walking back against the node.
+    async fn detect_reorg(&self, height: u32, block_data: &[u8]) -> Result<Option<u32>> {
+        if height == 0 {
+            return Ok(None);
         }
-        Ok(best)
-    }
-
-    async fn get_blockhash(&self, block_number: u32) -> Option<Vec<u8>> {
-        let key = (String::from(HEIGHT_TO_HASH) + &block_number.to_string()).into_bytes();
-        let runtime = match self.runtime.read().await {
-            runtime => runtime,
+        let mut db = {
+            let runtime = self.runtime.read().await;
+            let context = runtime.context.lock().map_err(|e| anyhow!("Failed to lock context: {}", e))?;
+            context.db.clone()
         };
-        
-        let mut context = match runtime.context.lock() {
-            Ok(context) => context,
-            Err(e) => {
-                error!("Failed to lock context: {}", e);
-                return None;
-            }
+        let stored = match reorg::stored_blockhash(&mut db, height - 1)? {
+            Some(hash) => hash,
+            None => return Ok(None),
         };
-        
-        match context.db.get(&key) {
-            Ok(result) => result,
-            Err(e) => {
-                error!("Database error when retrieving blockhash for block {}: {}", block_number, e);
-                None
+        match reorg::prev_blockhash(block_data) {
+            Some(prev) if prev != stored => {
+                info!("Block {} does not extend our block {}, searching for fork point", height, height - 1);
+                // If the node has since switched back to our chain, refetching
+                // from `height` is all that is needed
+                Ok(Some(reorg::find_fork_point(self.source.as_ref(), &mut db, height - 1).await?.unwrap_or(height - 1)))
             }
+            _ => Ok(None),
         }
     }
 
-    async fn fetch_blockhash(&self, block_number: u32) -> Result<Vec<u8>> {
-        let response = self
-            .post(serde_json::to_string(&JsonRpcRequest {
-                id: SystemTime::now()
-                    .duration_since(UNIX_EPOCH)?
-                    .as_secs()
-                    .try_into()?,
-                jsonrpc: String::from("2.0"),
-                method: String::from("getblockhash"),
-                params: vec![Value::Number(Number::from(block_number))],
-            })?)
-            .await?;
-
-        let result: Value = response.json().await?;
-        let blockhash = result["result"]
-            .as_str()
-            .ok_or_else(|| anyhow!("missing result from JSON-RPC response"))?;
-        Ok(hex::decode(blockhash)?)
+    // Roll back everything indexed above `fork`; the caller restarts fetching at `fork + 1`
+    async fn rollback(&self, fork: u32, tip: u32) -> Result<()> {
+        let mut runtime = self.runtime.write().await;
+        reorg::rollback_to(runtime.context.clone(), fork, tip)?;
+        runtime.refresh_memory()?;
+        CURRENT_HEIGHT.store(fork + 1, Ordering::SeqCst);
+        metrics::INDEXED_HEIGHT.set(fork as i64);
+        metrics::sync_counter(&metrics::MEMORY_REFRESHES, runtime.memory_refreshes);
+        Ok(())
     }
 
-    async fn pull_block(&self, block_number: u32) -> Result<Vec<u8>> {
+    async fn pull_block(&self, block_number: u32) -> Result<(Vec<u8>, Vec<u8>)> {
         loop {
-            let count = self.fetch_blockcount().await?;
+            let count = self.source.tip().await?;
             if block_number > count {
-                tokio::time::sleep(Duration::from_millis(3000)).await;
+                self.notifier.wait(Duration::from_millis(3000)).await;
             } else {
                 break;
             }
         }
-        let blockhash = self.fetch_blockhash(block_number).await?;
-
-        let runtime = self.runtime.write().await;
-        runtime.context.lock().unwrap().db.put(
-            &(String::from(HEIGHT_TO_HASH) + &block_number.to_string()).into_bytes(),
-            &blockhash,
-        )?;
-
-        let response = self
-            .post(serde_json::to_string(&JsonRpcRequest {
-                id: SystemTime::now()
-                    .duration_since(UNIX_EPOCH)?
-                    .as_secs()
-                    .try_into()?,
-                jsonrpc: String::from("2.0"),
-                method: String::from("getblock"),
-                params: vec![
-                    Value::String(hex::encode(&blockhash)),
-                    Value::Number(Number::from(0)),
-                ],
-            })?)
-            .await?;
-
-        let result: Value = response.json().await?;
-        let block_hex = result["result"]
-            .as_str()
-            .ok_or_else(|| anyhow!("missing result from JSON-RPC response"))?;
-        Ok(hex::decode(block_hex)?)
+        self.source.block_at(block_number).await
     }
 
     // Process a single block
-    async fn process_block(&self, height: u32, block_data: Vec<u8>) -> Result<()> {
+    async fn process_block(&self, height: u32, blockhash: &[u8], block_data: &[u8]) -> Result<()> {
         // Get a lock on the runtime with better error handling
         let mut runtime = match self.runtime.write().await {
             runtime => runtime,
@@ -350,9 +303,10 @@ async fn post(&self, body: String) -> Result<Response> {
         // Set block data with better error handling
         match runtime.context.lock() {
             Ok(mut context) => {
-                context.block = block_data;
+                context.block = block_data.to_vec();
                 context.height = height;
                 context.db.set_height(height);
+                journal::begin(&context.db, height, blockhash)?;
             },
             Err(e) => {
                 return Err(anyhow!("Failed to lock context: {}", e));
@@ -371,9 +325,17 @@ async fn post(&self, body: String) -> Result<Response> {
         }
         
         // Execute the runtime with better error handling
+        let started = std::time::Instant::now();
         match runtime.run() {
             Ok(_) => {
                 debug!("Successfully processed block {}", height);
+                metrics::START_SECONDS.observe(started.elapsed().as_secs_f64());
+                metrics::FLUSHED_KEYS.observe(runtime.flushed_keys() as f64);
+                metrics::sync_counter(&metrics::MEMORY_REFRESHES, runtime.memory_refreshes);
+                let instance = runtime.instance;
+                if let Some(memory) = instance.get_memory(&mut runtime.wasmstore, "memory") {
+                    metrics::WASM_MEMORY_BYTES.set(memory.data_size(&mut runtime.wasmstore) as i64);
+                }
                 
                 // Store the blockhash for this height to ensure it's available for future queries
                 if let Ok(mut context) = runtime.context.lock() {
@@ -390,43 +352,23 @@ async fn post(&self, body: String) -> Result<Response> {
             Err(e) => {
                 // Log detailed memory stats when runtime execution fails
                 let memory_stats = self.get_memory_stats(&mut runtime);
-                error!("CRITICAL: Runtime execution failed for block {}: {}", height, e);
+                error!("Runtime execution failed for block {}: {}", height, e);
                 error!("Memory stats at failure: {}", memory_stats);
                 
-                // Crash the process to force a restart and make the issue more visible
-                panic!("Runtime execution failed with memory stats: {}", memory_stats);
-                
-                // The code below is unreachable due to the panic, but kept for reference
-                /*
-                // Try to refresh memory with better error handling
-                match runtime.refresh_memory() {
-                    Ok(_) => {
-                        // Try running again after memory refresh
-                        match runtime.run() {
-                            Ok(_) => {
-                                debug!("Successfully processed block {} after memory refresh", height);
-                                Ok(())
-                            },
-                            Err(run_err) => {
-                                error!("Runtime execution failed after memory refresh: {}", run_err);
-                                Err(anyhow!(run_err))
-                            }
-                        }
-                    },
-                    Err(refresh_err) => {
-                        error!("Memory refresh failed: {}", refresh_err);
-                        Err(anyhow!(refresh_err))
-                    }
+                // Start any retry from a fresh instance
+                if let Err(refresh_err) = runtime.refresh_memory() {
+                    error!("Memory refresh failed: {}", refresh_err);
                 }
-                */
+                Err(e)
             }
         }
     }
 
     // Improvement 5: Parallel processing with pipeline
-    async fn run_pipeline(&mut self) -> Result<()> {
+    async fn run_pipeline(&mut self, mut shutdown: watch::Receiver<bool>) -> Result<()> {
         let mut height: u32 = self.query_height().await?;
         CURRENT_HEIGHT.store(height, Ordering::SeqCst);
+        metrics::INDEXED_HEIGHT.set(height as i64 - 1);
         // Determine optimal pipeline size based on CPU cores if not specified
         let pipeline_size = match self.args.pipeline_size {
             Some(size) => size,
@@ -442,94 +384,56 @@ async fn post(&self, body: String) -> Result<Response> {
             }
         };
         
-        // Create channels for the pipeline
-        let (block_sender, mut block_receiver) = mpsc::channel::<(u32, Vec<u8>)>(pipeline_size);
+        // Blocks are fetched concurrently and reassembled in height order
         let (result_sender, mut result_receiver) = mpsc::channel::<BlockResult>(pipeline_size);
+        let prefetcher = Prefetcher::new(height, self.args.exit_at, self.args.prefetch_buffer_mb << 20);
+        let fetch_workers = prefetcher.spawn_workers(pipeline_size, self.source.clone(), self.notifier.clone());
+        info!("Started {} block fetch workers", fetch_workers.len());
         
-        // Spawn block fetcher task on dedicated thread
-        let fetcher_handle = {
-            let args = self.args.clone();
+        // Spawn block processor task on dedicated thread
+        let processor_handle = {
             let indexer = self.clone();
+            let prefetcher = prefetcher.clone();
             let result_sender_clone = result_sender.clone();
-            let block_sender_clone = block_sender.clone();
             
-            // Spawn a task for the block fetcher
+            // Spawn a task for the block processor
             tokio::spawn(async move {
-                    // Register this thread as the fetcher thread
-                    indexer.register_current_thread_as_fetcher();
-                    info!("Block fetcher task started on thread {:?}", std::thread::current().id());
-                let mut current_height = height;
-                
-                loop {
-                    // Check if we should exit
-                    if let Some(exit_at) = args.exit_at {
-                        if current_height >= exit_at {
-                            info!("Fetcher reached exit-at block {}, shutting down", exit_at);
-                            break;
-                        }
-                    }
-                    
-                    // Find the best height considering potential reorgs
-                    let best_height = match indexer.best_height(current_height).await {
-                        Ok(h) => h,
-                        Err(e) => {
-                            error!("Failed to determine best height: {}", e);
-                            // Send error result and continue
-                            if result_sender_clone.send(BlockResult::Error(current_height, e)).await.is_err() {
-                                break;
+                    // Register this thread as the processor thread
+                    indexer.register_current_thread_as_processor();
+                    info!("Block processor task started on thread {:?}", std::thread::current().id());
+                while let Some((block_height, blockhash, block_data)) = prefetcher.next().await {
+                    match indexer.detect_reorg(block_height, &block_data).await {
+                        Ok(None) => {},
+                        Ok(Some(fork)) => {
+                            info!("Reorg detected at block {}, rolling back to block {}", block_height, fork);
+                            if let Err(e) = indexer.rollback(fork, block_height - 1).await {
+                                error!("Rollback to block {} failed: {}", fork, e);
+                                if result_sender_clone.send(BlockResult::Error(block_height, e)).await.is_err() {
+                                    break;
+                                }
+                                // Leave the tip where it is and try again
+                                prefetcher.rewind(block_height);
+                                continue;
                             }
-                            sleep(Duration::from_secs(1)).await;
+                            prefetcher.rewind(fork + 1);
                             continue;
-                        }
-                    };
-                    
-                    // Fetch the block
-                    match indexer.pull_block(best_height).await {
-                        Ok(block_data) => {
-                            debug!("Fetched block {} ({})", best_height, block_data.len());
-                            // Send block to processor
-                            if block_sender_clone.send((best_height, block_data)).await.is_err() {
-                                break;
-                            }
                         },
                         Err(e) => {
-                            error!("Failed to fetch block {}: {}", best_height, e);
-                            // Send error result
-                            if result_sender_clone.send(BlockResult::Error(best_height, e)).await.is_err() {
+                            if result_sender_clone.send(BlockResult::Error(block_height, e)).await.is_err() {
                                 break;
                             }
-                            sleep(Duration::from_secs(1)).await;
+                            prefetcher.rewind(block_height);
                             continue;
                         }
                     }
                     
-                    current_height = best_height + 1;
-                }
-                
-                debug!("Block fetcher task completed");
-            })
-        };
-        
-        // Spawn block processor task on dedicated thread
-        let processor_handle = {
-            let indexer = self.clone();
-            let result_sender_clone = result_sender.clone();
-            
-            // Spawn a task for the block processor
-            tokio::spawn(async move {
-                    // Register this thread as the processor thread
-                    indexer.register_current_thread_as_processor();
-                    info!("Block processor task started on thread {:?}", std::thread::current().id());
-                while let Some((block_height, block_data)) = block_receiver.recv().await {
                     debug!("Processing block {} ({})", block_height, block_data.len());
                     
-                    let result = match indexer.process_block(block_height, block_data).await {
-                        Ok(_) => BlockResult::Success(block_height),
-                        Err(e) => BlockResult::Error(block_height, e),
-                    };
+                    let result = indexer.process_with_policy(block_height, &blockhash, &block_data).await;
+                    let halt = matches!(result, BlockResult::Halt(..));
                     
                     // Send result
-                    if result_sender_clone.send(result).await.is_err() {
+                    if result_sender_clone.send(result).await.is_err() || halt {
                         break;
                     }
                 }
@@ -539,13 +443,32 @@ async fn post(&self, body: String) -> Result<Response> {
         };
         
         // Main loop to handle results
-        while let Some(result) = result_receiver.recv().await {
+        let mut outcome = Ok(());
+        loop {
+            let result = tokio::select! {
+                result = result_receiver.recv() => match result {
+                    Some(result) => result,
+                    None => break,
+                },
+                _ = shutdown.changed() => {
+                    info!("Stopping block fetchers and finishing the block in progress");
+                    break;
+                }
+            };
             match result {
-                BlockResult::Success(processed_height) => {
+                BlockResult::Success(processed_height) | BlockResult::Skipped(processed_height) => {
                     debug!("Successfully processed block {}", processed_height);
+                    if matches!(result, BlockResult::Skipped(_)) {
+                        metrics::BLOCKS_SKIPPED.inc();
+                    }
+                    metrics::record_block(processed_height);
                     height = processed_height + 1;
                     CURRENT_HEIGHT.store(height, Ordering::SeqCst);
                 },
+                BlockResult::Halt(failed_height, error) => {
+                    outcome = Err(anyhow!("Indexer halted at block {}: {}", failed_height, error));
+                    break;
+                },
                 BlockResult::Error(failed_height, error) => {
                     error!("Failed to process block {}: {}", failed_height, error);
                     // We could implement more sophisticated error handling here
@@ -564,13 +487,44 @@ async fn post(&self, body: String) -> Result<Response> {
         }
         
         // Clean up
-        drop(block_sender);
+        prefetcher.close();
         drop(result_sender);
         
-        // Wait for tasks to complete
-        let (_fetcher_result, _processor_result) = tokio::join!(fetcher_handle, processor_handle);
+        // Wait for the processor to finish its block, the fetchers hold nothing worth keeping
+        let _ = processor_handle.await;
+        for worker in fetch_workers {
+            worker.abort();
+        }
         
-        Ok(())
+        outcome
+    }
+
+    // Process a block, applying --on-block-failure when the indexer fails on it
+    async fn process_with_policy(&self, height: u32, blockhash: &[u8], block_data: &[u8]) -> BlockResult {
+        let mut attempts = 0;
+        loop {
+            let e = match self.process_block(height, blockhash, block_data).await {
+                Ok(_) => return BlockResult::Success(height),
+                Err(e) => e,
+            };
+            match self.args.on_block_failure {
+                FailurePolicy::Retry if attempts < self.args.block_retries => {
+                    attempts += 1;
+                    warn!("Retrying block {} (attempt {} of {})", height, attempts, self.args.block_retries);
+                }
+                FailurePolicy::Skip => {
+                    let runtime = self.runtime.write().await;
+                    return match journal::skip(runtime.context.clone(), height) {
+                        Ok(_) => {
+                            error!("ALERT: skipped block {} without indexing it: {}", height, e);
+                            BlockResult::Skipped(height)
+                        }
+                        Err(skip_err) => BlockResult::Halt(height, skip_err),
+                    };
+                }
+                _ => return BlockResult::Halt(height, e),
+            }
+        }
     }
 
     #[allow(dead_code)]
@@ -588,15 +542,20 @@ async fn post(&self, body: String) -> Result<Response> {
                 }
             }
 
-            let best: u32 = self.best_height(height).await.unwrap_or(height);
-            let block_data = self.pull_block(best).await?;
+            let (blockhash, block_data) = self.pull_block(height).await?;
+            if let Some(fork) = self.detect_reorg(height, &block_data).await? {
+                self.rollback(fork, height - 1).await?;
+                height = fork + 1;
+                continue;
+            }
 
             let mut runtime = self.runtime.write().await;
             {
                 let mut context = runtime.context.lock().unwrap();
                 context.block = block_data;
-                context.height = best;
-                context.db.set_height(best);
+                context.height = height;
+                context.db.set_height(height);
+                journal::begin(&context.db, height, &blockhash)?;
             }
 
             match runtime.run() {
@@ -615,7 +574,7 @@ async fn post(&self, body: String) -> Result<Response> {
                 }
             }
 
-            height = best + 1;
+            height += 1;
             CURRENT_HEIGHT.store(height, Ordering::SeqCst);
         }
     }
@@ -632,6 +591,8 @@ impl Clone for IndexerState {
             processor_thread_id_tx: self.processor_thread_id_tx.clone(),
             fetcher_thread_id: std::sync::Mutex::new(*self.fetcher_thread_id.lock().unwrap()),
             processor_thread_id: std::sync::Mutex::new(*self.processor_thread_id.lock().unwrap()),
+            source: self.source.clone(),
+            notifier: self.notifier.clone(),
         }
     }
 }
@@ -699,6 +660,8 @@ impl IndexerState {
         self.processor_thread_id_tx = Some(processor_tx);
     }
     
+    // Fetching is spread over several workers now, so no thread registers as the fetcher
+    #[allow(dead_code)]
     fn register_current_thread_as_fetcher(&self) {
         if let Some(tx) = &self.fetcher_thread_id_tx {
             let thread_id = std::thread::current().id();
@@ -761,309 +724,328 @@ impl IndexerState {
 
 #[post("/")]
 async fn handle_jsonrpc(
-    body: web::Json<JsonRpcRequest>,
+    body: web::Json<Value>,
     state: web::Data<AppState>,
 ) -> ActixResult<impl Responder> {
-    debug!("RPC request: {}", serde_json::to_string(&body).unwrap());
-
-    if body.method == "metashrew_view" {
-        if body.params.len() < 3 {
-            return Ok(HttpResponse::Ok().json(JsonRpcError {
-                id: body.id,
-                error: JsonRpcErrorObject {
-                    code: -32602,
-                    message: "Invalid params: requires [view_name, input_data, height]".to_string(),
-                    data: None,
-                },
-                jsonrpc: "2.0".to_string(),
-            }));
+    match body.into_inner() {
+        Value::Array(requests) => {
+            if requests.is_empty() || requests.len() > state.max_batch_size {
+                return Ok(HttpResponse::Ok().json(rpc_error(
+                    None,
+                    -32600,
+                    format!(
+                        "Invalid request: batch must hold between 1 and {} requests, got {}",
+                        state.max_batch_size,
+                        requests.len()
+                    ),
+                )));
+            }
+            // Views yield cooperatively, so the calls interleave on this worker
+            let responses = join_all(requests.into_iter().map(|request| dispatch_value(request, &state))).await;
+            Ok(HttpResponse::Ok().json(responses))
         }
+        request => Ok(HttpResponse::Ok().json(dispatch_value(request, &state).await)),
+    }
+}
 
-        let view_name = match body.params[0].as_str() {
-            Some(s) => s.to_string(),
-            None => {
-                return Ok(HttpResponse::Ok().json(JsonRpcError {
-                    id: body.id,
-                    error: JsonRpcErrorObject {
-                        code: -32602,
-                        message: "Invalid params: view_name must be a string".to_string(),
-                        data: None,
-                    },
-                    jsonrpc: "2.0".to_string(),
-                }))
-            }
-        };
+// Run one request of a single or batch call, reporting any failure as a JSON-RPC error
+async fn dispatch_value(request: Value, state: &AppState) -> Value {
+    let body: JsonRpcRequest = match serde_json::from_value(request) {
+        Ok(body) => body,
+        Err(e) => return rpc_error(None, -32600, format!("Invalid request: {}", e)),
+    };
+    match dispatch(body, state).await {
+        Ok(response) | Err(response) => response,
+    }
+}
 
-        let input_hex = match body.params[1].as_str() {
-            Some(s) => s.to_string(),
-            None => {
-                return Ok(HttpResponse::Ok().json(JsonRpcError {
-                    id: body.id,
-                    error: JsonRpcErrorObject {
-                        code: -32602,
-                        message: "Invalid params: input_data must be a hex string".to_string(),
-                        data: None,
-                    },
-                    jsonrpc: "2.0".to_string(),
-                }))
-            }
-        };
+fn rpc_value<T: Serialize>(response: T) -> Value {
+    serde_json::to_value(response).unwrap_or(Value::Null)
+}
 
-        let height = match &body.params[2] {
-            Value::String(s) if s == "latest" => CURRENT_HEIGHT.load(Ordering::SeqCst),
-            Value::Number(n) => n.as_u64().unwrap_or(0) as u32,
-            _ => {
-                return Ok(HttpResponse::Ok().json(JsonRpcError {
-                    id: body.id,
-                    error: JsonRpcErrorObject {
-                        code: -32602,
-                        message: "Invalid params: height must be a number or 'latest'".to_string(),
-                        data: None,
-                    },
-                    jsonrpc: "2.0".to_string(),
-                }))
-            }
-        };
+/// An error response without data. `id` is `None` when the request's own id
+/// could not be read.
+fn rpc_error(id: Option<u32>, code: i32, message: impl Into<String>) -> Value {
+    rpc_value(JsonRpcError {
+        id,
+        error: JsonRpcErrorObject {
+            code,
+            message: message.into(),
+            data: None,
+        },
+        jsonrpc: "2.0".to_string(),
+    })
+}
 
-        // Acquire a read lock on the runtime - this allows multiple readers
-        let runtime = state.runtime.read().await;
-        
-        // Decode input data outside the lock if possible
-        let input_data = match hex::decode(input_hex.trim_start_matches("0x")) {
-            Ok(data) => data,
-            Err(e) => return Ok(HttpResponse::Ok().json(JsonRpcError {
-                id: body.id,
-                error: JsonRpcErrorObject {
-                    code: -32602,
-                    message: format!("Invalid hex input: {}", e),
-                    data: None,
-                },
-                jsonrpc: "2.0".to_string(),
-            })),
-        };
+/// An invalid params error for `body`.
+fn invalid_params(body: &JsonRpcRequest, message: impl std::fmt::Display) -> Value {
+    rpc_error(Some(body.id), -32602, format!("Invalid params: {}", message))
+}
 
-        // Use await with the async view function
-        match runtime.view(
-            view_name,
-            &input_data,
-            height,
-        ).await {
-            Ok(result) => Ok(HttpResponse::Ok().json(JsonRpcResult {
-                id: body.id,
-                result: format!("0x{}", hex::encode(result)),
-                jsonrpc: "2.0".to_string(),
-            })),
-            Err(err) => Ok(HttpResponse::Ok().json(JsonRpcError {
-                id: body.id,
-                error: JsonRpcErrorObject {
-                    code: -32000,
-                    message: err.to_string(),
-                    data: None,
-                },
-                jsonrpc: "2.0".to_string(),
-            })),
-        }
-    } else if body.method == "metashrew_preview" {
-        // Acquire a read lock on the runtime - this allows multiple readers
-        let runtime = state.runtime.read().await;
-        // Ensure we have required params
-        if body.params.len() < 4 {
-            return Ok(HttpResponse::Ok().json(JsonRpcError {
-                id: body.id,
-                error: JsonRpcErrorObject {
-                    code: -32602,
-                    message: "Invalid params: requires [block_data, view_name, input_data, height]"
-                        .to_string(),
-                    data: None,
-                },
-                jsonrpc: "2.0".to_string(),
-            }));
-        }
+/// Fails unless `body` has a number of params in `count`, with `usage`
+/// naming them.
+fn param_count(body: &JsonRpcRequest, count: impl RangeBounds<usize>, usage: &str) -> std::result::Result<(), Value> {
+    if count.contains(&body.params.len()) {
+        Ok(())
+    } else {
+        Err(invalid_params(body, format!("requires {}", usage)))
+    }
+}
 
-        let block_hex = match body.params[0].as_str() {
-            Some(s) => s.to_string(),
-            None => {
-                return Ok(HttpResponse::Ok().json(JsonRpcError {
-                    id: body.id,
-                    error: JsonRpcErrorObject {
-                        code: -32602,
-                        message: "Invalid params: block_data must be a hex string".to_string(),
-                        data: None,
-                    },
-                    jsonrpc: "2.0".to_string(),
-                }))
-            }
-        };
+/// The string param at `index`, failing with `message` otherwise.
+fn param_str<'a>(body: &'a JsonRpcRequest, index: usize, message: &str) -> std::result::Result<&'a str, Value> {
+    body.params
+        .get(index)
+        .and_then(Value::as_str)
+        .ok_or_else(|| invalid_params(body, message))
+}
 
-        let view_name = match body.params[1].as_str() {
-            Some(s) => s.to_string(),
-            None => {
-                return Ok(HttpResponse::Ok().json(JsonRpcError {
-                    id: body.id,
-                    error: JsonRpcErrorObject {
-                        code: -32602,
-                        message: "Invalid params: view_name must be a string".to_string(),
-                        data: None,
-                    },
-                    jsonrpc: "2.0".to_string(),
-                }))
-            }
-        };
+/// The hex param at `index`, decoded, failing with `message` otherwise.
+fn param_hex(body: &JsonRpcRequest, index: usize, message: &str) -> std::result::Result<Vec<u8>, Value> {
+    hex::decode(param_str(body, index, message)?.trim_start_matches("0x")).map_err(|_| invalid_params(body, message))
+}
 
-        let input_hex = match body.params[2].as_str() {
-            Some(s) => s.to_string(),
-            None => {
-                return Ok(HttpResponse::Ok().json(JsonRpcError {
-                    id: body.id,
-                    error: JsonRpcErrorObject {
-                        code: -32602,
-                        message: "Invalid params: input_data must be a hex string".to_string(),
-                        data: None,
-                    },
-                    jsonrpc: "2.0".to_string(),
-                }))
-            }
-        };
+/// Decodes hex `data` passed to a view or preview, rejecting it as the
+/// view's invalid input, with `what` naming it.
+fn decode_view_input(body: &JsonRpcRequest, data: &str, what: &str) -> std::result::Result<Vec<u8>, Value> {
+    hex::decode(data.trim_start_matches("0x"))
+        .map_err(|e| view_error(body.id, ViewError::invalid_input(format!("Invalid hex {}: {}", what, e))))
+}
 
-        let height = match &body.params[3] {
-            Value::String(s) if s == "latest" => CURRENT_HEIGHT.load(Ordering::SeqCst),
-            Value::Number(n) => n.as_u64().unwrap_or(0) as u32,
-            _ => {
-                return Ok(HttpResponse::Ok().json(JsonRpcError {
-                    id: body.id,
-                    error: JsonRpcErrorObject {
-                        code: -32602,
-                        message: "Invalid params: height must be a number or 'latest'".to_string(),
-                        data: None,
-                    },
-                    jsonrpc: "2.0".to_string(),
-                }))
-            }
-        };
+/// The height param at `index`, with `None` meaning "latest".
+fn param_height(body: &JsonRpcRequest, index: usize) -> std::result::Result<Option<u32>, Value> {
+    match body.params.get(index) {
+        Some(Value::String(s)) if s == "latest" => Ok(None),
+        Some(Value::Number(n)) => Ok(Some(n.as_u64().unwrap_or(0) as u32)),
+        _ => Err(invalid_params(body, "height must be a number or 'latest'")),
+    }
+}
 
-        // Decode input data outside the lock if possible
-        let block_data = match hex::decode(block_hex.trim_start_matches("0x")) {
-            Ok(data) => data,
-            Err(e) => {
-                return Ok(HttpResponse::Ok().json(JsonRpcError {
-                    id: body.id,
-                    error: JsonRpcErrorObject {
-                        code: -32602,
-                        message: format!("Invalid hex block data: {}", e),
-                        data: None,
-                    },
-                    jsonrpc: "2.0".to_string(),
-                }))
-            }
-        };
-        
-        let input_data = match hex::decode(input_hex.trim_start_matches("0x")) {
-            Ok(data) => data,
-            Err(e) => return Ok(HttpResponse::Ok().json(JsonRpcError {
-                id: body.id,
-                error: JsonRpcErrorObject {
-                    code: -32602,
-                    message: format!("Invalid hex input: {}", e),
-                    data: None,
-                },
-                jsonrpc: "2.0".to_string(),
-            })),
-        };
-        
-        match runtime.preview_async(
-          &block_data,
-          view_name,
-          &input_data,
-          height,
-      ).await {
-            Ok(result) => Ok(HttpResponse::Ok().json(JsonRpcResult {
-                id: body.id,
-                result: format!("0x{}", hex::encode(result)),
-                jsonrpc: "2.0".to_string(),
-            })),
-            Err(err) => Ok(HttpResponse::Ok().json(JsonRpcError {
-                id: body.id,
-                error: JsonRpcErrorObject {
-                    code: -32000,
-                    message: err.to_string(),
-                    data: None,
-                },
-                jsonrpc: "2.0".to_string(),
-            })),
+/// Snapshots the views' database and resolves a requested height against
+/// the last block committed in that snapshot, with `None` meaning
+/// "latest". A block the indexer is still writing is never visible.
+fn pin_height(
+    views: &ViewHandle<RocksDBRuntimeAdapter>,
+    requested: Option<u32>,
+) -> std::result::Result<(ViewHandle<RocksDBRuntimeAdapter>, u32), ViewError> {
+    let mut views = views.snapshot().map_err(ViewError::internal)?;
+    let tip = views.db.committed_height().map_err(ViewError::internal)?.unwrap_or(0);
+    match requested {
+        Some(height) if height > tip => Err(ViewError::height_out_of_range(height, tip)),
+        Some(height) => Ok((views, height)),
+        None => Ok((views, tip)),
+    }
+}
+
+/// Whether a `metashrew_view` options param asks for the view's `__log` output.
+fn debug_requested(options: Option<&Value>) -> bool {
+    options
+        .and_then(|options| options.get("debug"))
+        .and_then(Value::as_bool)
+        .unwrap_or(false)
+}
+
+/// A `metashrew_view` result: the hex data alone, or the data with its
+/// `__log` output when the view ran in debug mode.
+fn view_response(id: u32, result: Vec<u8>, log: Option<Vec<String>>) -> Value {
+    let data = format!("0x{}", hex::encode(result));
+    match log {
+        Some(log) => rpc_value(JsonRpcResult {
+            id,
+            result: ViewDebugResult { data, log },
+            jsonrpc: "2.0".to_string(),
+        }),
+        None => rpc_value(JsonRpcResult {
+            id,
+            result: data,
+            jsonrpc: "2.0".to_string(),
+        }),
+    }
+}
+
+fn view_error(id: u32, err: ViewError) -> Value {
+    rpc_value(JsonRpcError {
+        id: Some(id),
+        error: err.into(),
+        jsonrpc: "2.0".to_string(),
+    })
+}
+
+/// Runs one request, with `Err` holding the error response for a request
+/// that could not be served.
+async fn dispatch(body: JsonRpcRequest, state: &AppState) -> std::result::Result<Value, Value> {
+    debug!("RPC request: {}", serde_json::to_string(&body).unwrap());
+
+    if body.method == "metashrew_view" {
+        param_count(&body, 3.., "[view_name, input_data, height]")?;
+        let view_name = param_str(&body, 0, "view_name must be a string")?.to_string();
+        let input_hex = param_str(&body, 1, "input_data must be a hex string")?;
+        let requested = param_height(&body, 2)?;
+        let input_data = decode_view_input(&body, input_hex, "input")?;
+        let (views, height) = pin_height(&state.views, requested).map_err(|err| view_error(body.id, err))?;
+
+        let started = std::time::Instant::now();
+        // Guest output is only captured when the caller asked to see it
+        let (result, log) = if debug_requested(body.params.get(3)) {
+            views
+                .view_with_log(view_name.clone(), &input_data, height)
+                .await
+                .map(|(result, log)| (result, Some(log)))
+        } else {
+            views
+                .view(view_name.clone(), &input_data, height)
+                .await
+                .map(|result| (result, None))
         }
+        .map_err(|err| view_error(body.id, err))?;
+        // Only successful calls are timed, so unknown view names add no series
+        metrics::VIEW_SECONDS
+            .with_label_values(&[&view_name])
+            .observe(started.elapsed().as_secs_f64());
+        Ok(view_response(body.id, result, log))
+    } else if body.method == "metashrew_preview" {
+        param_count(&body, 4.., "[block_data, view_name, input_data, height]")?;
+        let block_hex = param_str(&body, 0, "block_data must be a hex string")?;
+        let view_name = param_str(&body, 1, "view_name must be a string")?.to_string();
+        let input_hex = param_str(&body, 2, "input_data must be a hex string")?;
+        let requested = param_height(&body, 3)?;
+        let block_data = decode_view_input(&body, block_hex, "block data")?;
+        let input_data = decode_view_input(&body, input_hex, "input")?;
+        let (views, height) = pin_height(&state.views, requested).map_err(|err| view_error(body.id, err))?;
+
+        let result = views
+            .preview_async(&block_data, view_name, &input_data, height)
+            .await
+            .map_err(|err| view_error(body.id, err))?;
+        Ok(rpc_value(JsonRpcResult {
+            id: body.id,
+            result: format!("0x{}", hex::encode(result)),
+            jsonrpc: "2.0".to_string(),
+        }))
     } else if body.method == "metashrew_height" {
         // No need to lock the runtime for this operation
-        Ok(HttpResponse::Ok().json(JsonRpcResult {
+        Ok(rpc_value(JsonRpcResult {
             id: body.id,
             result: CURRENT_HEIGHT.load(Ordering::SeqCst).to_string(),
             jsonrpc: "2.0".to_string(),
         }))
-    } else if body.method == "metashrew_getblockhash" {
-        // Acquire a read lock on the runtime
-        let runtime = state.runtime.read().await;
-        if body.params.len() != 1 {
-            return Ok(HttpResponse::Ok().json(JsonRpcError {
+    } else if body.method == "metashrew_stateroot" {
+        param_count(&body, 1..=1, "[height]")?;
+        let requested = param_height(&body, 0)?;
+        let (mut views, height) = pin_height(&state.views, requested).map_err(|err| view_error(body.id, err))?;
+
+        match state_root(&mut views.db, height) {
+            Ok(Some(root)) => Ok(rpc_value(JsonRpcResult {
                 id: body.id,
-                error: JsonRpcErrorObject {
-                    code: -32602,
-                    message: "Invalid params: requires [block_number]".to_string(),
-                    data: None,
-                },
+                result: format!("0x{}", hex::encode(root)),
                 jsonrpc: "2.0".to_string(),
-            }));
+            })),
+            Err(e) => Err(view_error(body.id, ViewError::internal(e))),
+            Ok(None) => Err(rpc_error(Some(body.id), -32000, "State root not found")),
+        }
+    } else if body.method == "metashrew_getproof" {
+        if !state.views.options.state_proofs {
+            return Err(rpc_error(
+                Some(body.id),
+                -32601,
+                "State proofs are disabled; restart with --enable-state-proofs",
+            ));
+        }
+        param_count(&body, 2..=2, "[key, height]")?;
+        let key = param_hex(&body, 0, "key must be a hex string")?;
+        let requested = param_height(&body, 1)?;
+        let (views, height) = pin_height(&state.views, requested).map_err(|err| view_error(body.id, err))?;
+
+        let proof = views
+            .prove(&key, height)
+            .map_err(|e| view_error(body.id, ViewError::internal(e)))?;
+        Ok(rpc_value(JsonRpcResult {
+            id: body.id,
+            result: ProofResult {
+                height,
+                root: format!("0x{}", hex::encode(proof.root)),
+                value: proof.value.map(|value| format!("0x{}", hex::encode(value))),
+                siblings: proof
+                    .proof
+                    .siblings
+                    .iter()
+                    .map(|sibling| format!("0x{}", hex::encode(sibling)))
+                    .collect(),
+                leaf: proof.proof.leaf.map(|(path, value_hash)| ProofLeaf {
+                    path: format!("0x{}", hex::encode(path)),
+                    value_hash: format!("0x{}", hex::encode(value_hash)),
+                }),
+            },
+            jsonrpc: "2.0".to_string(),
+        }))
+    } else if body.method == "metashrew_getkey" {
+        if !state.debug_rpc {
+            return Err(rpc_error(
+                Some(body.id),
+                -32601,
+                "Debug RPCs are disabled; restart with --enable-debug-rpc",
+            ));
+        }
+        param_count(&body, 2..=2, "[key, height]")?;
+        let key = param_hex(&body, 0, "key must be a hex string")?;
+        let requested = param_height(&body, 1)?;
+        let (views, height) = pin_height(&state.views, requested).map_err(|err| view_error(body.id, err))?;
+
+        let result = views
+            .get_key(&key, height)
+            .map_err(|e| view_error(body.id, ViewError::internal(e)))?;
+        Ok(rpc_value(JsonRpcResult {
+            id: body.id,
+            result,
+            jsonrpc: "2.0".to_string(),
+        }))
+    } else if body.method == "metashrew_getkeyhistory" {
+        if !state.debug_rpc {
+            return Err(rpc_error(
+                Some(body.id),
+                -32601,
+                "Debug RPCs are disabled; restart with --enable-debug-rpc",
+            ));
         }
+        param_count(&body, 1..=1, "[key]")?;
+        let key = param_hex(&body, 0, "key must be a hex string")?;
+        // Leave out anything written above the committed tip
+        let (views, tip) = pin_height(&state.views, None).map_err(|err| view_error(body.id, err))?;
 
+        let result = views
+            .get_key_history(&key, tip)
+            .map_err(|e| view_error(body.id, ViewError::internal(e)))?;
+        Ok(rpc_value(JsonRpcResult {
+            id: body.id,
+            result,
+            jsonrpc: "2.0".to_string(),
+        }))
+    } else if body.method == "metashrew_getblockhash" {
+        param_count(&body, 1..=1, "[block_number]")?;
         let height = match &body.params[0] {
             Value::Number(n) => n.as_u64().unwrap_or(0) as u32,
-            _ => {
-                return Ok(HttpResponse::Ok().json(JsonRpcError {
-                    id: body.id,
-                    error: JsonRpcErrorObject {
-                        code: -32602,
-                        message: "Invalid params: block_number must be a number".to_string(),
-                        data: None,
-                    },
-                    jsonrpc: "2.0".to_string(),
-                }))
-            }
+            _ => return Err(invalid_params(&body, "block_number must be a number")),
         };
 
         let key = (String::from(HEIGHT_TO_HASH) + &height.to_string()).into_bytes();
-        
-        // Fix lifetime issue by storing the context in a variable
-        let mut context = runtime.context.lock().unwrap();
-        let result = context.db.get(&key).map_err(|_| {
-            <anyhow::Error as Into<IndexerError>>::into(anyhow!(
-                "DB connection error while fetching blockhash"
-            ))
+        let result = state.views.db.clone().get(&key).map_err(|_| {
+            rpc_error(Some(body.id), -32000, "DB connection error while fetching blockhash")
         })?;
-        
+
         match result {
-            Some(hash) => Ok(HttpResponse::Ok().json(JsonRpcResult {
+            Some(hash) => Ok(rpc_value(JsonRpcResult {
                 id: body.id,
                 result: format!("0x{}", hex::encode(hash)),
                 jsonrpc: "2.0".to_string(),
             })),
-            None => Ok(HttpResponse::Ok().json(JsonRpcError {
-                id: body.id,
-                error: JsonRpcErrorObject {
-                    code: -32000,
-                    message: "Block hash not found".to_string(),
-                    data: None,
-                },
-                jsonrpc: "2.0".to_string(),
-            })),
+            None => Err(rpc_error(Some(body.id), -32000, "Block hash not found")),
         }
     } else {
-        Ok(HttpResponse::Ok().json(JsonRpcError {
-            id: body.id,
-            error: JsonRpcErrorObject {
-                code: -32601,
-                message: format!("Method '{}' not found", body.method),
-                data: None,
-            },
-            jsonrpc: "2.0".to_string(),
-        }))
+        Err(rpc_error(
+            Some(body.id),
+            -32601,
+            format!("Method '{}' not found", body.method),
+        ))
     }
 }
 
@@ -1149,11 +1131,26 @@ async fn async_main(args: Arc<Args>, start_block: u32) -> Result<()> {
     // Removed deprecated call to set_max_background_compactions
     opts.set_disable_auto_compactions(false);
 
+    // Open the database and bring its storage layout up to date
+    let adapter = RocksDBRuntimeAdapter::open(args.db_path.clone(), opts)?;
+    migrate_storage(&adapter.db)?;
+
+    let tip = query_height(adapter.db.clone(), start_block).await?;
+
     // Create runtime with RocksDB adapter
-    let runtime = Arc::new(RwLock::new(MetashrewRuntime::load(
-      PathBuf::from(&args.indexer),
-      RocksDBRuntimeAdapter::open(args.db_path.clone(), opts)?,
-  )?));
+    let runtime = MetashrewRuntime::load_with_options(
+        PathBuf::from(&args.indexer),
+        adapter,
+        args.runtime_options(),
+    )?;
+
+    // Repair any block left half-applied by an unclean shutdown before resuming
+    if journal::recover(runtime.context.clone(), tip)? {
+        info!("Recovered uncommitted state above block {}", tip);
+    }
+    runtime.verify_wasm(args.allow_wasm_mismatch)?;
+    let views = runtime.view_handle()?;
+    let runtime = Arc::new(RwLock::new(runtime));
 
     // Create indexer state
     let mut indexer = IndexerState {
@@ -1164,6 +1161,11 @@ async fn async_main(args: Arc<Args>, start_block: u32) -> Result<()> {
         processor_thread_id_tx: None,
         fetcher_thread_id: std::sync::Mutex::new(None),
         processor_thread_id: std::sync::Mutex::new(None),
+        source: args.source.build(&args.daemon_rpc_url, args.auth.as_deref())?,
+        notifier: match &args.zmq_hashblock {
+            Some(endpoint) => BlockNotifier::zmq(endpoint.clone()),
+            None => BlockNotifier::polling(),
+        },
     };
     
     // Log the pipeline size configuration
@@ -1181,7 +1183,10 @@ async fn async_main(args: Arc<Args>, start_block: u32) -> Result<()> {
 
     // Create app state for JSON-RPC server
     let app_state = web::Data::new(AppState {
-        runtime: runtime.clone(),
+        views,
+        max_lag: args.max_lag,
+        max_batch_size: args.max_batch_size,
+        debug_rpc: args.enable_debug_rpc,
     });
 
     // Create a channel to communicate thread IDs
@@ -1198,16 +1203,14 @@ async fn async_main(args: Arc<Args>, start_block: u32) -> Result<()> {
     indexer.set_thread_id_senders(thread_id_tx.clone(), thread_id_tx.clone());
     
     // Start the indexer in a separate task
-    let indexer_handle = tokio::spawn(async move {
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut indexer_handle = tokio::spawn(async move {
         info!("Starting indexer task");
-        
-        if let Err(e) = indexer.run_pipeline().await {
-            error!("Indexer error: {}", e);
-        }
+        indexer.run_pipeline(shutdown_rx).await
     });
 
-    // Start the JSON-RPC server
-    let server_handle = tokio::spawn({
+    // Start the JSON-RPC server; signals are handled below so the indexer stops first
+    let server = {
         let args_clone = args.clone();
         HttpServer::new(move || {
             let cors = match &args_clone.cors {
@@ -1243,25 +1246,137 @@ async fn async_main(args: Arc<Args>, start_block: u32) -> Result<()> {
                 .wrap(cors)
                 .app_data(app_state.clone())
                 .service(handle_jsonrpc)
+                .service(metrics::handle_metrics)
+                .service(health::handle_health)
+                .service(health::handle_ready)
         })
         .bind((args.host.as_str(), args.port))?
+        .disable_signals()
         .run()
-    });
+    };
+    let server_handle = server.handle();
+    let mut server_task = tokio::spawn(server);
     info!("Server running at http://{}:{}", args.host, args.port);
 
-    // Wait for either component to finish (or fail)
-    tokio::select! {
-        result = indexer_handle => {
-            if let Err(e) = result {
-                log::error!("Indexer task failed: {}", e);
-            }
+    // Run until the indexer finishes or fails, a signal arrives or the server dies
+    let indexed = tokio::select! {
+        result = &mut indexer_handle => result,
+        _ = shutdown_signal() => {
+            info!("Received shutdown signal");
+            health::SHUTTING_DOWN.store(true, Ordering::SeqCst);
+            let _ = shutdown_tx.send(true);
+            (&mut indexer_handle).await
         }
-        result = server_handle => {
-            if let Err(e) = result {
-                log::error!("Server task failed: {}", e);
+        result = &mut server_task => {
+            error!("JSON-RPC server stopped: {:?}", result);
+            health::SHUTTING_DOWN.store(true, Ordering::SeqCst);
+            let _ = shutdown_tx.send(true);
+            (&mut indexer_handle).await
+        }
+    };
+
+    health::SHUTTING_DOWN.store(true, Ordering::SeqCst);
+    info!("Stopping JSON-RPC server");
+    server_handle.stop(true).await;
+
+    // Flush memtables and the WAL so the next start has nothing to replay
+    let db = {
+        let runtime = runtime.read().await;
+        let context = runtime.context.lock().map_err(|e| anyhow!("Failed to lock context: {}", e))?;
+        context.db.db.clone()
+    };
+    db.flush()?;
+    db.flush_wal(true)?;
+    db.cancel_all_background_work(true);
+    info!("Database closed at block {}", CURRENT_HEIGHT.load(Ordering::SeqCst));
+
+    match indexed {
+        Ok(result) => result,
+        Err(e) => Err(anyhow!("Indexer task failed: {}", e)),
+    }
+}
+
+// Resolves on SIGINT or SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {},
+                    _ = terminate.recv() => {},
+                }
+            }
+            Err(e) => {
+                error!("Failed to install SIGTERM handler: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
             }
         }
     }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok(())
+    fn request(params: Value) -> JsonRpcRequest {
+        serde_json::from_value(serde_json::json!({
+            "id": 7,
+            "method": "metashrew_getkey",
+            "params": params,
+            "jsonrpc": "2.0",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_params() {
+        let body = request(serde_json::json!(["0x6b", 5]));
+        assert_eq!(param_count(&body, 2..=2, "[key, height]"), Ok(()));
+        assert_eq!(param_hex(&body, 0, "key must be a hex string"), Ok(b"k".to_vec()));
+        assert_eq!(param_height(&body, 1), Ok(Some(5)));
+        let body = request(serde_json::json!(["zz", "latest"]));
+        assert_eq!(param_height(&body, 1), Ok(None));
+
+        let invalid = param_hex(&body, 0, "key must be a hex string").unwrap_err();
+        assert_eq!(invalid["id"], 7);
+        assert_eq!(invalid["error"]["code"], -32602);
+        assert_eq!(invalid["error"]["message"], "Invalid params: key must be a hex string");
+        assert!(param_height(&body, 0).is_err());
+        assert!(param_height(&body, 2).is_err());
+        assert!(param_count(&body, 1..=1, "[key]").is_err());
+        assert!(param_str(&request(serde_json::json!([1])), 0, "key must be a hex string").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_hex_as_invalid_view_input() {
+        let body = request(serde_json::json!([]));
+        assert_eq!(decode_view_input(&body, "0x6b", "input"), Ok(b"k".to_vec()));
+        let error = decode_view_input(&body, "0xzz", "input").unwrap_err();
+        assert_eq!(error["id"], 7);
+        assert_eq!(error["error"]["code"], -32602);
+        assert_eq!(error["error"]["data"]["kind"], "invalid_input");
+    }
+
+    #[test]
+    fn returns_view_logs_only_in_debug_mode() {
+        assert!(debug_requested(Some(&serde_json::json!({ "debug": true }))));
+        assert!(!debug_requested(Some(&serde_json::json!({}))));
+        assert!(!debug_requested(None));
+        let plain = view_response(7, b"ok".to_vec(), None);
+        assert_eq!(plain["result"], "0x6f6b");
+        let debug = view_response(7, b"ok".to_vec(), Some(vec!["hello\n".to_string()]));
+        assert_eq!(debug["result"]["data"], "0x6f6b");
+        assert_eq!(debug["result"]["log"], serde_json::json!(["hello\n"]));
+    }
+
+    #[test]
+    fn reports_unreadable_ids_as_null() {
+        let error = rpc_error(None, -32600, "Invalid request");
+        assert_eq!(error["id"], Value::Null);
+        assert_eq!(error["error"]["code"], -32600);
+    }
 }
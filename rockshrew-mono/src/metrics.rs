@@ -0,0 +1,132 @@
+use actix_web::{get, HttpResponse, Responder};
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, register_gauge, register_histogram, register_histogram_vec,
+    register_int_counter, register_int_gauge, Encoder, Gauge, Histogram, HistogramVec, IntCounter,
+    IntGauge, TextEncoder,
+};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Blocks over which `metashrew_blocks_per_second` is averaged.
+const RATE_WINDOW: usize = 100;
+
+lazy_static! {
+    pub static ref INDEXED_HEIGHT: IntGauge = register_int_gauge!(
+        "metashrew_indexed_height",
+        "Height of the last block committed by the indexer"
+    )
+    .unwrap();
+    pub static ref NODE_TIP: IntGauge = register_int_gauge!(
+        "metashrew_node_tip_height",
+        "Best block height last reported by the block source"
+    )
+    .unwrap();
+    pub static ref BLOCKS_PROCESSED: IntCounter = register_int_counter!(
+        "metashrew_blocks_processed_total",
+        "Blocks committed by the indexer"
+    )
+    .unwrap();
+    pub static ref BLOCKS_SKIPPED: IntCounter = register_int_counter!(
+        "metashrew_blocks_skipped_total",
+        "Blocks committed without index changes by --on-block-failure skip"
+    )
+    .unwrap();
+    pub static ref BLOCKS_PER_SECOND: Gauge = register_gauge!(
+        "metashrew_blocks_per_second",
+        "Indexing rate over the last 100 blocks"
+    )
+    .unwrap();
+    pub static ref START_SECONDS: Histogram = register_histogram!(
+        "metashrew_block_start_seconds",
+        "Wall time of the indexer's _start per block",
+        exponential_buckets(0.001, 2.0, 18).unwrap()
+    )
+    .unwrap();
+    pub static ref FLUSHED_KEYS: Histogram = register_histogram!(
+        "metashrew_block_flushed_keys",
+        "Keys written by __flush per block",
+        exponential_buckets(1.0, 4.0, 12).unwrap()
+    )
+    .unwrap();
+    pub static ref WASM_MEMORY_BYTES: IntGauge = register_int_gauge!(
+        "metashrew_wasm_memory_bytes",
+        "Linear memory size of the indexer instance"
+    )
+    .unwrap();
+    pub static ref MEMORY_REFRESHES: IntCounter = register_int_counter!(
+        "metashrew_memory_refreshes_total",
+        "Times the indexer instance was recreated to reclaim memory"
+    )
+    .unwrap();
+    pub static ref VIEW_SECONDS: HistogramVec = register_histogram_vec!(
+        "metashrew_view_seconds",
+        "Latency of successful metashrew_view calls",
+        &["view"],
+        exponential_buckets(0.0005, 2.0, 16).unwrap()
+    )
+    .unwrap();
+    pub static ref RPC_RETRIES: IntCounter = register_int_counter!(
+        "metashrew_rpc_retries_total",
+        "Requests to the block source retried after a transport failure"
+    )
+    .unwrap();
+    static ref RECENT_BLOCKS: Mutex<VecDeque<Instant>> = Mutex::new(VecDeque::new());
+}
+
+/// Records a committed block and updates the indexing rate.
+pub fn record_block(height: u32) {
+    INDEXED_HEIGHT.set(height as i64);
+    BLOCKS_PROCESSED.inc();
+    let mut recent = RECENT_BLOCKS.lock().unwrap();
+    recent.push_back(Instant::now());
+    if recent.len() > RATE_WINDOW {
+        recent.pop_front();
+    }
+    if let (Some(first), Some(last)) = (recent.front(), recent.back()) {
+        let elapsed = last.duration_since(*first);
+        if elapsed > Duration::ZERO {
+            BLOCKS_PER_SECOND.set((recent.len() - 1) as f64 / elapsed.as_secs_f64());
+        }
+    }
+}
+
+/// Brings a counter kept elsewhere up to `total`.
+pub fn sync_counter(counter: &IntCounter, total: u64) {
+    let current = counter.get();
+    if total > current {
+        counter.inc_by(total - current);
+    }
+}
+
+#[get("/metrics")]
+pub async fn handle_metrics() -> impl Responder {
+    sync_counter(&RPC_RETRIES, metashrew_blocksource::retries());
+    let mut buffer = vec![];
+    match TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        Ok(_) => HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(buffer),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+
+    #[actix_web::test]
+    async fn exposes_recorded_blocks() {
+        record_block(41);
+        record_block(42);
+        let app = test::init_service(App::new().service(handle_metrics)).await;
+        let response = test::call_service(&app, test::TestRequest::get().uri("/metrics").to_request()).await;
+        assert!(response.status().is_success());
+        let body = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+        assert!(body.contains("metashrew_indexed_height 42"));
+        assert!(body.contains("metashrew_blocks_processed_total"));
+        assert!(body.contains("metashrew_rpc_retries_total 0"));
+    }
+}
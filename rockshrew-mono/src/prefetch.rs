@@ -0,0 +1,267 @@
+use anyhow::Result;
+use log::{debug, error};
+use metashrew_blocksource::{BlockNotifier, BlockSource};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+/// A fetched block: height, blockhash and serialized block.
+pub type Fetched = (u32, Vec<u8>, Vec<u8>);
+
+struct State {
+    /// Bumped on every rewind so fetches claimed before it are discarded
+    epoch: u64,
+    next_claim: u32,
+    next_send: u32,
+    /// Last height to fetch, from --exit-at
+    last: Option<u32>,
+    ready: BTreeMap<u32, (Vec<u8>, Vec<u8>)>,
+    bytes: usize,
+    /// Node tip as last seen by a worker, so catch-up skips `getblockcount`
+    tip: Option<u32>,
+    closed: bool,
+}
+
+/// Fetches blocks ahead of the processor with several concurrent workers and
+/// hands them back strictly in height order.
+///
+/// Workers claim heights in order and park finished blocks in a reassembly
+/// buffer until the processor reaches them. No new height is claimed while
+/// the buffer holds `max_bytes` or more, so memory stays within `max_bytes`
+/// plus one block per worker. The next height the processor needs is always
+/// claimed before any later one, so a full buffer cannot starve it.
+pub struct Prefetcher {
+    state: Mutex<State>,
+    max_bytes: usize,
+    changed: Notify,
+}
+
+impl Prefetcher {
+    pub fn new(start: u32, last: Option<u32>, max_bytes: usize) -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(State {
+                epoch: 0,
+                next_claim: start,
+                next_send: start,
+                last,
+                ready: BTreeMap::new(),
+                bytes: 0,
+                tip: None,
+                closed: false,
+            }),
+            max_bytes,
+            changed: Notify::new(),
+        })
+    }
+
+    /// Starts `count` fetch workers pulling from `source`.
+    pub fn spawn_workers(
+        self: &Arc<Self>,
+        count: usize,
+        source: Arc<dyn BlockSource>,
+        notifier: BlockNotifier,
+    ) -> Vec<JoinHandle<()>> {
+        (0..count.max(1))
+            .map(|_| tokio::spawn(self.clone().work(source.clone(), notifier.clone())))
+            .collect()
+    }
+
+    async fn work(self: Arc<Self>, source: Arc<dyn BlockSource>, notifier: BlockNotifier) {
+        while let Some((epoch, height)) = self.claim().await {
+            while self.is_current(epoch) {
+                match self.fetch(source.as_ref(), &notifier, epoch, height).await {
+                    Ok(Some((blockhash, block))) => {
+                        debug!("Fetched block {} ({})", height, block.len());
+                        self.complete(epoch, height, blockhash, block);
+                        break;
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        error!("Failed to fetch block {}: {}", height, e);
+                        self.state.lock().unwrap().tip = None;
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Waits for the node to reach `height` and fetches it. Returns `None`
+    /// if the claim went stale while waiting.
+    async fn fetch(
+        &self,
+        source: &dyn BlockSource,
+        notifier: &BlockNotifier,
+        epoch: u64,
+        height: u32,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        while self.state.lock().unwrap().tip.is_none_or(|tip| tip < height) {
+            let tip = source.tip().await?;
+            self.state.lock().unwrap().tip = Some(tip);
+            crate::metrics::NODE_TIP.set(tip as i64);
+            crate::health::NODE_TIP.store(tip, std::sync::atomic::Ordering::SeqCst);
+            if tip >= height {
+                break;
+            }
+            if !self.is_current(epoch) {
+                return Ok(None);
+            }
+            notifier.wait(Duration::from_millis(3000)).await;
+        }
+        Ok(Some(source.block_at(height).await?))
+    }
+
+    /// Claims the next height to fetch, waiting while the buffer is full.
+    /// Returns `None` once the prefetcher is closed.
+    async fn claim(&self) -> Option<(u64, u32)> {
+        loop {
+            let changed = self.changed.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.closed {
+                    return None;
+                }
+                let past_last = state.last.is_some_and(|last| state.next_claim > last);
+                if !past_last && state.bytes < self.max_bytes {
+                    let height = state.next_claim;
+                    state.next_claim += 1;
+                    return Some((state.epoch, height));
+                }
+            }
+            changed.await;
+        }
+    }
+
+    fn is_current(&self, epoch: u64) -> bool {
+        let state = self.state.lock().unwrap();
+        !state.closed && state.epoch == epoch
+    }
+
+    fn complete(&self, epoch: u64, height: u32, blockhash: Vec<u8>, block: Vec<u8>) {
+        let mut state = self.state.lock().unwrap();
+        if state.epoch != epoch {
+            return;
+        }
+        state.bytes += block.len();
+        state.ready.insert(height, (blockhash, block));
+        drop(state);
+        self.changed.notify_waiters();
+    }
+
+    /// The next block in height order. Returns `None` after the last height
+    /// or once the prefetcher is closed.
+    pub async fn next(&self) -> Option<Fetched> {
+        loop {
+            let changed = self.changed.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.closed || state.last.is_some_and(|last| state.next_send > last) {
+                    return None;
+                }
+                let height = state.next_send;
+                if let Some((blockhash, block)) = state.ready.remove(&height) {
+                    state.bytes -= block.len();
+                    state.next_send += 1;
+                    drop(state);
+                    self.changed.notify_waiters();
+                    return Some((height, blockhash, block));
+                }
+            }
+            changed.await;
+        }
+    }
+
+    /// Discards everything fetched so far and restarts from `height`, after
+    /// a reorg rollback or a block that has to be fetched again.
+    pub fn rewind(&self, height: u32) {
+        let mut state = self.state.lock().unwrap();
+        debug!("Prefetcher rewinding to block {}", height);
+        state.epoch += 1;
+        state.next_claim = height;
+        state.next_send = height;
+        state.ready.clear();
+        state.bytes = 0;
+        state.tip = None;
+        drop(state);
+        self.changed.notify_waiters();
+    }
+
+    /// Stops the workers and ends `next`.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.changed.notify_waiters();
+    }
+
+    #[cfg(test)]
+    fn buffered_bytes(&self) -> usize {
+        self.state.lock().unwrap().bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metashrew_blocksource::MemoryBlockSource;
+
+    const BLOCK_SIZE: usize = 100;
+
+    fn chain(len: u8) -> Arc<MemoryBlockSource> {
+        let source = Arc::new(MemoryBlockSource::new());
+        for h in 0..len {
+            source.push(vec![h; 32], vec![h; BLOCK_SIZE]);
+        }
+        source
+    }
+
+    #[tokio::test]
+    async fn delivers_in_order_up_to_last() {
+        let prefetcher = Prefetcher::new(2, Some(40), usize::MAX);
+        let workers = prefetcher.spawn_workers(8, chain(50), BlockNotifier::polling());
+        for height in 2..=40u32 {
+            let (h, blockhash, block) = prefetcher.next().await.unwrap();
+            assert_eq!(h, height);
+            assert_eq!(blockhash, vec![height as u8; 32]);
+            assert_eq!(block[0], height as u8);
+        }
+        assert!(prefetcher.next().await.is_none());
+        prefetcher.close();
+        for worker in workers {
+            worker.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn bounds_buffered_bytes() {
+        let workers = 4;
+        let max_bytes = 3 * BLOCK_SIZE;
+        let prefetcher = Prefetcher::new(0, None, max_bytes);
+        prefetcher.spawn_workers(workers, chain(50), BlockNotifier::polling());
+        for height in 0..20u32 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            let buffered = prefetcher.buffered_bytes();
+            assert!(buffered <= max_bytes + workers * BLOCK_SIZE, "{} bytes buffered", buffered);
+            assert_eq!(prefetcher.next().await.unwrap().0, height);
+        }
+        prefetcher.close();
+    }
+
+    #[tokio::test]
+    async fn rewind_refetches_from_new_chain() {
+        let source = chain(10);
+        let prefetcher = Prefetcher::new(0, None, usize::MAX);
+        prefetcher.spawn_workers(3, source.clone(), BlockNotifier::polling());
+        for height in 0..6u32 {
+            assert_eq!(prefetcher.next().await.unwrap().0, height);
+        }
+        source.truncate(3);
+        source.push(vec![0xaa; 32], vec![0xaa; BLOCK_SIZE]);
+        prefetcher.rewind(4);
+        let (height, blockhash, _) = prefetcher.next().await.unwrap();
+        assert_eq!(height, 4);
+        assert_eq!(blockhash, vec![0xaa; 32]);
+        prefetcher.close();
+        assert!(prefetcher.next().await.is_none());
+    }
+}
@@ -0,0 +1,209 @@
+use anyhow::{anyhow, Result};
+use log::info;
+use metashrew_blocksource::BlockSource;
+use metashrew_runtime::{BatchLike, KeyValueStoreLike, MetashrewRuntime, MetashrewRuntimeContext};
+use rockshrew_runtime::{RocksDBBatch, RocksDBRuntimeAdapter};
+use std::sync::{Arc, Mutex};
+
+pub const HEIGHT_TO_HASH: &str = "/__INTERNAL/height-to-hash/";
+
+type Context = Arc<Mutex<MetashrewRuntimeContext<RocksDBRuntimeAdapter>>>;
+
+pub fn height_to_hash_key(height: u32) -> Vec<u8> {
+    (String::from(HEIGHT_TO_HASH) + &height.to_string()).into_bytes()
+}
+
+pub fn stored_blockhash(db: &mut RocksDBRuntimeAdapter, height: u32) -> Result<Option<Vec<u8>>> {
+    db.get(height_to_hash_key(height))
+        .map_err(|e| anyhow!("failed to read blockhash for block {}: {}", height, e))
+}
+
+/// Returns the previous blockhash committed to by a raw serialized block,
+/// reversed into RPC display order to match the stored hashes.
+pub fn prev_blockhash(block: &[u8]) -> Option<Vec<u8>> {
+    let mut hash = block.get(4..36)?.to_vec();
+    hash.reverse();
+    Some(hash)
+}
+
+/// Walks back from the indexed `tip` until the stored blockhash agrees with
+/// the node. Returns `None` if `tip` itself is still canonical, otherwise the
+/// highest height both chains share. A height with no stored hash (e.g. below
+/// the configured start block) is taken as agreeing.
+pub async fn find_fork_point<S: BlockSource + ?Sized>(
+    node: &S,
+    db: &mut RocksDBRuntimeAdapter,
+    tip: u32,
+) -> Result<Option<u32>> {
+    let node_tip = node.tip().await?;
+    let mut height = tip;
+    loop {
+        let canonical = match stored_blockhash(db, height)? {
+            None => true,
+            // Anything above the node's tip has been orphaned from its view
+            Some(_) if height > node_tip => false,
+            Some(stored) => stored == node.blockhash(height).await?,
+        };
+        if canonical {
+            return Ok(if height == tip { None } else { Some(height) });
+        }
+        if height == 0 {
+            return Err(anyhow!("indexed chain diverges from the node at genesis"));
+        }
+        height -= 1;
+    }
+}
+
+/// Reverts every key touched in `fork + 1..=tip` and removes the orphaned
+/// height-to-hash and update-list entries, committing everything in a single
+/// write that also moves the tip back to `fork + 1`.
+pub fn rollback_to(context: Context, fork: u32, tip: u32) -> Result<()> {
+    let mut batch = RocksDBBatch::default();
+    let keys = MetashrewRuntime::db_rollback_blocks(context.clone(), &mut batch, fork + 1, tip)?;
+    for height in fork + 1..=tip {
+        batch.delete(height_to_hash_key(height));
+    }
+    let mut guard = context
+        .lock()
+        .map_err(|e| anyhow!("failed to lock context: {}", e))?;
+    guard.db.set_height(fork);
+    guard.db.write(batch)?;
+    info!(
+        "rolled back {} keys across blocks {}..={} to fork point {}",
+        keys.len(),
+        fork + 1,
+        tip,
+        fork
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metashrew_blocksource::MemoryBlockSource;
+    use metashrew_runtime::{
+        db_annotate_value, db_make_length_key, db_make_list_key, state_root_key, u32_to_vec,
+    };
+    use rocksdb::Options;
+    use tempdir::TempDir;
+
+    fn hash(height: u32, branch: u8) -> Vec<u8> {
+        vec![branch, height as u8]
+    }
+
+    /// A node whose chain matches the one `index_chain` stores up to `fork`
+    /// and continues on `branch` above it, up to `tip`.
+    fn node(tip: u32, fork: u32, branch: u8) -> MemoryBlockSource {
+        let source = MemoryBlockSource::new();
+        for h in 0..=tip {
+            source.push(hash(h, if h > fork { branch } else { 0 }), vec![]);
+        }
+        source
+    }
+
+    fn open(dir: &TempDir) -> Context {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        let db = RocksDBRuntimeAdapter::open(dir.path().to_str().unwrap().to_string(), opts)
+            .unwrap();
+        Arc::new(Mutex::new(MetashrewRuntimeContext {
+            db,
+            height: 0,
+            block: vec![],
+            state: 0,
+        }))
+    }
+
+    fn get(context: &Context, key: &Vec<u8>) -> Option<Vec<u8>> {
+        context.lock().unwrap().db.get(key).unwrap()
+    }
+
+    /// Indexes `tip + 1` blocks as `__flush` would, each setting `key` to
+    /// its height and recording a state root and the branch 0 blockhash.
+    fn index_chain(context: &Context, key: &Vec<u8>, tip: u32) {
+        let mut guard = context.lock().unwrap();
+        for height in 0..=tip {
+            let mut batch = RocksDBBatch::default();
+            let value = db_annotate_value(&vec![height as u8], height).unwrap();
+            batch.put(db_make_list_key(key, height).unwrap(), value);
+            batch.put(db_make_length_key(key).unwrap(), u32_to_vec(height + 1).unwrap());
+            let updated = u32_to_vec(height).unwrap();
+            batch.put(db_make_list_key(&updated, 0).unwrap(), key);
+            batch.put(db_make_length_key(&updated).unwrap(), u32_to_vec(1).unwrap());
+            batch.put(height_to_hash_key(height), hash(height, 0));
+            batch.put(state_root_key(height), [height as u8; 32]);
+            guard.db.set_height(height);
+            guard.db.write(batch).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn no_fork_when_tip_matches() {
+        let dir = TempDir::new("reorg").unwrap();
+        let context = open(&dir);
+        index_chain(&context, &b"/k".to_vec(), 10);
+        let mut db = context.lock().unwrap().db.clone();
+        let source = node(10, 10, 0);
+        assert_eq!(find_fork_point(&source, &mut db, 10).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn finds_deep_fork_point() {
+        let dir = TempDir::new("reorg").unwrap();
+        let context = open(&dir);
+        index_chain(&context, &b"/k".to_vec(), 20);
+        let mut db = context.lock().unwrap().db.clone();
+        let source = node(22, 7, 1);
+        assert_eq!(find_fork_point(&source, &mut db, 20).await.unwrap(), Some(7));
+        // A node that is behind our tip orphans everything above its tip
+        let source = node(15, 15, 0);
+        assert_eq!(find_fork_point(&source, &mut db, 20).await.unwrap(), Some(15));
+    }
+
+    #[tokio::test]
+    async fn genesis_mismatch_is_an_error() {
+        let dir = TempDir::new("reorg").unwrap();
+        let context = open(&dir);
+        index_chain(&context, &b"/k".to_vec(), 3);
+        let mut db = context.lock().unwrap().db.clone();
+        let source = node(3, 0, 1);
+        assert_eq!(find_fork_point(&source, &mut db, 3).await.unwrap(), Some(0));
+        let other = MemoryBlockSource::new();
+        for h in 0..=3 {
+            other.push(hash(h, 9), vec![]);
+        }
+        assert!(find_fork_point(&other, &mut db, 3).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rollback_restores_state_at_fork() {
+        let dir = TempDir::new("reorg").unwrap();
+        let context = open(&dir);
+        let key = b"/k".to_vec();
+        index_chain(&context, &key, 12);
+        let source = node(13, 4, 1);
+        let mut db = context.lock().unwrap().db.clone();
+        let fork = find_fork_point(&source, &mut db, 12).await.unwrap().unwrap();
+        assert_eq!(fork, 4);
+
+        rollback_to(context.clone(), fork, 12).unwrap();
+
+        let length = get(&context, &db_make_length_key(&key).unwrap()).unwrap();
+        assert_eq!(length, u32_to_vec(5).unwrap());
+        assert!(get(&context, &db_make_list_key(&key, 5).unwrap()).is_none());
+        assert!(get(&context, &db_make_list_key(&key, 4).unwrap()).is_some());
+        for height in 5..=12 {
+            assert!(get(&context, &height_to_hash_key(height)).is_none());
+            assert!(get(&context, &state_root_key(height)).is_none());
+            let updated = u32_to_vec(height).unwrap();
+            assert!(get(&context, &db_make_length_key(&updated).unwrap()).is_none());
+            assert!(get(&context, &db_make_list_key(&updated, 0).unwrap()).is_none());
+        }
+        assert!(get(&context, &height_to_hash_key(4)).is_some());
+        assert!(get(&context, &state_root_key(4)).is_some());
+        let tip = get(&context, &b"/__INTERNAL/tip-height".to_vec()).unwrap();
+        assert_eq!(tip, u32_to_vec(5).unwrap());
+        assert_eq!(find_fork_point(&source, &mut db, 4).await.unwrap(), None);
+    }
+}
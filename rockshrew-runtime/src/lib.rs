@@ -1,14 +1,51 @@
-use anyhow::Result;
-use metashrew_runtime::{BatchLike, KeyValueStoreLike};
-use rocksdb::{DB, Options, WriteBatch, WriteBatchIterator};
+use anyhow::{anyhow, Result};
+use log::info;
+use metashrew_runtime::{db_entry_height, BatchLike, KeyValuePairs, KeyValueStoreLike};
+use rocksdb::{
+    Direction, IteratorMode, Options, SnapshotWithThreadMode, WriteBatch, WriteBatchIterator, DB,
+};
 use std::sync::{Arc};
 
-const TIP_HEIGHT_KEY: &'static str = "/__INTERNAL/tip-height";
+const TIP_HEIGHT_KEY: &str = "/__INTERNAL/tip-height";
+const STORAGE_VERSION_KEY: &str = "/__INTERNAL/storage-version";
+
+/// Version 1: every append list is contiguous and its entry heights are
+/// non-decreasing, which lets `db_value_at_block` binary search it.
+pub const STORAGE_VERSION: u32 = 1;
+
+const MIGRATION_BATCH_SIZE: usize = 10000;
 
 #[derive(Clone)]
 pub struct RocksDBRuntimeAdapter {
     pub db: Arc<DB>,
     pub height: u32,
+    /// When set, reads go through this snapshot instead of the live database
+    pub snapshot: Option<Arc<RocksDBSnapshot>>,
+    /// Opened with `open_secondary`. RocksDB secondaries cannot take
+    /// snapshots, so their readers pin a committed height instead.
+    pub secondary: bool,
+}
+
+/// A RocksDB snapshot that holds its own reference to the database, so an
+/// adapter reading through it can be cloned and moved like any other.
+pub struct RocksDBSnapshot {
+    // Declared before `_db` so the snapshot is released first
+    snapshot: SnapshotWithThreadMode<'static, DB>,
+    _db: Arc<DB>,
+}
+
+impl RocksDBSnapshot {
+    pub fn new(db: Arc<DB>) -> Self {
+        let snapshot = db.snapshot();
+        // Safety: the snapshot borrows the `DB` behind the `Arc`, which does
+        // not move and outlives it because this struct owns both
+        let snapshot = unsafe {
+            std::mem::transmute::<SnapshotWithThreadMode<'_, DB>, SnapshotWithThreadMode<'static, DB>>(
+                snapshot,
+            )
+        };
+        RocksDBSnapshot { snapshot, _db: db }
+    }
 }
 
 static mut _LABEL: Option<String> = None;
@@ -63,6 +100,118 @@ pub async fn query_height(db: Arc<DB>, start_block: u32) -> Result<u32> {
     Ok(u32::from_le_bytes(bytes_ref.try_into().unwrap()))
 }
 
+pub fn storage_version(db: &DB) -> Result<u32> {
+    match db.get(to_labeled_key(&STORAGE_VERSION_KEY.as_bytes().to_vec()))? {
+        Some(bytes) if bytes.len() == 4 => Ok(u32::from_le_bytes(bytes.as_slice().try_into()?)),
+        _ => Ok(0),
+    }
+}
+
+/// Fails unless the database is at `STORAGE_VERSION`, for processes that
+/// read it but must not migrate it, like secondaries. A database nothing has
+/// been indexed into yet passes, since it holds no lists to search.
+pub fn check_storage_version(db: &DB) -> Result<()> {
+    let version = storage_version(db)?;
+    if version >= STORAGE_VERSION || db.get(to_labeled_key(&TIP_HEIGHT_KEY.as_bytes().to_vec()))?.is_none() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "database is at storage version {} but {} is required; run rockshrew-mono on it once to migrate",
+        version,
+        STORAGE_VERSION
+    ))
+}
+
+/// Rewrites one append list so its entries are contiguous with non-decreasing
+/// heights. Entries followed by one at a lower height were left behind by
+/// reorgs that were never rolled back; a lookup always resolves to the later
+/// entry, so dropping them does not change any historical value.
+fn compact_append_list(db: &DB, key: &[u8], batch: &mut WriteBatch) -> Result<bool> {
+    let list_key = |index: u32| -> Vec<u8> {
+        let mut entry = key.to_vec();
+        entry.extend(index.to_le_bytes());
+        entry
+    };
+    let length_key = list_key(u32::MAX);
+    let length = match db.get(&length_key)? {
+        Some(bytes) if bytes.len() == 4 => u32::from_le_bytes(bytes.as_slice().try_into()?),
+        _ => return Ok(false),
+    };
+
+    let mut kept: Vec<Vec<u8>> = vec![];
+    let mut min_height = u32::MAX;
+    for index in (0..length).rev() {
+        if let Some(value) = db.get(list_key(index))? {
+            let height = db_entry_height(&value)?;
+            if height <= min_height {
+                min_height = height;
+                kept.push(value);
+            }
+        }
+    }
+    if kept.len() == length as usize {
+        return Ok(false);
+    }
+
+    kept.reverse();
+    for (index, value) in kept.iter().enumerate() {
+        batch.put(list_key(index as u32), value);
+    }
+    for index in (kept.len() as u32)..length {
+        batch.delete(list_key(index));
+    }
+    if kept.is_empty() {
+        batch.delete(&length_key);
+    } else {
+        batch.put(&length_key, (kept.len() as u32).to_le_bytes());
+    }
+    Ok(true)
+}
+
+/// Brings a database written by an older release up to `STORAGE_VERSION`.
+/// Must run on the primary before indexing resumes.
+pub fn migrate_storage(db: &DB) -> Result<()> {
+    let version = storage_version(db)?;
+    if version >= STORAGE_VERSION {
+        return Ok(());
+    }
+    info!("migrating database from storage version {} to {}", version, STORAGE_VERSION);
+
+    let prefix = to_labeled_key(&vec![]);
+    let length_suffix = u32::MAX.to_le_bytes();
+    let mut batch = WriteBatch::default();
+    let mut scanned: u64 = 0;
+    let mut rewritten: u64 = 0;
+    for item in db.iterator(IteratorMode::From(&prefix, Direction::Forward)) {
+        let (k, _) = item?;
+        if !k.starts_with(&prefix) {
+            break;
+        }
+        // Logical keys of exactly 4 bytes are the per-height update lists,
+        // whose entries carry no height annotation
+        if k.len() == prefix.len() + 8 || !k.ends_with(&length_suffix) {
+            continue;
+        }
+        scanned += 1;
+        if compact_append_list(db, &k[..k.len() - 4], &mut batch)? {
+            rewritten += 1;
+        }
+        if batch.len() >= MIGRATION_BATCH_SIZE {
+            db.write(std::mem::take(&mut batch))?;
+        }
+        if scanned.is_multiple_of(1_000_000) {
+            info!("migration: scanned {} keys, rewrote {}", scanned, rewritten);
+        }
+    }
+    batch.put(
+        to_labeled_key(&STORAGE_VERSION_KEY.as_bytes().to_vec()),
+        STORAGE_VERSION.to_le_bytes(),
+    );
+    db.write(batch)?;
+    info!("migration complete: scanned {} keys, rewrote {}", scanned, rewritten);
+    Ok(())
+}
+
 impl RocksDBRuntimeAdapter {
     pub fn open_secondary(
         primary_path: String,
@@ -72,7 +221,9 @@ impl RocksDBRuntimeAdapter {
         let db = rocksdb::DB::open_as_secondary(&opts, &primary_path, &secondary_path)?;
         Ok(RocksDBRuntimeAdapter {
             db: Arc::new(db),
-            height: 0
+            height: 0,
+            snapshot: None,
+            secondary: true,
         })
     }
     pub fn open(path: String, opts: Options) -> Result<RocksDBRuntimeAdapter> {
@@ -80,20 +231,31 @@ impl RocksDBRuntimeAdapter {
         Ok(RocksDBRuntimeAdapter {
             db: Arc::new(db),
             height: 0,
+            snapshot: None,
+            secondary: false,
         })
     }
 
     pub fn is_open(&self) -> bool {
         true // RocksDB doesn't need connection management like Redis
     }
-    pub fn set_height(&mut self, height: u32) {
-        self.height = height;
-    }
-
     pub fn clone(&self) -> Self {
         RocksDBRuntimeAdapter {
             db: self.db.clone(),
             height: self.height,
+            snapshot: self.snapshot.clone(),
+            secondary: self.secondary,
+        }
+    }
+
+    /// The last block fully written, according to the tip this adapter
+    /// reads, or `None` before the first block is committed.
+    pub fn committed_height(&mut self) -> Result<Option<u32>> {
+        match self.get(TIP_HEIGHT_KEY.as_bytes())? {
+            Some(bytes) if bytes.len() == 4 => {
+                Ok(u32::from_le_bytes(bytes.as_slice().try_into()?).checked_sub(1))
+            }
+            _ => Ok(None),
         }
     }
 }
@@ -108,6 +270,10 @@ impl BatchLike for RocksDBBatch {
     fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, k: K, v: V) {
         self.0.put(to_labeled_key(&k.as_ref().to_vec()), v);
     }
+
+    fn delete<K: AsRef<[u8]>>(&mut self, k: K) {
+        self.0.delete(to_labeled_key(&k.as_ref().to_vec()));
+    }
 }
 
 pub struct RocksDBBatchCloner<'a>(&'a mut WriteBatch);
@@ -116,8 +282,8 @@ impl<'a> WriteBatchIterator for RocksDBBatchCloner<'a> {
   fn put(&mut self, key: Box<[u8]>, value: Box<[u8]>) {
     self.0.put(key.as_ref(), value.as_ref());
   }
-  fn delete(&mut self, _key: Box<[u8]>) {
-    //no-op
+  fn delete(&mut self, key: Box<[u8]>) {
+    self.0.delete(key.as_ref());
   }
 }
 
@@ -127,7 +293,7 @@ impl KeyValueStoreLike for RocksDBRuntimeAdapter {
 
     fn write(&mut self, batch: RocksDBBatch) -> Result<(), Self::Error> {
         let key_bytes: Vec<u8> = TIP_HEIGHT_KEY.as_bytes().to_vec();
-        let height_bytes: Vec<u8> = (self.height + 1).to_le_bytes().to_vec();
+        let height_bytes: Vec<u8> = self.height.wrapping_add(1).to_le_bytes().to_vec();
         
         let mut final_batch = WriteBatch::default();
         final_batch.put(&to_labeled_key(&key_bytes), &height_bytes);
@@ -137,7 +303,11 @@ impl KeyValueStoreLike for RocksDBRuntimeAdapter {
     }
 
     fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
-        self.db.get(to_labeled_key(&key.as_ref().to_vec())).map(|opt| opt.map(|v| v.to_vec()))
+        let key = to_labeled_key(&key.as_ref().to_vec());
+        match &self.snapshot {
+            Some(snapshot) => snapshot.snapshot.get(key),
+            None => self.db.get(key),
+        }
     }
 
     fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
@@ -147,4 +317,190 @@ impl KeyValueStoreLike for RocksDBRuntimeAdapter {
     fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<(), Self::Error> {
         self.db.put(to_labeled_key(&key.as_ref().to_vec()), value)
     }
+
+    fn scan_prefix<K: AsRef<[u8]>>(&mut self, prefix: K) -> Result<KeyValuePairs, Self::Error> {
+        self.scan_prefix_from(prefix.as_ref(), prefix.as_ref(), usize::MAX)
+    }
+
+    fn scan_prefix_from<K: AsRef<[u8]>>(
+        &mut self,
+        prefix: K,
+        start: K,
+        limit: usize,
+    ) -> Result<KeyValuePairs, Self::Error> {
+        let labeled_prefix = to_labeled_key(&prefix.as_ref().to_vec());
+        let label_len = labeled_prefix.len() - prefix.as_ref().len();
+        let labeled_start = to_labeled_key(&start.as_ref().to_vec()).max(labeled_prefix.clone());
+        let mut result = Vec::new();
+        let mode = IteratorMode::From(&labeled_start, Direction::Forward);
+        let iterator = match &self.snapshot {
+            Some(snapshot) => snapshot.snapshot.iterator(mode),
+            None => self.db.iterator(mode),
+        };
+        for item in iterator {
+            if result.len() >= limit {
+                break;
+            }
+            let (k, v) = item?;
+            if !k.starts_with(&labeled_prefix) {
+                break;
+            }
+            result.push((k[label_len..].to_vec(), v.to_vec()));
+        }
+        Ok(result)
+    }
+    fn set_height(&mut self, height: u32) {
+        self.height = height;
+    }
+
+    fn snapshot(&self) -> Result<Self, Self::Error> {
+        // A secondary reads live data, which stays consistent for a reader
+        // that resolves values at a committed height, as views do
+        if self.snapshot.is_some() || self.secondary {
+            return Ok(self.clone());
+        }
+        Ok(RocksDBRuntimeAdapter {
+            snapshot: Some(Arc::new(RocksDBSnapshot::new(self.db.clone()))),
+            ..self.clone()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metashrew_runtime::{db_annotate_value, db_make_length_key, db_make_list_key};
+
+    fn open() -> (tempdir::TempDir, RocksDBRuntimeAdapter) {
+        let dir = tempdir::TempDir::new("rockshrew-runtime").unwrap();
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        let adapter = RocksDBRuntimeAdapter::open(dir.path().to_string_lossy().to_string(), opts).unwrap();
+        (dir, adapter)
+    }
+
+    /// Writes an append list for `key` with one entry at each of `heights`.
+    fn write_list(db: &DB, key: &[u8], heights: &[u32]) {
+        let key = key.to_vec();
+        for (index, height) in heights.iter().enumerate() {
+            db.put(
+                db_make_list_key(&key, index as u32).unwrap(),
+                db_annotate_value(&vec![index as u8], *height).unwrap(),
+            )
+            .unwrap();
+        }
+        db.put(db_make_length_key(&key).unwrap(), (heights.len() as u32).to_le_bytes())
+            .unwrap();
+    }
+
+    /// Heights of the entries in the append list of `key`.
+    fn list_heights(db: &DB, key: &[u8]) -> Vec<u32> {
+        let key = key.to_vec();
+        let length = db
+            .get(db_make_length_key(&key).unwrap())
+            .unwrap()
+            .map(|length| u32::from_le_bytes(length.try_into().unwrap()))
+            .unwrap();
+        (0..length)
+            .map(|index| {
+                let entry = db.get(db_make_list_key(&key, index).unwrap()).unwrap().unwrap();
+                db_entry_height(&entry).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn migrates_lists_left_out_of_order_by_reorgs() {
+        let (_dir, adapter) = open();
+        // Blocks 5 and 7 were orphaned, and their entries left in place, when
+        // 3 and 4 were indexed on the replacement chain
+        write_list(&adapter.db, b"reorged", &[1, 5, 7, 3, 4]);
+        write_list(&adapter.db, b"ordered", &[2, 2, 6]);
+        write_list(&adapter.db, b"empty", &[]);
+        write_list(&adapter.db, b"ab", &[1, 9, 2]);
+        // The update list of block 3, whose entries are bare keys
+        let update_list = 3u32.to_le_bytes().to_vec();
+        adapter.db.put(db_make_list_key(&update_list, 0).unwrap(), b"reorged").unwrap();
+        adapter.db.put(db_make_length_key(&update_list).unwrap(), 1u32.to_le_bytes()).unwrap();
+        adapter.db.put(TIP_HEIGHT_KEY, 8u32.to_le_bytes()).unwrap();
+        assert_eq!(storage_version(&adapter.db).unwrap(), 0);
+        assert!(check_storage_version(&adapter.db).is_err());
+
+        migrate_storage(&adapter.db).unwrap();
+        assert_eq!(storage_version(&adapter.db).unwrap(), STORAGE_VERSION);
+        check_storage_version(&adapter.db).unwrap();
+        assert_eq!(list_heights(&adapter.db, b"reorged"), vec![1, 3, 4]);
+        assert_eq!(list_heights(&adapter.db, b"ordered"), vec![2, 2, 6]);
+        assert_eq!(list_heights(&adapter.db, b"empty"), Vec::<u32>::new());
+        assert_eq!(list_heights(&adapter.db, b"ab"), vec![1, 2]);
+        assert_eq!(
+            adapter.db.get(db_make_list_key(&update_list, 0).unwrap()).unwrap(),
+            Some(b"reorged".to_vec())
+        );
+
+        // A second run finds the database already migrated
+        write_list(&adapter.db, b"reorged", &[1, 5, 3]);
+        migrate_storage(&adapter.db).unwrap();
+        assert_eq!(list_heights(&adapter.db, b"reorged"), vec![1, 5, 3]);
+    }
+
+    #[test]
+    fn accepts_databases_that_were_never_indexed() {
+        let (_dir, adapter) = open();
+        check_storage_version(&adapter.db).unwrap();
+        migrate_storage(&adapter.db).unwrap();
+        assert_eq!(storage_version(&adapter.db).unwrap(), STORAGE_VERSION);
+    }
+
+    /// Counts the keys under "k" visible at the view's height
+    const SCANNER: &str = r#"
+        (module
+          (import "env" "__scan_prefix" (func $scan (param i32) (result i32)))
+          (import "env" "__iter_next_len" (func $next_len (param i32) (result i32)))
+          (import "env" "__iter_next" (func $next (param i32 i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 16) "\01\00\00\00k")
+          (func (export "_start"))
+          (func (export "scan") (result i32) (local $handle i32) (local $len i32) (local $count i32)
+            (local.set $handle (call $scan (i32.const 20)))
+            (block $done
+              (loop $more
+                (local.set $len (call $next_len (local.get $handle)))
+                (br_if $done (i32.eqz (local.get $len)))
+                (br_if $done (i32.eq (local.get $len) (i32.const 0x7fffffff)))
+                (call $next (local.get $handle) (i32.const 1024))
+                (local.set $count (i32.add (local.get $count) (i32.const 1)))
+                (br $more)))
+            (i32.store (i32.const 28) (i32.const 4))
+            (i32.store (i32.const 32) (local.get $count))
+            (i32.const 32)))
+    "#;
+
+    #[tokio::test]
+    async fn scans_through_a_secondary() {
+        let (dir, adapter) = open();
+        write_list(&adapter.db, b"ka", &[1]);
+        write_list(&adapter.db, b"kb", &[1, 2]);
+        write_list(&adapter.db, b"j", &[1]);
+        adapter.db.put(TIP_HEIGHT_KEY, 3u32.to_le_bytes()).unwrap();
+        adapter.db.flush().unwrap();
+
+        let secondary_dir = tempdir::TempDir::new("rockshrew-runtime-secondary").unwrap();
+        let secondary = RocksDBRuntimeAdapter::open_secondary(
+            dir.path().to_string_lossy().to_string(),
+            secondary_dir.path().to_string_lossy().to_string(),
+            Options::default(),
+        )
+        .unwrap();
+        secondary.db.try_catch_up_with_primary().unwrap();
+        let indexer = secondary_dir.path().join("scanner.wat");
+        std::fs::write(&indexer, SCANNER).unwrap();
+        let runtime = metashrew_runtime::MetashrewRuntime::load(indexer, secondary).unwrap();
+
+        // Secondaries take no snapshots, so a view pins the committed height
+        let mut views = runtime.view_handle().unwrap().snapshot().unwrap();
+        assert_eq!(views.db.committed_height().unwrap(), Some(2));
+        assert_eq!(views.view("scan".to_string(), &[], 2).await.unwrap(), 2u32.to_le_bytes());
+        assert_eq!(views.view("scan".to_string(), &[], 0).await.unwrap(), 0u32.to_le_bytes());
+    }
 }
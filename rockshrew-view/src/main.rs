@@ -1,26 +1,51 @@
 use actix_cors::Cors;
 use actix_web::error;
 use actix_web::http::{StatusCode};
-use actix_web::{post, web, App, HttpResponse, HttpServer, Responder, Result};
+use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder, Result};
 use anyhow;
 use clap::{Parser};
+use futures::future::join_all;
 use lazy_static::lazy_static;
 use log::{debug, info};
-use rockshrew_runtime::{query_height, set_label, RocksDBRuntimeAdapter};
-use metashrew_runtime::MetashrewRuntime;
+use rockshrew_runtime::{check_storage_version, query_height, set_label, RocksDBRuntimeAdapter};
+use metashrew_runtime::{
+    schedule_hashes, state_root, verify_wasm, CallLimits, KeyValueStoreLike, MetashrewRuntime,
+    RuntimeOptions, Upgrade, ViewError, ViewHandle,
+};
 use rocksdb::Options;
 use serde::{Deserialize, Serialize};
 use serde_json;
-use std::fs::File;
-use std::io::{prelude::*, BufReader};
+use std::ops::RangeBounds;
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicU64, Ordering};
-use tiny_keccak::{Hasher, Sha3};
 use tokio::sync::RwLock;
 
 lazy_static! {
     static ref CATCH_UP_LOCK: RwLock<()> = RwLock::new(());
     static ref LAST_CATCH_UP: AtomicU64 = AtomicU64::new(0);
+    /// Primary's tip height as of the last background catch-up
+    static ref PRIMARY_TIP: AtomicU64 = AtomicU64::new(0);
+    /// When `PRIMARY_TIP` last advanced, 0 until it first does
+    static ref PRIMARY_ADVANCED: AtomicU64 = AtomicU64::new(0);
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Records the primary's tip as the background catch-up last saw it, and
+/// when it last moved.
+async fn record_primary_tip(db: std::sync::Arc<rocksdb::DB>) {
+    match query_height(db, 0).await {
+        Ok(tip) if PRIMARY_TIP.swap(tip as u64, Ordering::AcqRel) != tip as u64 => {
+            PRIMARY_ADVANCED.store(unix_now(), Ordering::Release);
+        }
+        Ok(_) => {}
+        Err(e) => log::warn!("Failed to read the primary's height after catch-up: {}", e),
+    }
 }
 
 async fn synchronized_catch_up(db: &rocksdb::DB) -> Result<(), rocksdb::Error> {
@@ -85,6 +110,89 @@ struct RockshrewViewArgs {
     /// Port number to listen on
     #[arg(long, env = "PORT", default_value_t = 8080)]
     port: u16,
+
+    /// Seconds since the last successful catch-up with the primary after
+    /// which /ready reports not ready
+    #[arg(long, env = "MAX_CATCH_UP_AGE", default_value_t = 30)]
+    max_catch_up_age: u64,
+
+    /// Most requests accepted in one JSON-RPC batch
+    #[arg(long, env = "MAX_BATCH_SIZE", default_value_t = 100)]
+    max_batch_size: usize,
+
+    /// Fuel each metashrew_view call may consume (unlimited when unset)
+    #[arg(long, env = "VIEW_FUEL")]
+    view_fuel: Option<u64>,
+
+    /// Milliseconds each metashrew_view call may run (unlimited when unset)
+    #[arg(long, env = "VIEW_TIMEOUT_MS")]
+    view_timeout_ms: Option<u64>,
+
+    /// Fuel each metashrew_preview call may consume (unlimited when unset)
+    #[arg(long, env = "PREVIEW_FUEL")]
+    preview_fuel: Option<u64>,
+
+    /// Milliseconds each metashrew_preview call may run (unlimited when unset)
+    #[arg(long, env = "PREVIEW_TIMEOUT_MS")]
+    preview_timeout_ms: Option<u64>,
+
+    /// MiB of WASM memory each metashrew_view call may use (unlimited when unset)
+    #[arg(long, env = "VIEW_MEMORY_MB")]
+    view_memory_mb: Option<usize>,
+
+    /// MiB of WASM memory each metashrew_preview call may use (unlimited when unset)
+    #[arg(long, env = "PREVIEW_MEMORY_MB")]
+    preview_memory_mb: Option<usize>,
+
+    /// Preallocate this many WASM instances for views and previews
+    /// (allocated on demand when unset)
+    #[arg(long, env = "VIEW_INSTANCE_POOL")]
+    view_instance_pool: Option<u32>,
+
+    /// Directory caching the compiled indexer so restarts skip recompiling it
+    #[arg(long, env = "MODULE_CACHE_DIR")]
+    module_cache_dir: Option<PathBuf>,
+
+    /// Indexers that took over at later heights, as HEIGHT:PATH, so views
+    /// at each height run the indexer that produced it
+    #[arg(long = "upgrade", env = "INDEXER_UPGRADES", value_name = "HEIGHT:PATH", value_delimiter = ',')]
+    upgrades: Vec<Upgrade>,
+
+    /// Only warn when the indexer WASM differs from the one the database
+    /// was indexed with
+    #[arg(long, env = "ALLOW_WASM_MISMATCH")]
+    allow_wasm_mismatch: bool,
+
+    /// Serve metashrew_getproof, for databases whose primary was started
+    /// with --enable-state-proofs
+    #[arg(long, env = "ENABLE_STATE_PROOFS")]
+    enable_state_proofs: bool,
+
+    /// Serve metashrew_getkey and metashrew_getkeyhistory, which read raw keys
+    #[arg(long, env = "ENABLE_DEBUG_RPC")]
+    enable_debug_rpc: bool,
+}
+
+impl RockshrewViewArgs {
+    fn runtime_options(&self) -> RuntimeOptions {
+        RuntimeOptions {
+            view: CallLimits {
+                fuel: self.view_fuel,
+                timeout: self.view_timeout_ms.map(std::time::Duration::from_millis),
+                max_memory: self.view_memory_mb.map(|mb| mb * 1024 * 1024),
+            },
+            preview: CallLimits {
+                fuel: self.preview_fuel,
+                timeout: self.preview_timeout_ms.map(std::time::Duration::from_millis),
+                max_memory: self.preview_memory_mb.map(|mb| mb * 1024 * 1024),
+            },
+            instance_pool: self.view_instance_pool,
+            module_cache: self.module_cache_dir.clone(),
+            upgrades: self.upgrades.clone(),
+            state_proofs: self.enable_state_proofs,
+            ..Default::default()
+        }
+    }
 }
 
 fn from_anyhow(err: anyhow::Error) -> actix_web::Error {
@@ -101,14 +209,39 @@ struct JsonRpcRequest {
 }
 
 #[derive(Serialize)]
-struct JsonRpcResult {
+struct JsonRpcResult<T = String> {
     id: u32,
-    result: String,
+    result: T,
     jsonrpc: String,
 }
+
+/// `metashrew_view` result when the request passes `{"debug": true}` as a
+/// fourth param
+#[derive(Serialize)]
+struct ViewDebugResult {
+    data: String,
+    log: Vec<String>,
+}
+/// `metashrew_getproof` result. `value` is null when the key was never
+/// written, and `leaf` is null when its path ends at an empty subtree.
+#[derive(Serialize)]
+struct ProofResult {
+    height: u32,
+    root: String,
+    value: Option<String>,
+    siblings: Vec<String>,
+    leaf: Option<ProofLeaf>,
+}
+
+#[derive(Serialize)]
+struct ProofLeaf {
+    path: String,
+    value_hash: String,
+}
+
 #[derive(Serialize)]
 struct JsonRpcError {
-    id: u32,
+    id: Option<u32>,
     error: JsonRpcErrorObject,
     jsonrpc: String,
 }
@@ -117,15 +250,24 @@ struct JsonRpcError {
 struct JsonRpcErrorObject {
     code: i32,
     message: String,
-    data: Option<String>,
+    data: Option<serde_json::Value>,
+}
+
+impl From<ViewError> for JsonRpcErrorObject {
+    fn from(err: ViewError) -> Self {
+        Self {
+            code: err.code(),
+            message: err.to_string(),
+            data: Some(err.data()),
+        }
+    }
 }
 
 struct Context {
-    #[allow(dead_code)]
-    hash: [u8; 32],
-    #[allow(dead_code)]
-    program: Vec<u8>,
     runtime: MetashrewRuntime<RocksDBRuntimeAdapter>,
+    max_catch_up_age: u64,
+    max_batch_size: usize,
+    debug_rpc: bool,
 }
 
 static mut _HEIGHT: u32 = 0;
@@ -148,315 +290,420 @@ pub async fn fetch_and_set_height(internal_db: &RocksDBRuntimeAdapter) -> Result
     Ok(set_height(height))
 }
 
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    /// Condition that failed: `catch_up_stale`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failing: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    /// Last block committed by the primary, as of the last catch-up
+    indexed_height: Option<u32>,
+    /// Seconds since the primary last committed a block, which grows while
+    /// it is stalled even as catch-ups succeed
+    indexed_height_age: Option<u64>,
+    /// Seconds since the last successful catch-up, absent if none succeeded
+    catch_up_age: Option<u64>,
+    max_catch_up_age: u64,
+}
+
+#[get("/health")]
+async fn health() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+#[get("/ready")]
+async fn ready(context: web::Data<Context>) -> impl Responder {
+    // Reports what the background catch-up recorded, without catching up
+    let now = unix_now();
+    let age = |at: u64| match at {
+        0 => None,
+        at => Some(now.saturating_sub(at)),
+    };
+    let catch_up_age = age(LAST_CATCH_UP.load(Ordering::Acquire));
+    let indexed_height_age = age(PRIMARY_ADVANCED.load(Ordering::Acquire));
+    let indexed_height = (PRIMARY_TIP.load(Ordering::Acquire) as u32).checked_sub(1);
+    let (failing, message) = match catch_up_age {
+        Some(age) if age <= context.max_catch_up_age => (None, None),
+        Some(age) => (
+            Some("catch_up_stale"),
+            Some(format!("last catch-up with the primary was {}s ago (max {}s)", age, context.max_catch_up_age)),
+        ),
+        None => (
+            Some("catch_up_stale"),
+            Some("no successful catch-up with the primary yet".to_string()),
+        ),
+    };
+    let status = Readiness {
+        ready: failing.is_none(),
+        failing,
+        message,
+        indexed_height,
+        indexed_height_age,
+        catch_up_age,
+        max_catch_up_age: context.max_catch_up_age,
+    };
+    if status.ready {
+        HttpResponse::Ok().json(status)
+    } else {
+        HttpResponse::ServiceUnavailable().json(status)
+    }
+}
+
 #[post("/")]
 async fn jsonrpc_call(
-    body: web::Json<JsonRpcRequest>,
+    body: web::Json<serde_json::Value>,
     context: web::Data<Context>,
 ) -> Result<impl Responder> {
-    debug!("{}", serde_json::to_string(&body).unwrap());
-
     // Ensure we're caught up with primary before processing request
-    if let Err(e) = synchronized_catch_up(&context.runtime.context.lock().unwrap().db.db).await {
+    if let Err(e) = synchronized_catch_up(&adapter(&context).db).await {
         log::warn!("Failed to catch up with primary before request: {}", e);
         // Continue processing despite catch-up failure
     }
 
-    if body.method == "metashrew_view" {
-        if body.params.len() < 3 {
-            let error = JsonRpcError {
-                id: body.id,
-                error: JsonRpcErrorObject {
-                    code: -32602,
-                    message: "Invalid params: requires [view_name, input_data, height]".to_string(),
-                    data: None,
-                },
-                jsonrpc: "2.0".to_string(),
-            };
-            return Ok(HttpResponse::Ok().json(error));
-        }
-
-        let view_name = match body.params[0].as_str() {
-            Some(s) => s.to_string(),
-            None => {
-                let error = JsonRpcError {
-                    id: body.id,
-                    error: JsonRpcErrorObject {
-                        code: -32602,
-                        message: "Invalid params: view_name must be a string".to_string(),
-                        data: None,
-                    },
-                    jsonrpc: "2.0".to_string(),
-                };
+    match body.into_inner() {
+        serde_json::Value::Array(requests) => {
+            if requests.is_empty() || requests.len() > context.max_batch_size {
+                let error = rpc_error(
+                    None,
+                    -32600,
+                    format!(
+                        "Invalid request: batch must hold between 1 and {} requests, got {}",
+                        context.max_batch_size,
+                        requests.len()
+                    ),
+                );
                 return Ok(HttpResponse::Ok().json(error));
             }
-        };
+            // Views yield cooperatively, so the calls interleave on this worker
+            let responses =
+                join_all(requests.into_iter().map(|request| dispatch_value(request, &context))).await;
+            Ok(HttpResponse::Ok().json(responses))
+        }
+        request => Ok(HttpResponse::Ok().json(dispatch_value(request, &context).await)),
+    }
+}
 
-        let input_hex = match body.params[1].as_str() {
-            Some(s) => s.to_string(),
-            None => {
-                let error = JsonRpcError {
-                    id: body.id,
-                    error: JsonRpcErrorObject {
-                        code: -32602,
-                        message: "Invalid params: input_data must be a hex string".to_string(),
-                        data: None,
-                    },
-                    jsonrpc: "2.0".to_string(),
-                };
-                return Ok(HttpResponse::Ok().json(error));
-            }
-        };
+/// Runs one request of a single or batch call, reporting any failure as a
+/// JSON-RPC error.
+async fn dispatch_value(request: serde_json::Value, context: &Context) -> serde_json::Value {
+    let body: JsonRpcRequest = match serde_json::from_value(request) {
+        Ok(body) => body,
+        Err(e) => return rpc_error(None, -32600, format!("Invalid request: {}", e)),
+    };
+    match dispatch(body, context).await {
+        Ok(response) | Err(response) => response,
+    }
+}
 
-        let height: u32 = match &body.params[2] {
-            serde_json::Value::String(s) if s == "latest" => {
-                fetch_and_set_height(&context.runtime.context.lock().unwrap().db).await?
-            }
-            serde_json::Value::Number(n) => {
-                let h = n.as_u64().unwrap_or(0) as u32;
-                if h > height() {
-                    fetch_and_set_height(&context.runtime.context.lock().unwrap().db).await?
-                } else {
-                    h
-                }
-            }
-            serde_json::Value::String(s) => match s.parse::<u32>() {
-                Ok(h) => {
-                    if h > height() {
-                        fetch_and_set_height(&context.runtime.context.lock().unwrap().db).await?
-                    } else {
-                        h
-                    }
-                }
-                Err(_) => {
-                    let error = JsonRpcError {
-                        id: body.id,
-                        error: JsonRpcErrorObject {
-                            code: -32602,
-                            message: "Invalid params: height must be a number or 'latest'"
-                                .to_string(),
-                            data: None,
-                        },
-                        jsonrpc: "2.0".to_string(),
-                    };
-                    return Ok(HttpResponse::Ok().json(error));
-                }
-            },
-            _ => {
-                let error = JsonRpcError {
-                    id: body.id,
-                    error: JsonRpcErrorObject {
-                        code: -32602,
-                        message: "Invalid params: height must be a number or 'latest'".to_string(),
-                        data: None,
-                    },
-                    jsonrpc: "2.0".to_string(),
-                };
-                return Ok(HttpResponse::Ok().json(error));
-            }
-        };
-// Use await with the async view function
-match context.runtime.view(
-    view_name,
-    &hex::decode(input_hex.trim_start_matches("0x"))
-        .map_err(|e| error::ErrorBadRequest(format!("Invalid hex input: {}", e)))?,
-    height,
-).await {
-    Ok(res_string) => {
-                let result = JsonRpcResult {
-                    id: body.id,
-                    result: String::from("0x") + hex::encode(res_string).as_str(),
-                    jsonrpc: "2.0".to_string(),
-                };
-                Ok(HttpResponse::Ok().json(result))
-            }
-            Err(err) => {
-                let error = JsonRpcError {
-                    id: body.id,
-                    error: JsonRpcErrorObject {
-                        code: -32000,
-                        message: err.to_string(),
-                        data: None,
-                    },
-                    jsonrpc: "2.0".to_string(),
-                };
-                Ok(HttpResponse::Ok().json(error))
-            }
+fn rpc_value<T: Serialize>(response: T) -> serde_json::Value {
+    serde_json::to_value(response).unwrap_or(serde_json::Value::Null)
+}
+
+/// An error response without data. `id` is `None` when the request's own id
+/// could not be read.
+fn rpc_error(id: Option<u32>, code: i32, message: impl Into<String>) -> serde_json::Value {
+    rpc_value(JsonRpcError {
+        id,
+        error: JsonRpcErrorObject {
+            code,
+            message: message.into(),
+            data: None,
+        },
+        jsonrpc: "2.0".to_string(),
+    })
+}
+
+/// An error response for a failure while serving `body`.
+fn server_error(body: &JsonRpcRequest, err: impl std::fmt::Display) -> serde_json::Value {
+    rpc_error(Some(body.id), -32000, err.to_string())
+}
+
+/// An invalid params error for `body`.
+fn invalid_params(body: &JsonRpcRequest, message: impl std::fmt::Display) -> serde_json::Value {
+    rpc_error(Some(body.id), -32602, format!("Invalid params: {}", message))
+}
+
+/// Fails unless `body` has a number of params in `count`, with `usage`
+/// naming them.
+fn param_count(
+    body: &JsonRpcRequest,
+    count: impl RangeBounds<usize>,
+    usage: &str,
+) -> std::result::Result<(), serde_json::Value> {
+    if count.contains(&body.params.len()) {
+        Ok(())
+    } else {
+        Err(invalid_params(body, format!("requires {}", usage)))
+    }
+}
+
+/// The string param at `index`, failing with `message` otherwise.
+fn param_str<'a>(
+    body: &'a JsonRpcRequest,
+    index: usize,
+    message: &str,
+) -> std::result::Result<&'a str, serde_json::Value> {
+    body.params
+        .get(index)
+        .and_then(serde_json::Value::as_str)
+        .ok_or_else(|| invalid_params(body, message))
+}
+
+/// The hex param at `index`, decoded, failing with `message` otherwise.
+fn param_hex(body: &JsonRpcRequest, index: usize, message: &str) -> std::result::Result<Vec<u8>, serde_json::Value> {
+    hex::decode(param_str(body, index, message)?.trim_start_matches("0x")).map_err(|_| invalid_params(body, message))
+}
+
+/// Decodes hex `data` passed to a view or preview, rejecting it as the
+/// view's invalid input, with `what` naming it.
+fn decode_view_input(body: &JsonRpcRequest, data: &str, what: &str) -> std::result::Result<Vec<u8>, serde_json::Value> {
+    hex::decode(data.trim_start_matches("0x"))
+        .map_err(|e| view_error(body.id, ViewError::invalid_input(format!("Invalid hex {}: {}", what, e))))
+}
+
+/// The height param at `index`, as a number or a decimal string, with
+/// `None` meaning "latest".
+fn param_height(body: &JsonRpcRequest, index: usize) -> std::result::Result<Option<u32>, serde_json::Value> {
+    match body.params.get(index) {
+        Some(serde_json::Value::String(s)) if s == "latest" => Ok(None),
+        Some(serde_json::Value::Number(n)) => Ok(Some(n.as_u64().unwrap_or(0) as u32)),
+        Some(serde_json::Value::String(s)) => s
+            .parse::<u32>()
+            .map(Some)
+            .map_err(|_| invalid_params(body, "height must be a number or 'latest'")),
+        _ => Err(invalid_params(body, "height must be a number or 'latest'")),
+    }
+}
+
+/// Whether a `metashrew_view` options param asks for the view's `__log` output.
+fn debug_requested(options: Option<&serde_json::Value>) -> bool {
+    options
+        .and_then(|options| options.get("debug"))
+        .and_then(serde_json::Value::as_bool)
+        .unwrap_or(false)
+}
+
+/// A `metashrew_view` result: the hex data alone, or the data with its
+/// `__log` output when the view ran in debug mode.
+fn view_response(id: u32, result: Vec<u8>, log: Option<Vec<String>>) -> serde_json::Value {
+    let data = format!("0x{}", hex::encode(result));
+    match log {
+        Some(log) => rpc_value(JsonRpcResult {
+            id,
+            result: ViewDebugResult { data, log },
+            jsonrpc: "2.0".to_string(),
+        }),
+        None => rpc_value(JsonRpcResult {
+            id,
+            result: data,
+            jsonrpc: "2.0".to_string(),
+        }),
+    }
+}
+
+fn view_error(id: u32, err: ViewError) -> serde_json::Value {
+    rpc_value(JsonRpcError {
+        id: Some(id),
+        error: err.into(),
+        jsonrpc: "2.0".to_string(),
+    })
+}
+
+/// The runtime's database adapter, cloned out of its lock so the lock is
+/// never held across an `.await`.
+fn adapter(context: &Context) -> RocksDBRuntimeAdapter {
+    context.runtime.context.lock().unwrap().db.clone()
+}
+
+/// Resolves a requested height against the last block the primary fully
+/// committed, with `None` meaning "latest". Views read values as of that
+/// height, so what the primary writes meanwhile does not change them. The
+/// secondary takes no snapshot, as RocksDB secondaries do not support them.
+fn pin_height(
+    body: &JsonRpcRequest,
+    requested: Option<u32>,
+    context: &Context,
+) -> std::result::Result<(ViewHandle<RocksDBRuntimeAdapter>, u32), serde_json::Value> {
+    let mut db = context
+        .runtime
+        .context
+        .lock()
+        .unwrap()
+        .db
+        .snapshot()
+        .map_err(|e| server_error(body, e))?;
+    let tip = db.committed_height().map_err(|e| server_error(body, e))?.unwrap_or(0);
+    let height = match requested {
+        Some(height) if height > tip => {
+            return Err(view_error(body.id, ViewError::height_out_of_range(height, tip)))
+        }
+        Some(height) => height,
+        None => tip,
+    };
+    let views = ViewHandle {
+        db,
+        ..context.runtime.view_handle().map_err(|e| server_error(body, e))?
+    };
+    Ok((views, height))
+}
+
+/// Runs one request, with `Err` holding the error response for a request
+/// that could not be served.
+async fn dispatch(body: JsonRpcRequest, context: &Context) -> std::result::Result<serde_json::Value, serde_json::Value> {
+    debug!("{}", serde_json::to_string(&body).unwrap());
+
+    if body.method == "metashrew_view" {
+        param_count(&body, 3.., "[view_name, input_data, height]")?;
+        let view_name = param_str(&body, 0, "view_name must be a string")?.to_string();
+        let input_hex = param_str(&body, 1, "input_data must be a hex string")?;
+        let requested = param_height(&body, 2)?;
+        let input = decode_view_input(&body, input_hex, "input")?;
+        let (views, height) = pin_height(&body, requested, context)?;
+
+        // Guest output is only captured when the caller asked to see it
+        let (result, log) = if debug_requested(body.params.get(3)) {
+            views
+                .view_with_log(view_name, &input, height)
+                .await
+                .map(|(result, log)| (result, Some(log)))
+        } else {
+            views
+                .view(view_name, &input, height)
+                .await
+                .map(|result| (result, None))
         }
+        .map_err(|err| view_error(body.id, err))?;
+        Ok(view_response(body.id, result, log))
     } else if body.method == "metashrew_height" {
-        let height = fetch_and_set_height(&context.runtime.context.lock().unwrap().db).await?;
+        let height = fetch_and_set_height(&adapter(context))
+            .await
+            .map_err(|e| server_error(&body, e))?;
         let result = JsonRpcResult {
             id: body.id,
             result: height.to_string(),
             jsonrpc: "2.0".to_string(),
         };
-        Ok(HttpResponse::Ok().json(result))
-    } else if body.method == "metashrew_preview" {
-        // Ensure we have required params
-        if body.params.len() < 4 {
-            let error = JsonRpcError {
-                id: body.id,
-                error: JsonRpcErrorObject {
-                    code: -32602,
-                    message: "Invalid params: requires [block_data, view_name, input_data, height]"
-                        .to_string(),
-                    data: None,
-                },
-                jsonrpc: "2.0".to_string(),
-            };
-            return Ok(HttpResponse::Ok().json(error));
-        }
-
-        let block_hex = match body.params[0].as_str() {
-            Some(s) => s.to_string(),
-            None => {
-                let error = JsonRpcError {
-                    id: body.id,
-                    error: JsonRpcErrorObject {
-                        code: -32602,
-                        message: "Invalid params: block_data must be a hex string".to_string(),
-                        data: None,
-                    },
-                    jsonrpc: "2.0".to_string(),
-                };
-                return Ok(HttpResponse::Ok().json(error));
-            }
-        };
-
-        let view_name = match body.params[1].as_str() {
-            Some(s) => s.to_string(),
-            None => {
-                let error = JsonRpcError {
-                    id: body.id,
-                    error: JsonRpcErrorObject {
-                        code: -32602,
-                        message: "Invalid params: view_name must be a string".to_string(),
-                        data: None,
-                    },
-                    jsonrpc: "2.0".to_string(),
-                };
-                return Ok(HttpResponse::Ok().json(error));
-            }
-        };
-
-        let input_hex = match body.params[2].as_str() {
-            Some(s) => s.to_string(),
-            None => {
-                let error = JsonRpcError {
+        Ok(rpc_value(result))
+    } else if body.method == "metashrew_stateroot" {
+        param_count(&body, 1..=1, "[height]")?;
+        let requested = param_height(&body, 0)?;
+        // Read the tip and the root from one snapshot so "latest" names a
+        // block whose root is already written
+        let (mut views, height) = pin_height(&body, requested, context)?;
+
+        match state_root(&mut views.db, height).map_err(|e| server_error(&body, e))? {
+            Some(root) => {
+                let result = JsonRpcResult {
                     id: body.id,
-                    error: JsonRpcErrorObject {
-                        code: -32602,
-                        message: "Invalid params: input_data must be a hex string".to_string(),
-                        data: None,
-                    },
+                    result: format!("0x{}", hex::encode(root)),
                     jsonrpc: "2.0".to_string(),
                 };
-                return Ok(HttpResponse::Ok().json(error));
-            }
-        };
-
-        let height: u32 = match &body.params[3] {
-            serde_json::Value::String(s) if s == "latest" => {
-                fetch_and_set_height(&context.runtime.context.lock().unwrap().db).await?
-            }
-            serde_json::Value::Number(n) => {
-                let h = n.as_u64().unwrap_or(0) as u32;
-                if h > height() {
-                    fetch_and_set_height(&context.runtime.context.lock().unwrap().db).await?
-                } else {
-                    h
-                }
+                Ok(rpc_value(result))
             }
-            serde_json::Value::String(s) => match s.parse::<u32>() {
-                Ok(h) => {
-                    if h > height() {
-                        fetch_and_set_height(&context.runtime.context.lock().unwrap().db).await?
-                    } else {
-                        h
-                    }
-                }
-                Err(_) => {
-                    let error = JsonRpcError {
-                        id: body.id,
-                        error: JsonRpcErrorObject {
-                            code: -32602,
-                            message: "Invalid params: height must be a number or 'latest'"
-                                .to_string(),
-                            data: None,
-                        },
-                        jsonrpc: "2.0".to_string(),
-                    };
-                    return Ok(HttpResponse::Ok().json(error));
-                }
+            None => Err(rpc_error(Some(body.id), -32000, "State root not found")),
+        }
+    } else if body.method == "metashrew_getproof" {
+        if !context.runtime.options.state_proofs {
+            return Err(rpc_error(
+                Some(body.id),
+                -32601,
+                "State proofs are disabled; restart with --enable-state-proofs",
+            ));
+        }
+        param_count(&body, 2..=2, "[key, height]")?;
+        let key = param_hex(&body, 0, "key must be a hex string")?;
+        let requested = param_height(&body, 1)?;
+        // Prove from the snapshot the tip was read from, as for
+        // metashrew_stateroot
+        let (views, height) = pin_height(&body, requested, context)?;
+
+        let proof = views.prove(&key, height).map_err(|e| server_error(&body, e))?;
+        let result = JsonRpcResult {
+            id: body.id,
+            result: ProofResult {
+                height,
+                root: format!("0x{}", hex::encode(proof.root)),
+                value: proof.value.map(|value| format!("0x{}", hex::encode(value))),
+                siblings: proof
+                    .proof
+                    .siblings
+                    .iter()
+                    .map(|sibling| format!("0x{}", hex::encode(sibling)))
+                    .collect(),
+                leaf: proof.proof.leaf.map(|(path, value_hash)| ProofLeaf {
+                    path: format!("0x{}", hex::encode(path)),
+                    value_hash: format!("0x{}", hex::encode(value_hash)),
+                }),
             },
-            _ => {
-                let error = JsonRpcError {
-                    id: body.id,
-                    error: JsonRpcErrorObject {
-                        code: -32602,
-                        message: "Invalid params: height must be a number or 'latest'".to_string(),
-                        data: None,
-                    },
-                    jsonrpc: "2.0".to_string(),
-                };
-                return Ok(HttpResponse::Ok().json(error));
-            }
+            jsonrpc: "2.0".to_string(),
         };
+        Ok(rpc_value(result))
+    } else if body.method == "metashrew_getkey" {
+        if !context.debug_rpc {
+            return Err(rpc_error(
+                Some(body.id),
+                -32601,
+                "Debug RPCs are disabled; restart with --enable-debug-rpc",
+            ));
+        }
+        param_count(&body, 2..=2, "[key, height]")?;
+        let key = param_hex(&body, 0, "key must be a hex string")?;
+        let requested = param_height(&body, 1)?;
+        let (views, height) = pin_height(&body, requested, context)?;
 
-        let block_data = match hex::decode(block_hex.trim_start_matches("0x")) {
-            Ok(data) => data,
-            Err(e) => {
-                let error = JsonRpcError {
-                    id: body.id,
-                    error: JsonRpcErrorObject {
-                        code: -32602,
-                        message: format!("Invalid hex block data: {}", e),
-                        data: None,
-                    },
-                    jsonrpc: "2.0".to_string(),
-                };
-                return Ok(HttpResponse::Ok().json(error));
-            }
+        let result = JsonRpcResult {
+            id: body.id,
+            result: views.get_key(&key, height).map_err(|e| server_error(&body, e))?,
+            jsonrpc: "2.0".to_string(),
         };
-
-        match context.runtime.preview_async(
-            &block_data,
-            view_name,
-            &hex::decode(input_hex.trim_start_matches("0x"))
-                .map_err(|e| error::ErrorBadRequest(format!("Invalid hex input: {}", e)))?,
-            height,
-        ).await {
-            Ok(res_string) => {
-                let result = JsonRpcResult {
-                    id: body.id,
-                    result: String::from("0x") + hex::encode(res_string).as_str(),
-                    jsonrpc: "2.0".to_string(),
-                };
-                Ok(HttpResponse::Ok().json(result))
-            }
-            Err(err) => {
-                let error = JsonRpcError {
-                    id: body.id,
-                    error: JsonRpcErrorObject {
-                        code: -32000,
-                        message: err.to_string(),
-                        data: None,
-                    },
-                    jsonrpc: "2.0".to_string(),
-                };
-                Ok(HttpResponse::Ok().json(error))
-            }
+        Ok(rpc_value(result))
+    } else if body.method == "metashrew_getkeyhistory" {
+        if !context.debug_rpc {
+            return Err(rpc_error(
+                Some(body.id),
+                -32601,
+                "Debug RPCs are disabled; restart with --enable-debug-rpc",
+            ));
         }
-    } else {
-        let error = JsonRpcError {
+        param_count(&body, 1..=1, "[key]")?;
+        let key = param_hex(&body, 0, "key must be a hex string")?;
+        // Leave out anything written above the committed tip
+        let (views, tip) = pin_height(&body, None, context)?;
+
+        let result = JsonRpcResult {
             id: body.id,
-            error: JsonRpcErrorObject {
-                code: -32601,
-                message: format!("Method '{}' not found", body.method),
-                data: None,
-            },
+            result: views.get_key_history(&key, tip).map_err(|e| server_error(&body, e))?,
+            jsonrpc: "2.0".to_string(),
+        };
+        Ok(rpc_value(result))
+    } else if body.method == "metashrew_preview" {
+        param_count(&body, 4.., "[block_data, view_name, input_data, height]")?;
+        let block_hex = param_str(&body, 0, "block_data must be a hex string")?;
+        let view_name = param_str(&body, 1, "view_name must be a string")?.to_string();
+        let input_hex = param_str(&body, 2, "input_data must be a hex string")?;
+        let requested = param_height(&body, 3)?;
+        let block_data = decode_view_input(&body, block_hex, "block data")?;
+        let input = decode_view_input(&body, input_hex, "input")?;
+        let (views, height) = pin_height(&body, requested, context)?;
+
+        let result = views
+            .preview_async(&block_data, view_name, &input, height)
+            .await
+            .map_err(|err| view_error(body.id, err))?;
+        let result = JsonRpcResult {
+            id: body.id,
+            result: String::from("0x") + hex::encode(result).as_str(),
             jsonrpc: "2.0".to_string(),
         };
-        Ok(HttpResponse::Ok().json(error))
+        Ok(rpc_value(result))
+    } else {
+        Err(rpc_error(
+            Some(body.id),
+            -32601,
+            format!("Method '{}' not found", body.method),
+        ))
     }
 }
 
@@ -467,20 +714,17 @@ async fn main() -> std::io::Result<()> {
 
     // Parse command line arguments (falls back to env vars via #[arg(env)])
     let args = RockshrewViewArgs::parse();
+    let runtime_options = args.runtime_options();
 
     if let Some(label) = args.label {
         set_label(label);
     }
 
-    let program = File::open(&args.indexer).expect("Failed to open program file");
-    let mut buf = BufReader::new(program);
-    let mut bytes: Vec<u8> = vec![];
-    let _ = buf.read_to_end(&mut bytes);
-    let mut hasher = Sha3::v256();
-    let mut output = [0; 32];
-    hasher.update(bytes.as_slice());
-    hasher.finalize(&mut output);
-    info!("program hash: 0x{}", hex::encode(output));
+    let expected = schedule_hashes(&args.indexer, &args.upgrades)
+        .map_err(|e| std::io::Error::other(format!("{:#}", e)))?;
+    for (height, hash) in &expected {
+        info!("indexer for blocks from {}: sha256 0x{}", height, hex::encode(hash));
+    }
 
     // Configure RocksDB options for optimal performance
     // Configure RocksDB options for optimal performance while limiting resource usage
@@ -501,11 +745,22 @@ async fn main() -> std::io::Result<()> {
     // Create secondary path if it doesn't exist
     std::fs::create_dir_all(&args.secondary_path)?;
 
-    // Setup periodic catch-up with primary using exponential backoff
-    let secondary_path = args.secondary_path.clone();
-    let db_path = args.db_path.clone();
+    // Refuse to serve views of a database left at an older storage version,
+    // or indexed with different WASM
+    // One secondary instance serves every worker, so catching it up here
+    // is what the workers read
+    let mut adapter = RocksDBRuntimeAdapter::open_secondary(
+        args.db_path.clone(),
+        args.secondary_path.clone(),
+        opts.clone(),
+    )
+    .map_err(std::io::Error::other)?;
+    check_storage_version(&adapter.db).map_err(|e| std::io::Error::other(format!("{:#}", e)))?;
+    verify_wasm(&mut adapter, &expected, args.allow_wasm_mismatch)
+        .map_err(|e| std::io::Error::other(format!("{:#}", e)))?;
 
-    let opts_clone = opts.clone();
+    // Setup periodic catch-up with primary using exponential backoff
+    let db = adapter.db.clone();
     let catch_up_interval = std::time::Duration::from_secs(1);
     actix_web::rt::spawn(async move {
         let mut interval = actix_web::rt::time::interval(catch_up_interval);
@@ -514,22 +769,15 @@ async fn main() -> std::io::Result<()> {
         
         loop {
             interval.tick().await;
-            match rocksdb::DB::open_as_secondary(&opts_clone, &db_path, &secondary_path) {
-                Ok(db) => {
-                    if let Err(e) = db.try_catch_up_with_primary() {
-                        log::warn!("Error catching up with primary: {}", e);
-                        // On error, use exponential backoff
-                        backoff = std::cmp::min(backoff * 2, max_backoff);
-                        actix_web::rt::time::sleep(backoff).await;
-                    } else {
-                        // On success, reset backoff
-                        backoff = std::time::Duration::from_secs(1);
-                    }
-                    // Explicitly close the DB handle
-                    drop(db);
+            match synchronized_catch_up(&db).await {
+                Ok(()) => {
+                    // On success, reset backoff
+                    backoff = std::time::Duration::from_secs(1);
+                    record_primary_tip(db.clone()).await;
                 }
                 Err(e) => {
-                    log::error!("Failed to open secondary DB: {}", e);
+                    log::warn!("Error catching up with primary: {}", e);
+                    // On error, use exponential backoff
                     backoff = std::cmp::min(backoff * 2, max_backoff);
                     actix_web::rt::time::sleep(backoff).await;
                 }
@@ -547,19 +795,18 @@ async fn main() -> std::io::Result<()> {
                 }
             }))
             .app_data(web::Data::new(Context {
-                hash: output,
-                program: bytes.clone(),
-                runtime: MetashrewRuntime::load(
+                runtime: MetashrewRuntime::load_with_options(
                     args.indexer.clone(),
-                    RocksDBRuntimeAdapter::open_secondary(
-                        args.db_path.clone(),
-                        args.secondary_path.clone(),
-                        opts.clone(),
-                    )
-                    .unwrap(),
+                    adapter.clone(),
+                    runtime_options.clone(),
                 ).unwrap(),
+                max_catch_up_age: args.max_catch_up_age,
+                max_batch_size: args.max_batch_size,
+                debug_rpc: args.enable_debug_rpc,
             }))
             .service(jsonrpc_call)
+            .service(health)
+            .service(ready)
     })
     .bind((args.host.as_str(), args.port))?
     .run()
@@ -0,0 +1,221 @@
+use serde_json::{json, Value};
+use std::fmt;
+use wasmtime::{Trap, WasmBacktrace};
+
+/// What went wrong in a view or preview call.
+#[derive(Debug)]
+pub enum ViewErrorKind {
+    /// The indexer exports no function by that name
+    UnknownSymbol(String),
+    /// The guest trapped, with the WASM backtrace when one was captured
+    Trap {
+        message: String,
+        backtrace: Option<String>,
+    },
+    /// The call used up its fuel or time budget
+    BudgetExceeded(Budget),
+    /// Linear memory could not grow past the store's limit
+    MemoryLimit(MemoryLimitExceeded),
+    /// The request itself was malformed
+    InvalidInput(String),
+    /// The requested height is past the last indexed block
+    HeightOutOfRange { height: u32, tip: u32 },
+    /// A host-side failure unrelated to the guest
+    Internal(anyhow::Error),
+}
+
+/// Raised by the store limiter when linear memory would grow past its cap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryLimitExceeded {
+    pub limit: usize,
+    pub desired: usize,
+}
+
+impl fmt::Display for MemoryLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "growing memory to {} bytes exceeds the limit of {} bytes",
+            self.desired, self.limit
+        )
+    }
+}
+
+impl std::error::Error for MemoryLimitExceeded {}
+
+/// An execution budget from [`CallLimits`](crate::CallLimits).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Budget {
+    Fuel,
+    Time,
+}
+
+impl Budget {
+    fn name(&self) -> &'static str {
+        match self {
+            Budget::Fuel => "fuel",
+            Budget::Time => "time",
+        }
+    }
+}
+
+/// A failed view call, carrying the `__log` output the guest produced
+/// before it failed.
+#[derive(Debug)]
+pub struct ViewError {
+    pub kind: ViewErrorKind,
+    pub log: Vec<String>,
+}
+
+impl ViewError {
+    pub fn new(kind: ViewErrorKind) -> Self {
+        Self { kind, log: vec![] }
+    }
+
+    pub fn unknown_symbol(symbol: &str) -> Self {
+        Self::new(ViewErrorKind::UnknownSymbol(symbol.to_string()))
+    }
+
+    pub fn invalid_input(message: impl Into<String>) -> Self {
+        Self::new(ViewErrorKind::InvalidInput(message.into()))
+    }
+
+    pub fn height_out_of_range(height: u32, tip: u32) -> Self {
+        Self::new(ViewErrorKind::HeightOutOfRange { height, tip })
+    }
+
+    pub fn internal(err: anyhow::Error) -> Self {
+        Self::new(ViewErrorKind::Internal(err))
+    }
+
+    /// Classifies an error returned by a guest call.
+    pub fn from_call(err: anyhow::Error, log: Vec<String>) -> Self {
+        if let Some(exceeded) = err.downcast_ref::<MemoryLimitExceeded>() {
+            return Self {
+                kind: ViewErrorKind::MemoryLimit(*exceeded),
+                log,
+            };
+        }
+        let kind = match err.downcast_ref::<Trap>() {
+            Some(Trap::OutOfFuel) => ViewErrorKind::BudgetExceeded(Budget::Fuel),
+            Some(Trap::Interrupt) => ViewErrorKind::BudgetExceeded(Budget::Time),
+            Some(trap) => ViewErrorKind::Trap {
+                message: trap.to_string(),
+                backtrace: err
+                    .downcast_ref::<WasmBacktrace>()
+                    .map(|backtrace| backtrace.to_string()),
+            },
+            None => ViewErrorKind::Internal(err),
+        };
+        Self { kind, log }
+    }
+
+    /// JSON-RPC error code for the failure.
+    pub fn code(&self) -> i32 {
+        match &self.kind {
+            ViewErrorKind::Trap { .. } => -32000,
+            ViewErrorKind::UnknownSymbol(_) => -32001,
+            ViewErrorKind::BudgetExceeded(_) => -32002,
+            ViewErrorKind::MemoryLimit(_) => -32003,
+            ViewErrorKind::HeightOutOfRange { .. } => -32004,
+            ViewErrorKind::InvalidInput(_) => -32602,
+            ViewErrorKind::Internal(_) => -32603,
+        }
+    }
+
+    /// Short machine-readable name for the failure.
+    pub fn kind_name(&self) -> &'static str {
+        match &self.kind {
+            ViewErrorKind::UnknownSymbol(_) => "unknown_symbol",
+            ViewErrorKind::Trap { .. } => "trap",
+            ViewErrorKind::BudgetExceeded(_) => "budget_exceeded",
+            ViewErrorKind::MemoryLimit(_) => "memory_limit",
+            ViewErrorKind::InvalidInput(_) => "invalid_input",
+            ViewErrorKind::HeightOutOfRange { .. } => "height_out_of_range",
+            ViewErrorKind::Internal(_) => "internal",
+        }
+    }
+
+    /// The `data` member of the JSON-RPC error object.
+    pub fn data(&self) -> Value {
+        let mut data = json!({ "kind": self.kind_name() });
+        match &self.kind {
+            ViewErrorKind::UnknownSymbol(symbol) => data["symbol"] = json!(symbol),
+            ViewErrorKind::BudgetExceeded(budget) => data["budget"] = json!(budget.name()),
+            ViewErrorKind::Trap { message, backtrace } => {
+                data["trap"] = json!(message);
+                if let Some(backtrace) = backtrace {
+                    data["backtrace"] = json!(backtrace);
+                }
+            }
+            ViewErrorKind::MemoryLimit(exceeded) => {
+                data["limit"] = json!(exceeded.limit);
+                data["desired"] = json!(exceeded.desired);
+            }
+            ViewErrorKind::HeightOutOfRange { height, tip } => {
+                data["height"] = json!(height);
+                data["tip"] = json!(tip);
+            }
+            _ => {}
+        }
+        if !self.log.is_empty() {
+            data["log"] = json!(self.log);
+        }
+        data
+    }
+}
+
+impl fmt::Display for ViewError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ViewErrorKind::UnknownSymbol(symbol) => {
+                write!(f, "indexer exports no function '{}'", symbol)
+            }
+            ViewErrorKind::Trap { message, .. } => write!(f, "view trapped: {}", message),
+            ViewErrorKind::BudgetExceeded(budget) => {
+                write!(f, "execution budget exceeded: out of {}", budget.name())
+            }
+            ViewErrorKind::MemoryLimit(exceeded) => write!(f, "memory limit exceeded: {}", exceeded),
+            ViewErrorKind::InvalidInput(message) => write!(f, "invalid input: {}", message),
+            ViewErrorKind::HeightOutOfRange { height, tip } => {
+                write!(f, "height {} is past the indexed tip {}", height, tip)
+            }
+            ViewErrorKind::Internal(err) => write!(f, "{:#}", err),
+        }
+    }
+}
+
+impl std::error::Error for ViewError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_kinds_to_codes_and_data() {
+        let err = ViewError::from_call(anyhow::Error::new(Trap::OutOfFuel), vec![]);
+        assert_eq!(err.code(), -32002);
+        assert_eq!(err.data(), json!({ "kind": "budget_exceeded", "budget": "fuel" }));
+        let err = ViewError::from_call(anyhow::Error::new(Trap::Interrupt), vec![]);
+        assert_eq!(err.data()["budget"], "time");
+
+        let err = ViewError::from_call(
+            anyhow::Error::new(Trap::UnreachableCodeReached),
+            vec!["balance: 0\n".to_string()],
+        );
+        assert_eq!(err.code(), -32000);
+        assert_eq!(err.data()["kind"], "trap");
+        assert_eq!(err.data()["log"], json!(["balance: 0\n"]));
+
+        let exceeded = MemoryLimitExceeded {
+            limit: 65536,
+            desired: 131072,
+        };
+        let err = ViewError::from_call(anyhow::Error::new(exceeded).context("wasm backtrace"), vec![]);
+        assert_eq!(err.code(), -32003);
+        assert_eq!(err.data()["limit"], 65536);
+
+        assert_eq!(ViewError::unknown_symbol("balance").code(), -32001);
+        assert_eq!(ViewError::height_out_of_range(10, 5).data()["tip"], 5);
+    }
+}
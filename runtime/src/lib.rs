@@ -1,8 +1,11 @@
 #[macro_use]
 extern crate log;
 
+pub mod error;
+pub mod proof;
 #[allow(renamed_and_removed_lints)]
 pub mod proto;
 pub mod runtime;
 
+pub use error::{Budget, MemoryLimitExceeded, ViewError, ViewErrorKind};
 pub use runtime::*;
@@ -0,0 +1,238 @@
+//! Maintenance of the state tree that `metashrew_getproof` proves keys
+//! against. See [`metashrew_support::proof`] for its shape and verification.
+//!
+//! Each node is an append-only list like any indexer key, so the tree as of
+//! a height is read with `db_value_at_block` and rolled back with the rest of
+//! a block's updates.
+use anyhow::{anyhow, Result};
+use metashrew_support::proof::{key_path, leaf_hash, node_hash, path_bit, value_hash, KeyProof, DEPTH, EMPTY};
+use std::collections::BTreeMap;
+
+pub const STATE_TREE_PREFIX: &[u8] = b"/__INTERNAL/smt/";
+
+/// Key of the node at `depth` on `path`: the depth followed by the first
+/// `depth` bits of the path.
+pub fn node_key(depth: usize, path: &[u8; 32]) -> Vec<u8> {
+    let mut key = STATE_TREE_PREFIX.to_vec();
+    key.extend_from_slice(&(depth as u16).to_be_bytes());
+    let mut prefix = path[..depth.div_ceil(8)].to_vec();
+    let bits = depth % 8;
+    if bits > 0 {
+        if let Some(last) = prefix.last_mut() {
+            *last &= 0xff << (8 - bits);
+        }
+    }
+    key.extend(prefix);
+    key
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Node {
+    Empty,
+    Leaf { path: [u8; 32], value_hash: [u8; 32] },
+    Internal([u8; 32]),
+}
+
+impl Node {
+    pub fn hash(&self) -> [u8; 32] {
+        match self {
+            Node::Empty => EMPTY,
+            Node::Leaf { path, value_hash } => leaf_hash(path, value_hash),
+            Node::Internal(hash) => *hash,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Node::Empty => vec![],
+            Node::Leaf { path, value_hash } => [&[0u8][..], path, value_hash].concat(),
+            Node::Internal(hash) => [&[1u8][..], hash].concat(),
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        match bytes {
+            [] => Ok(Node::Empty),
+            [0, rest @ ..] if rest.len() == 64 => Ok(Node::Leaf {
+                path: rest[..32].try_into()?,
+                value_hash: rest[32..].try_into()?,
+            }),
+            [1, rest @ ..] if rest.len() == 32 => Ok(Node::Internal(rest.try_into()?)),
+            _ => Err(anyhow!("malformed state tree node")),
+        }
+    }
+}
+
+fn with_bit(path: &[u8; 32], depth: usize, bit: bool) -> [u8; 32] {
+    let mut path = *path;
+    let mask = 0x80 >> (depth % 8);
+    if bit {
+        path[depth / 8] |= mask;
+    } else {
+        path[depth / 8] &= !mask;
+    }
+    path
+}
+
+/// Reads the node at `depth` on `path` through `read`, which looks up a node
+/// key in the tree as of some height.
+fn read_node<F>(read: &mut F, depth: usize, path: &[u8; 32]) -> Result<Node>
+where
+    F: FnMut(&Vec<u8>) -> Result<Vec<u8>>,
+{
+    Node::decode(&read(&node_key(depth, path))?)
+}
+
+/// Root of the tree read through `read`.
+pub fn tree_root<F>(mut read: F) -> Result<[u8; 32]>
+where
+    F: FnMut(&Vec<u8>) -> Result<Vec<u8>>,
+{
+    Ok(read_node(&mut read, 0, &EMPTY)?.hash())
+}
+
+/// Proof for `key` in the tree read through `read`.
+pub fn prove<F>(mut read: F, key: &[u8]) -> Result<KeyProof>
+where
+    F: FnMut(&Vec<u8>) -> Result<Vec<u8>>,
+{
+    let path = key_path(key);
+    let mut siblings = vec![];
+    for depth in 0..=DEPTH {
+        match read_node(&mut read, depth, &path)? {
+            Node::Empty => return Ok(KeyProof { siblings, leaf: None }),
+            Node::Leaf { path, value_hash } => {
+                return Ok(KeyProof {
+                    siblings,
+                    leaf: Some((path, value_hash)),
+                })
+            }
+            Node::Internal(_) => {
+                let sibling = with_bit(&path, depth, !path_bit(&path, depth));
+                siblings.push(read_node(&mut read, depth + 1, &sibling)?.hash());
+            }
+        }
+    }
+    Err(anyhow!("state tree is deeper than {} levels", DEPTH))
+}
+
+/// Nodes changed by the writes of one flush, on top of the tree read
+/// through `read`.
+pub struct TreeUpdate<F> {
+    read: F,
+    changed: BTreeMap<Vec<u8>, Node>,
+}
+
+impl<F> TreeUpdate<F>
+where
+    F: FnMut(&Vec<u8>) -> Result<Vec<u8>>,
+{
+    pub fn new(read: F) -> Self {
+        Self {
+            read,
+            changed: BTreeMap::new(),
+        }
+    }
+
+    fn node(&mut self, depth: usize, path: &[u8; 32]) -> Result<Node> {
+        match self.changed.get(&node_key(depth, path)) {
+            Some(node) => Ok(node.clone()),
+            None => read_node(&mut self.read, depth, path),
+        }
+    }
+
+    fn set(&mut self, depth: usize, path: &[u8; 32], node: Node) {
+        self.changed.insert(node_key(depth, path), node);
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.insert_at(0, &key_path(key), value_hash(value))
+    }
+
+    fn insert_at(&mut self, depth: usize, path: &[u8; 32], value_hash: [u8; 32]) -> Result<()> {
+        if depth > DEPTH {
+            return Err(anyhow!("state tree is deeper than {} levels", DEPTH));
+        }
+        match self.node(depth, path)? {
+            Node::Empty => {
+                self.set(depth, path, Node::Leaf { path: *path, value_hash });
+                return Ok(());
+            }
+            Node::Leaf { path: other, .. } if other == *path => {
+                self.set(depth, path, Node::Leaf { path: *path, value_hash });
+                return Ok(());
+            }
+            // The leaf already here moves down a level to make room
+            Node::Leaf { path: other, value_hash: other_hash } => self.set(
+                depth + 1,
+                &other,
+                Node::Leaf {
+                    path: other,
+                    value_hash: other_hash,
+                },
+            ),
+            Node::Internal(_) => {}
+        }
+        self.insert_at(depth + 1, path, value_hash)?;
+        let left = self.node(depth + 1, &with_bit(path, depth, false))?.hash();
+        let right = self.node(depth + 1, &with_bit(path, depth, true))?.hash();
+        self.set(depth, path, Node::Internal(node_hash(&left, &right)));
+        Ok(())
+    }
+
+    /// The changed nodes, as node keys and their encoded values.
+    pub fn into_changes(self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.changed
+            .into_iter()
+            .map(|(key, node)| (key, node.encode()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metashrew_support::proof::verify_proof;
+    use std::collections::HashMap;
+
+    fn apply(tree: &mut HashMap<Vec<u8>, Vec<u8>>, writes: &[(Vec<u8>, Vec<u8>)]) {
+        let snapshot = tree.clone();
+        let mut update = TreeUpdate::new(|key: &Vec<u8>| Ok(snapshot.get(key).cloned().unwrap_or_default()));
+        for (key, value) in writes {
+            update.insert(key, value).unwrap();
+        }
+        tree.extend(update.into_changes());
+    }
+
+    #[test]
+    fn proves_present_and_absent_keys() {
+        let mut tree = HashMap::new();
+        let read = |tree: &HashMap<Vec<u8>, Vec<u8>>| {
+            let tree = tree.clone();
+            move |key: &Vec<u8>| Ok(tree.get(key).cloned().unwrap_or_default())
+        };
+        assert_eq!(tree_root(read(&tree)).unwrap(), EMPTY);
+
+        let mut values = HashMap::new();
+        for batch in 0..4u8 {
+            let writes = (0..16u8)
+                .map(|i| (vec![batch, i], vec![i; batch as usize + 1]))
+                .chain([(vec![0, 0], vec![batch])])
+                .collect::<Vec<_>>();
+            apply(&mut tree, &writes);
+            values.extend(writes);
+            let root = tree_root(read(&tree)).unwrap();
+            for (key, value) in &values {
+                let proof = prove(read(&tree), key).unwrap();
+                assert!(verify_proof(&root, key, Some(value), &proof));
+                assert!(!verify_proof(&root, key, Some(b"other"), &proof));
+                assert!(!verify_proof(&root, key, None, &proof));
+            }
+            for key in [vec![9, 9], vec![batch + 1, 0], vec![]] {
+                let proof = prove(read(&tree), &key).unwrap();
+                assert!(verify_proof(&root, &key, None, &proof));
+                assert!(!verify_proof(&root, &key, Some(b""), &proof));
+            }
+        }
+    }
+}
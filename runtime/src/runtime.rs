@@ -2,10 +2,20 @@ use anyhow::{anyhow, Context, Result};
 use itertools::Itertools;
 //use rlp;
 use protobuf::Message;
-use std::collections::HashSet;
-use std::path::PathBuf;
+use serde::Serialize;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use std::any::Any;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashSet, VecDeque};
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use wasmtime::{Caller, Linker, Store, StoreLimits, StoreLimitsBuilder};
+use std::time::{Duration, Instant};
+use wasmtime::{
+    Caller, InstanceAllocationStrategy, InstancePre, Linker, PoolingAllocationConfig,
+    ResourceLimiter, Store,
+};
 
 fn lock_err<T>(err: std::sync::PoisonError<T>) -> anyhow::Error {
     anyhow!("Mutex lock error: {}", err)
@@ -16,11 +26,20 @@ fn try_into_vec<const N: usize>(bytes: [u8; N]) -> Result<Vec<u8>> {
     Vec::<u8>::try_from(bytes).map_err(|e| anyhow!("Failed to convert bytes to Vec: {:?}", e))
 }
 
+use crate::error::{MemoryLimitExceeded, ViewError};
+use crate::proof::{prove, tree_root, TreeUpdate};
 use crate::proto::metashrew::KeyValueFlush;
+use metashrew_support::proof::{key_path, KeyProof};
 
 type SerBlock = Vec<u8>;
+/// Raw key/value pairs read from a store, in key order
+pub type KeyValuePairs = Vec<(Vec<u8>, Vec<u8>)>;
+/// Logical keys found in one page of a prefix scan, and the raw key the
+/// next page starts at
+pub type KeyPage = (Vec<Vec<u8>>, Option<Vec<u8>>);
 pub trait BatchLike {
     fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V);
+    fn delete<K: AsRef<[u8]>>(&mut self, key: K);
     fn default() -> Self;
 }
 pub trait KeyValueStoreLike {
@@ -33,14 +52,139 @@ pub trait KeyValueStoreLike {
     where
         K: AsRef<[u8]>,
         V: AsRef<[u8]>;
+    /// Returns every raw key/value pair whose key starts with `prefix`, in key order.
+    fn scan_prefix<K: AsRef<[u8]>>(
+        &mut self,
+        prefix: K,
+    ) -> Result<KeyValuePairs, Self::Error>;
+    /// Returns up to `limit` raw key/value pairs whose key starts with
+    /// `prefix` and is at or after `start`, in key order. Stores that can
+    /// seek should override this so a page never reads the whole prefix.
+    fn scan_prefix_from<K: AsRef<[u8]>>(
+        &mut self,
+        prefix: K,
+        start: K,
+        limit: usize,
+    ) -> Result<KeyValuePairs, Self::Error> {
+        Ok(self
+            .scan_prefix(prefix)?
+            .into_iter()
+            .filter(|(k, _)| k.as_slice() >= start.as_ref())
+            .take(limit)
+            .collect())
+    }
+    /// Sets the block the next `write` commits, which records `height + 1`
+    /// (wrapping) as the tip.
+    fn set_height(&mut self, height: u32);
+    /// A copy of the store whose reads see its contents as of now, ignoring
+    /// later writes. Stores without snapshots return a copy that reads live
+    /// data.
+    fn snapshot(&self) -> Result<Self, Self::Error>
+    where
+        Self: Clone,
+    {
+        Ok(self.clone())
+    }
 }
 
 //const TIP_KEY: &[u8] = b"T";
 //const HEADERS_CF: &str = "headers";
 
 pub struct State {
-    limits: StoreLimits,
+    limits: MemoryLimiter,
     had_failure: bool,
+    scans: Vec<ScanCursor>,
+    /// Keys written by the last `__flush`
+    flushed: u32,
+    /// `__log` output, collected only for views run with
+    /// [`ViewHandle::view_with_log`]
+    log: Option<CallLog>,
+    /// Prefix for `__log` records, naming the block or view being run
+    label: String,
+    /// Context of the view being run, for host functions shared by every
+    /// view through the `InstancePre`
+    call_context: Option<Arc<dyn Any + Send + Sync>>,
+    /// State root the block's next `__flush` chains onto: the previous
+    /// block's until the block flushes, then the one its last flush wrote
+    root: Option<[u8; 32]>,
+    /// `(range start, SHA-256)` of a newly activated indexer, recorded in
+    /// the batch of the first block it commits
+    wasm_hash: Option<(u32, [u8; 32])>,
+}
+
+/// Caps a store's linear memory. A refused grow fails the call with
+/// [`MemoryLimitExceeded`] instead of letting the host run out of memory.
+#[derive(Default)]
+struct MemoryLimiter {
+    max_memory: Option<usize>,
+}
+
+impl ResourceLimiter for MemoryLimiter {
+    fn memory_growing(&mut self, _current: usize, desired: usize, _maximum: Option<usize>) -> Result<bool> {
+        match self.max_memory {
+            Some(limit) if desired > limit => Err(MemoryLimitExceeded { limit, desired }.into()),
+            _ => Ok(true),
+        }
+    }
+
+    fn table_growing(&mut self, _current: u32, _desired: u32, _maximum: Option<u32>) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn instances(&self) -> usize {
+        usize::MAX
+    }
+
+    fn tables(&self) -> usize {
+        usize::MAX
+    }
+
+    fn memories(&self) -> usize {
+        usize::MAX
+    }
+}
+
+/// Host-side cursor backing a `__scan_prefix` handle. Raw entries are read
+/// a page at a time as the guest advances, so a scan it stops early never
+/// reads the rest of the prefix. `pending` holds the encoded entry reported
+/// by the last `__iter_next_len` call.
+#[derive(Default)]
+struct ScanCursor {
+    prefix: Vec<u8>,
+    /// Raw key the next page starts at, `None` once the prefix is exhausted
+    next: Option<Vec<u8>>,
+    keys: VecDeque<Vec<u8>>,
+    pending: Option<Vec<u8>>,
+}
+
+/// Raw entries read per page of a prefix scan
+const SCAN_PAGE_SIZE: usize = 1024;
+
+/// Most `__log` output a single view keeps, in bytes. Output past it is
+/// still written to the host log but dropped from the call's result.
+pub const MAX_CALL_LOG_BYTES: usize = 64 * 1024;
+
+/// `__log` output captured for one call, bounded by [`MAX_CALL_LOG_BYTES`]
+#[derive(Default)]
+struct CallLog {
+    lines: Vec<String>,
+    bytes: usize,
+    truncated: bool,
+}
+
+impl CallLog {
+    fn push(&mut self, text: &str) {
+        if self.truncated {
+            return;
+        }
+        if self.bytes + text.len() > MAX_CALL_LOG_BYTES {
+            self.lines.push(String::from("[log truncated]\n"));
+            self.truncated = true;
+            return;
+        }
+        self.bytes += text.len();
+        self.lines.push(text.to_string());
+    }
 }
 
 #[derive(Debug)]
@@ -77,6 +221,45 @@ impl<T: KeyValueStoreLike + Clone> KeyValueStoreLike for PreviewDBWrapper<T> {
         self.underlying_db.get(key)
     }
 
+    fn scan_prefix<K: AsRef<[u8]>>(
+        &mut self,
+        prefix: K,
+    ) -> Result<KeyValuePairs, Self::Error> {
+        let mut merged: std::collections::BTreeMap<Vec<u8>, Vec<u8>> = self
+            .underlying_db
+            .scan_prefix(prefix.as_ref())?
+            .into_iter()
+            .collect();
+        // Overlay entries shadow the underlying db, same as in get
+        for (k, v) in self.overlay.iter() {
+            if k.starts_with(prefix.as_ref()) {
+                merged.insert(k.clone(), v.clone());
+            }
+        }
+        Ok(merged.into_iter().collect())
+    }
+
+    fn scan_prefix_from<K: AsRef<[u8]>>(
+        &mut self,
+        prefix: K,
+        start: K,
+        limit: usize,
+    ) -> Result<KeyValuePairs, Self::Error> {
+        // Every underlying key past this page sorts after all of it, so the
+        // first `limit` of the page and the overlay together are exact
+        let mut merged: std::collections::BTreeMap<Vec<u8>, Vec<u8>> = self
+            .underlying_db
+            .scan_prefix_from(prefix.as_ref(), start.as_ref(), limit)?
+            .into_iter()
+            .collect();
+        for (k, v) in self.overlay.iter() {
+            if k.starts_with(prefix.as_ref()) && k.as_slice() >= start.as_ref() {
+                merged.insert(k.clone(), v.clone());
+            }
+        }
+        Ok(merged.into_iter().take(limit).collect())
+    }
+
     fn delete<K: AsRef<[u8]>>(&mut self, _key: K) -> Result<(), Self::Error> {
         // For preview we don't need to implement delete
         Ok(())
@@ -93,6 +276,10 @@ impl<T: KeyValueStoreLike + Clone> KeyValueStoreLike for PreviewDBWrapper<T> {
         );
         Ok(())
     }
+
+    fn set_height(&mut self, height: u32) {
+        self.underlying_db.set_height(height)
+    }
 }
 
 pub struct MetashrewRuntimeContext<T: KeyValueStoreLike + Clone> {
@@ -133,19 +320,430 @@ pub struct MetashrewRuntime<T: KeyValueStoreLike + Clone + 'static> {
     pub module: wasmtime::Module,
     pub linker: wasmtime::Linker<State>,
     pub instance: wasmtime::Instance,
+    /// Times the instance has been recreated by `refresh_memory`
+    pub memory_refreshes: u64,
+    pub options: RuntimeOptions,
+    /// Every indexer in the upgrade schedule, by activation height
+    pub schedule: Vec<IndexerModule>,
+    /// Index into `schedule` of the indexer running blocks, once one has run
+    pub active: Option<usize>,
+    /// Drives `async_engine`'s call timeouts, shared with every view handle
+    pub epoch_ticker: Option<Arc<EpochTicker>>,
+}
+
+/// An indexer from the upgrade schedule, compiled for both engines.
+#[derive(Clone)]
+pub struct IndexerModule {
+    /// First block this indexer processes
+    pub activation_height: u32,
+    /// SHA-256 of the WASM, recorded under `wasm_hash_key`
+    pub hash: [u8; 32],
+    pub module: wasmtime::Module,
+    pub async_module: wasmtime::Module,
+    /// `async_module` with the view imports resolved, shared by every view
+    pub view_pre: InstancePre<State>,
+}
+
+/// Index into `schedule`, sorted by activation height, of the indexer that
+/// processes `height`.
+fn scheduled_at(schedule: &[IndexerModule], height: u32) -> usize {
+    schedule
+        .partition_point(|indexer| indexer.activation_height <= height)
+        .saturating_sub(1)
+}
+
+pub const WASM_HASH_PREFIX: &[u8] = b"/__INTERNAL/wasm-hash/";
+
+/// Key recording the hash of the indexer that processed blocks from
+/// `start` up to the next recorded range.
+pub fn wasm_hash_key(start: u32) -> Vec<u8> {
+    let mut key = WASM_HASH_PREFIX.to_vec();
+    key.extend(start.to_be_bytes());
+    key
+}
+
+/// An indexer that replaces the previous one from `height` on, written
+/// `HEIGHT:PATH` on the command line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Upgrade {
+    pub height: u32,
+    pub indexer: PathBuf,
+}
+
+impl std::str::FromStr for Upgrade {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (height, indexer) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected HEIGHT:PATH, got '{}'", s))?;
+        let height = height
+            .parse()
+            .map_err(|e| format!("invalid activation height '{}': {}", height, e))?;
+        Ok(Upgrade {
+            height,
+            indexer: PathBuf::from(indexer),
+        })
+    }
+}
+
+pub const STATE_ROOT_PREFIX: &[u8] = b"/__INTERNAL/state-root/";
+
+/// Key holding the state root after block `height`.
+pub fn state_root_key(height: u32) -> Vec<u8> {
+    let mut key = STATE_ROOT_PREFIX.to_vec();
+    key.extend(height.to_be_bytes());
+    key
+}
+
+/// Digest of one `__flush`: every key and value, each prefixed with its
+/// length, in key order. Writes to the same key keep their flush order.
+pub fn block_digest(pairs: &[(Vec<u8>, Vec<u8>)]) -> [u8; 32] {
+    let mut sorted = pairs.iter().collect::<Vec<_>>();
+    sorted.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut engine = sha256::Hash::engine();
+    for (key, value) in sorted {
+        engine.input(&(key.len() as u32).to_le_bytes());
+        engine.input(key);
+        engine.input(&(value.len() as u32).to_le_bytes());
+        engine.input(value);
+    }
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+/// Folds a block's digest into the root before it, so two databases with
+/// the same root at a height wrote the same data at every height up to it.
+pub fn chain_state_root(previous: &[u8; 32], digest: &[u8; 32]) -> [u8; 32] {
+    let mut engine = sha256::Hash::engine();
+    engine.input(previous);
+    engine.input(digest);
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+/// The state root block `height` chains onto: the one recorded after the
+/// block before it. The chain starts from zeros at the first block indexed
+/// into a database, so a missing root below `height` once any root has been
+/// recorded is an error rather than a restart.
+pub fn previous_state_root<T: KeyValueStoreLike>(db: &mut T, height: u32) -> Result<[u8; 32]> {
+    if let Some(below) = height.checked_sub(1) {
+        if let Some(root) = state_root(db, below)? {
+            return Ok(root);
+        }
+    }
+    let recorded = db
+        .scan_prefix_from(STATE_ROOT_PREFIX, STATE_ROOT_PREFIX, 1)
+        .map_err(|e| anyhow!("Database error: {:?}", e))?;
+    if recorded.is_empty() {
+        Ok([0; 32])
+    } else {
+        Err(anyhow!("no state root recorded for block {}", height.wrapping_sub(1)))
+    }
+}
+
+/// The state root recorded after block `height`, if it was indexed.
+pub fn state_root<T: KeyValueStoreLike>(db: &mut T, height: u32) -> Result<Option<[u8; 32]>> {
+    match db
+        .get(state_root_key(height))
+        .map_err(|e| anyhow!("Database error: {:?}", e))?
+    {
+        Some(root) => Ok(Some(
+            root.as_slice()
+                .try_into()
+                .map_err(|_| anyhow!("Malformed state root at height {}", height))?,
+        )),
+        None => Ok(None),
+    }
+}
+
+/// The `(activation height, SHA-256)` of `indexer` and each upgrade, as
+/// `load_with_options` would record them.
+pub fn schedule_hashes(indexer: &Path, upgrades: &[Upgrade]) -> Result<Vec<(u32, [u8; 32])>> {
+    std::iter::once((0, indexer))
+        .chain(upgrades.iter().map(|upgrade| (upgrade.height, upgrade.indexer.as_path())))
+        .map(|(height, path)| {
+            let wasm = std::fs::read(path)
+                .with_context(|| format!("Failed to read WASM module {}", path.display()))?;
+            Ok((height, sha256::Hash::hash(&wasm).to_byte_array()))
+        })
+        .collect()
+}
+
+/// Every indexer hash recorded in `db`, as `(range start, SHA-256)` in
+/// height order. Each range runs up to the start of the next.
+pub fn recorded_wasm<T: KeyValueStoreLike>(db: &mut T) -> Result<Vec<(u32, [u8; 32])>> {
+    let mut recorded = db
+        .scan_prefix(WASM_HASH_PREFIX)
+        .map_err(|e| anyhow!("Database error: {:?}", e))?
+        .into_iter()
+        .map(|(key, hash)| {
+            let height = key[WASM_HASH_PREFIX.len()..]
+                .try_into()
+                .map(u32::from_be_bytes)
+                .map_err(|_| anyhow!("Malformed WASM hash key: {}", hex::encode(&key)))?;
+            let hash = hash
+                .as_slice()
+                .try_into()
+                .map_err(|_| anyhow!("Malformed WASM hash from block {}", height))?;
+            Ok((height, hash))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    recorded.sort_by_key(|(height, _)| *height);
+    Ok(recorded)
+}
+
+/// Describes each range of blocks recorded in `db` as indexed with a hash
+/// other than that of an indexer `expected` schedules within it. The last
+/// range is only checked against the indexer active where it starts, as
+/// later activations may not have been reached yet.
+pub fn wasm_mismatches<T: KeyValueStoreLike>(
+    db: &mut T,
+    expected: &[(u32, [u8; 32])],
+) -> Result<Vec<String>> {
+    let mut expected = expected.to_vec();
+    expected.sort_by_key(|(activation, _)| *activation);
+    let recorded = recorded_wasm(db)?;
+    let mut mismatches = vec![];
+    for (index, (start, hash)) in recorded.iter().enumerate() {
+        let end = recorded.get(index + 1).map(|(next, _)| *next);
+        let active = expected.partition_point(|(activation, _)| *activation <= *start);
+        if active == 0 {
+            mismatches.push(format!(
+                "the database was indexed with {} from block {}, but no indexer activates there",
+                hex::encode(hash),
+                start
+            ));
+            continue;
+        }
+        let within = expected[active..]
+            .iter()
+            .take_while(|(activation, _)| end.is_some_and(|end| *activation < end));
+        for (activation, supplied) in std::iter::once(&expected[active - 1]).chain(within) {
+            if supplied != hash {
+                mismatches.push(format!(
+                    "the indexer for blocks from {} has hash {}, but the database was indexed with {}",
+                    (*activation).max(*start),
+                    hex::encode(supplied),
+                    hex::encode(hash)
+                ));
+            }
+        }
+    }
+    Ok(mismatches)
+}
+
+/// Fails when `db` was indexed with WASM other than `expected`, or only
+/// warns with `allow_mismatch`.
+pub fn verify_wasm<T: KeyValueStoreLike>(
+    db: &mut T,
+    expected: &[(u32, [u8; 32])],
+    allow_mismatch: bool,
+) -> Result<()> {
+    let mismatches = wasm_mismatches(db, expected)?;
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+    if allow_mismatch {
+        for mismatch in &mismatches {
+            warn!("WASM mismatch: {}", mismatch);
+        }
+        return Ok(());
+    }
+    Err(anyhow!(
+        "indexer WASM does not match the database: {}; pass --allow-wasm-mismatch to run anyway",
+        mismatches.join("; ")
+    ))
+}
+
+/// How often the epoch ticker advances the async engine's epoch, which is
+/// the resolution of call timeouts.
+const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+/// Advances an engine's epoch every [`EPOCH_TICK`] on a thread of its own,
+/// which exits once the last runtime or view handle holding it is dropped.
+pub struct EpochTicker {
+    stop: Arc<AtomicBool>,
+}
+
+impl EpochTicker {
+    fn start(engine: wasmtime::Engine) -> Arc<Self> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        std::thread::spawn(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                std::thread::sleep(EPOCH_TICK);
+                engine.increment_epoch();
+            }
+        });
+        Arc::new(EpochTicker { stop })
+    }
+}
+
+impl Drop for EpochTicker {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Budget for a single view or preview call. `None` leaves a budget
+/// unlimited.
+#[derive(Clone, Debug, Default)]
+pub struct CallLimits {
+    /// Fuel the call may consume, roughly one unit per WASM instruction
+    pub fuel: Option<u64>,
+    /// Wall-clock time the call may run
+    pub timeout: Option<Duration>,
+    /// Bytes of linear memory the call's instance may grow to
+    pub max_memory: Option<usize>,
+}
+
+impl CallLimits {
+    fn apply(&self, store: &mut Store<State>, epochs: bool) -> Result<()> {
+        store.data_mut().limits.max_memory = self.max_memory;
+        store.set_fuel(self.fuel.unwrap_or(u64::MAX))?;
+        // Fuel also makes long calls yield so they share the executor
+        store.fuel_async_yield_interval(Some(10000))?;
+        if epochs {
+            let ticks = match self.timeout {
+                Some(timeout) => (timeout.as_millis() / EPOCH_TICK.as_millis()).max(1) as u64,
+                // Far enough to never arrive, without overflowing the epoch
+                None => u64::MAX / 2,
+            };
+            store.set_epoch_deadline(ticks);
+            store.epoch_deadline_trap();
+        }
+        Ok(())
+    }
+
+    /// What is left of these limits for a call that has `fuel` left and
+    /// began at `started`.
+    fn remaining(&self, fuel: u64, started: Instant) -> Self {
+        CallLimits {
+            fuel: self.fuel.map(|_| fuel),
+            timeout: self.timeout.map(|timeout| timeout.saturating_sub(started.elapsed())),
+            max_memory: self.max_memory,
+        }
+    }
+}
+
+/// Options for [`MetashrewRuntime::load_with_options`].
+#[derive(Clone, Debug, Default)]
+pub struct RuntimeOptions {
+    /// Limits on each `view` call
+    pub view: CallLimits,
+    /// Limits on each `preview_async` call, shared by the block and the view
+    pub preview: CallLimits,
+    /// Bytes of linear memory the indexer instance may grow to while
+    /// processing blocks
+    pub indexer_max_memory: Option<usize>,
+    /// Size of the pool of preallocated instances that views and previews
+    /// are instantiated into. `None` allocates each instance on demand.
+    pub instance_pool: Option<u32>,
+    /// Directory holding compiled modules, keyed by the hash of the WASM
+    /// and the engine settings, so restarts skip recompiling the indexer
+    pub module_cache: Option<PathBuf>,
+    /// Indexers that take over from the one passed to `load_with_options`
+    /// at later heights
+    pub upgrades: Vec<Upgrade>,
+    /// Maintain the state tree that key proofs are served from. It commits
+    /// only to keys flushed while this is on, so it must be set from the
+    /// first block indexed.
+    pub state_proofs: bool,
+}
+
+impl RuntimeOptions {
+    fn has_timeouts(&self) -> bool {
+        self.view.timeout.is_some() || self.preview.timeout.is_some()
+    }
+
+    /// Pooling allocator sized for `instance_pool` concurrent instances,
+    /// each with room for the largest view or preview memory limit.
+    fn pooling(&self) -> Option<PoolingAllocationConfig> {
+        let count = self.instance_pool?;
+        let max_memory = match (self.view.max_memory, self.preview.max_memory) {
+            (Some(view), Some(preview)) => Some(view.max(preview)),
+            _ => None,
+        };
+        let mut pooling = PoolingAllocationConfig::default();
+        pooling
+            .total_core_instances(count)
+            .total_memories(count)
+            .total_tables(count)
+            .total_stacks(count)
+            .memory_pages(max_memory.map_or(WASM_MAX_PAGES, |bytes| {
+                (bytes as u64).div_ceil(WASM_PAGE_SIZE).min(WASM_MAX_PAGES)
+            }));
+        Some(pooling)
+    }
+}
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+const WASM_MAX_PAGES: u64 = 65536;
+
+/// Compiles `wasm` for `engine`, reusing the copy serialized into
+/// `cache_dir` by an earlier run when there is one.
+fn load_module(engine: &wasmtime::Engine, wasm: &[u8], cache_dir: Option<&Path>) -> Result<wasmtime::Module> {
+    let cache_dir = match cache_dir {
+        Some(cache_dir) => cache_dir,
+        None => return wasmtime::Module::new(engine, wasm),
+    };
+    let mut hasher = DefaultHasher::new();
+    std::hash::Hash::hash(&engine.precompile_compatibility_hash(), &mut hasher);
+    let path = cache_dir.join(format!(
+        "{}-{:016x}.cwasm",
+        sha256::Hash::hash(wasm),
+        hasher.finish()
+    ));
+    if path.exists() {
+        // Safety: files in the cache are only ever written below, from
+        // `Module::serialize` on an engine with the same settings
+        match unsafe { wasmtime::Module::deserialize_file(engine, &path) } {
+            Ok(module) => return Ok(module),
+            Err(e) => warn!("recompiling, cached module {} is unusable: {:#}", path.display(), e),
+        }
+    }
+    let module = wasmtime::Module::new(engine, wasm)?;
+    // A cache that cannot be written only costs the next restart a compile
+    let stored = std::fs::create_dir_all(cache_dir)
+        .and_then(|_| module.serialize().map_err(std::io::Error::other))
+        .and_then(|bytes| {
+            let tmp = path.with_extension("tmp");
+            std::fs::write(&tmp, bytes)?;
+            std::fs::rename(&tmp, &path)
+        });
+    if let Err(e) = stored {
+        warn!("failed to cache compiled module at {}: {}", path.display(), e);
+    }
+    Ok(module)
 }
 
 impl State {
     pub fn new() -> Self {
         State {
-            limits: StoreLimitsBuilder::new()
-                .memories(usize::MAX)
-                .tables(usize::MAX)
-                .instances(usize::MAX)
-                .build(),
+            limits: MemoryLimiter::default(),
             had_failure: false,
+            scans: vec![],
+            flushed: 0,
+            log: None,
+            label: String::from("indexer"),
+            call_context: None,
+            root: None,
+            wasm_hash: None,
         }
     }
+
+    /// State for a view or preview call, which keeps its `__log` output
+    /// when `capture_log` is set.
+    pub fn for_call(label: String, capture_log: bool) -> Self {
+        State {
+            log: capture_log.then(CallLog::default),
+            label,
+            ..State::new()
+        }
+    }
+
+    fn take_log(&mut self) -> Vec<String> {
+        self.log.take().map(|log| log.lines).unwrap_or_default()
+    }
 }
 
 pub fn db_make_list_key(v: &Vec<u8>, index: u32) -> Result<Vec<u8>> {
@@ -195,6 +793,23 @@ pub fn db_annotate_value(v: &Vec<u8>, block_height: u32) -> Result<Vec<u8>> {
     Ok(entry)
 }
 
+/// Reads the block height annotated onto the end of a stored value.
+pub fn db_entry_height(value: &Vec<u8>) -> Result<u32> {
+    if value.len() < 4 {
+        return Err(anyhow!("Invalid value length: {}", value.len()));
+    }
+    let bytes: [u8; 4] = value.as_slice()[(value.len() - 4)..]
+        .try_into()
+        .map_err(|e| anyhow!("Invalid value height bytes: {:?}", e))?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Strips the height annotation from a stored value.
+pub fn db_entry_value(mut value: Vec<u8>) -> Vec<u8> {
+    value.truncate(value.len().saturating_sub(4));
+    value
+}
+
 pub fn to_signed_or_trap<'a, T: TryInto<i32>>(_caller: &mut Caller<'_, State>, v: T) -> i32 {
     return match <T as TryInto<i32>>::try_into(v) {
         Ok(v) => v,
@@ -213,23 +828,300 @@ pub fn to_usize_or_trap<'a, T: TryInto<usize>>(_caller: &mut Caller<'_, State>,
     };
 }
 
+/// A key's value at some height, `None` when it was never written, with
+/// the proof of it against the state tree root at that height.
+pub struct StateProof {
+    pub root: [u8; 32],
+    pub value: Option<Vec<u8>>,
+    pub proof: KeyProof,
+}
+
+/// `metashrew_getkey` result. `updated` is the height the value was written
+/// at, and both it and `value` are null when the key was not yet written.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct KeyResult {
+    pub height: u32,
+    pub updated: Option<u32>,
+    pub value: Option<String>,
+}
+
+/// One write in a `metashrew_getkeyhistory` result
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct KeyHistoryEntry {
+    pub height: u32,
+    pub value: String,
+}
+
+/// Runs views and previews independently of the [`MetashrewRuntime`] it
+/// came from, so they never wait on block processing. Each call reads a
+/// snapshot of the database at the height it is given, which pins its
+/// result no matter how far the indexer advances meanwhile.
+#[derive(Clone)]
+pub struct ViewHandle<T: KeyValueStoreLike + Clone + 'static> {
+    pub db: T,
+    pub engine: wasmtime::Engine,
+    /// Every indexer in the upgrade schedule, so a call runs the one that
+    /// was active at its height
+    pub schedule: Vec<IndexerModule>,
+    pub options: RuntimeOptions,
+    /// Keeps `engine`'s call timeouts ticking while the handle is alive
+    pub epoch_ticker: Option<Arc<EpochTicker>>,
+}
+
+impl<T: KeyValueStoreLike> ViewHandle<T>
+where
+    T: Sync + Send,
+    T: Clone + 'static,
+{
+    /// This handle with its database pinned to a snapshot taken now, so
+    /// every call made through it reads the same committed state.
+    pub fn snapshot(&self) -> Result<Self> {
+        Ok(ViewHandle {
+            db: self.db.snapshot().map_err(|e| anyhow!("Failed to snapshot database: {:?}", e))?,
+            ..self.clone()
+        })
+    }
+
+    /// The value of `key` at `height` with its proof against the root of
+    /// the state tree at that height.
+    pub fn prove(&self, key: &Vec<u8>, height: u32) -> Result<StateProof> {
+        let context = self.read_context(height)?;
+        let read = |key: &Vec<u8>| MetashrewRuntime::db_value_at_block(context.clone(), key, height);
+        let root = tree_root(read)?;
+        let proof = prove(read, key)?;
+        let value = match proof.leaf {
+            Some((path, _)) if path == key_path(key) => Some(read(key)?),
+            _ => None,
+        };
+        Ok(StateProof { root, value, proof })
+    }
+
+    /// The value of `key` at `height` with the height it was written at,
+    /// as [`MetashrewRuntime::db_entry_at_block`] reads it.
+    pub fn entry_at(&self, key: &Vec<u8>, height: u32) -> Result<Option<(u32, Vec<u8>)>> {
+        MetashrewRuntime::db_entry_at_block(self.read_context(height)?, key, height)
+    }
+
+    /// Every value written to `key`, as [`MetashrewRuntime::db_key_history`]
+    /// reads it.
+    pub fn key_history(&self, key: &Vec<u8>) -> Result<Vec<(u32, Vec<u8>)>> {
+        MetashrewRuntime::db_key_history(self.read_context(0)?, key)
+    }
+
+    /// `key` at `height` as `metashrew_getkey` reports it.
+    pub fn get_key(&self, key: &Vec<u8>, height: u32) -> Result<KeyResult> {
+        let entry = self.entry_at(key, height)?;
+        Ok(KeyResult {
+            height,
+            updated: entry.as_ref().map(|(updated, _)| *updated),
+            value: entry.map(|(_, value)| format!("0x{}", hex::encode(value))),
+        })
+    }
+
+    /// Every write to `key` up to `tip` as `metashrew_getkeyhistory` reports
+    /// it, leaving out anything written above the committed tip.
+    pub fn get_key_history(&self, key: &Vec<u8>, tip: u32) -> Result<Vec<KeyHistoryEntry>> {
+        Ok(self
+            .key_history(key)?
+            .into_iter()
+            .filter(|(height, _)| *height <= tip)
+            .map(|(height, value)| KeyHistoryEntry {
+                height,
+                value: format!("0x{}", hex::encode(value)),
+            })
+            .collect())
+    }
+
+    fn read_context(&self, height: u32) -> Result<Arc<Mutex<MetashrewRuntimeContext<T>>>> {
+        let db = self.snapshot()?.db;
+        Ok(Arc::new(Mutex::new(MetashrewRuntimeContext::new(db, height, vec![]))))
+    }
+
+    pub async fn preview_async(
+        &self,
+        block: &[u8],
+        symbol: String,
+        input: &[u8],
+        height: u32,
+    ) -> Result<Vec<u8>, ViewError> {
+        // Create preview context with wrapped DB
+        let preview_db = PreviewDBWrapper {
+            underlying_db: self.snapshot().map_err(ViewError::internal)?.db,
+            overlay: std::collections::HashMap::new(),
+        };
+
+        let indexer = &self.schedule[scheduled_at(&self.schedule, height)];
+        let started = Instant::now();
+        let limits = &self.options.preview;
+        let epochs = self.options.has_timeouts();
+
+        // Create a new runtime with preview db
+        let label = format!("preview at {}", height);
+        let mut runtime = MetashrewRuntime::<T>::new_with_db(preview_db, height, &self.engine, &indexer.async_module, limits, epochs, label)
+            .await
+            .map_err(ViewError::internal)?;
+        runtime.context.lock().map_err(lock_err).map_err(ViewError::internal)?.block = block.to_vec();
+
+        // Execute block via _start to populate preview db
+        let start = runtime.instance.get_typed_func::<(), ()>(&mut runtime.wasmstore, "_start")
+            .context("Failed to get _start function for preview")
+            .map_err(ViewError::internal)?;
+
+        match start.call_async(&mut runtime.wasmstore, ()).await {
+            Ok(_) => {
+                let context_guard = runtime.context.lock().map_err(lock_err).map_err(ViewError::internal)?;
+                if context_guard.state != 1 && !runtime.wasmstore.data().had_failure {
+                    return Err(ViewError::internal(anyhow!("indexer exited unexpectedly during preview")));
+                }
+            }
+            Err(e) => {
+                return Err(ViewError::from_call(e.context("Error executing _start in preview"), vec![]));
+            }
+        }
+
+        // Create new runtime just for the view using the same wrapped DB,
+        // with whatever budget the block left over
+        let db = runtime.context.lock().map_err(lock_err).map_err(ViewError::internal)?.db.clone();
+        let remaining = limits.remaining(runtime.wasmstore.get_fuel().unwrap_or(0), started);
+        let label = format!("preview {} at {}", symbol, height);
+        let mut view_runtime = MetashrewRuntime::<T>::new_with_db(db, height, &self.engine, &indexer.async_module, &remaining, epochs, label)
+            .await
+            .map_err(ViewError::internal)?;
+
+        // Set block to input for view
+        view_runtime.context.lock().map_err(lock_err).map_err(ViewError::internal)?.block = input.to_vec();
+
+        // Execute view function
+        if view_runtime.instance.get_func(&mut view_runtime.wasmstore, symbol.as_str()).is_none() {
+            return Err(ViewError::unknown_symbol(&symbol));
+        }
+        let func = view_runtime.instance
+            .get_typed_func::<(), i32>(&mut view_runtime.wasmstore, symbol.as_str())
+            .context("Failed to get view function")
+            .map_err(ViewError::internal)?;
+
+        let result = func
+            .call_async(&mut view_runtime.wasmstore, ())
+            .await
+            .map_err(|e| ViewError::from_call(e, vec![]))?;
+
+        let memory = view_runtime.instance
+            .get_memory(&mut view_runtime.wasmstore, "memory")
+            .ok_or_else(|| ViewError::internal(anyhow!("Failed to get memory for view result")))?;
+
+        // Get the final result
+        Ok(read_arraybuffer_as_vec(
+            memory.data(&mut view_runtime.wasmstore),
+            result,
+        ))
+    }
+
+    pub async fn view(&self, symbol: String, input: &[u8], height: u32) -> Result<Vec<u8>, ViewError> {
+        self.call_view(symbol, input, height, false).await.map(|(result, _)| result)
+    }
+
+    /// Runs a view like [`view`](Self::view) and also returns the `__log`
+    /// output it produced, up to [`MAX_CALL_LOG_BYTES`].
+    pub async fn view_with_log(
+        &self,
+        symbol: String,
+        input: &[u8],
+        height: u32,
+    ) -> Result<(Vec<u8>, Vec<String>), ViewError> {
+        self.call_view(symbol, input, height, true).await
+    }
+
+    async fn call_view(
+        &self,
+        symbol: String,
+        input: &[u8],
+        height: u32,
+        capture_log: bool,
+    ) -> Result<(Vec<u8>, Vec<String>), ViewError> {
+        let label = format!("view {} at {}", symbol, height);
+        let mut wasmstore = Store::<State>::new(&self.engine, State::for_call(label, capture_log));
+
+        let context = Arc::new(Mutex::new(MetashrewRuntimeContext::new(
+            self.snapshot().map_err(ViewError::internal)?.db,
+            height,
+            input.to_vec(),
+        )));
+
+        self.options
+            .view
+            .apply(&mut wasmstore, self.options.has_timeouts())
+            .map_err(ViewError::internal)?;
+
+        {
+            wasmstore.limiter(|state| &mut state.limits)
+        }
+        wasmstore.data_mut().call_context = Some(context);
+
+        let indexer = &self.schedule[scheduled_at(&self.schedule, height)];
+        let instance = indexer
+            .view_pre
+            .instantiate_async(&mut wasmstore)
+            .await
+            .context("Failed to instantiate module for view")
+            .map_err(ViewError::internal)?;
+
+        if instance.get_func(&mut wasmstore, symbol.as_str()).is_none() {
+            return Err(ViewError::unknown_symbol(&symbol));
+        }
+        let func = instance
+            .get_typed_func::<(), i32>(&mut wasmstore, symbol.as_str())
+            .with_context(|| format!("Failed to get view function '{}'", symbol))
+            .map_err(ViewError::internal)?;
+
+        // Use async call
+        let result = match func.call_async(&mut wasmstore, ()).await {
+            Ok(result) => result,
+            Err(e) => {
+                let log = wasmstore.data_mut().take_log();
+                return Err(ViewError::from_call(e, log));
+            }
+        };
+
+        let memory = instance
+            .get_memory(&mut wasmstore, "memory")
+            .ok_or_else(|| ViewError::internal(anyhow!("Failed to get memory for view result")))?;
+
+        let result = read_arraybuffer_as_vec(memory.data(&mut wasmstore), result);
+        Ok((result, wasmstore.data_mut().take_log()))
+    }
+}
+
 impl<T: KeyValueStoreLike> MetashrewRuntime<T>
 where
     T: Sync + Send,
     T: Clone + 'static,
 {
     pub fn load(indexer: PathBuf, store: T) -> Result<Self> {
+        Self::load_with_options(indexer, store, RuntimeOptions::default())
+    }
+
+    pub fn load_with_options(indexer: PathBuf, store: T, options: RuntimeOptions) -> Result<Self> {
         // Configure the engine with default settings
         let mut async_config = wasmtime::Config::default();
         async_config.consume_fuel(true);
         async_config.async_support(true);
+        async_config.epoch_interruption(options.has_timeouts());
+        if let Some(pooling) = options.pooling() {
+            async_config.allocation_strategy(InstanceAllocationStrategy::Pooling(pooling));
+        }
         let config = wasmtime::Config::default();
         let engine = wasmtime::Engine::new(&config)?;
         let async_engine = wasmtime::Engine::new(&async_config)?;
-        let module = wasmtime::Module::from_file(&engine, indexer.clone().into_os_string()).context("Failed to load WASM module")?;
-        let async_module = wasmtime::Module::from_file(&async_engine, indexer.into_os_string()).context("Failed to load WASM module")?;
+        let epoch_ticker = options.has_timeouts().then(|| EpochTicker::start(async_engine.clone()));
+        let mut upgrades = vec![Upgrade { height: 0, indexer }];
+        upgrades.extend(options.upgrades.iter().cloned());
+        upgrades.sort_by_key(|upgrade| upgrade.height);
+        if let Some(pair) = upgrades.windows(2).find(|pair| pair[0].height == pair[1].height) {
+            return Err(anyhow!("more than one indexer activates at height {}", pair[1].height));
+        }
         let mut linker = Linker::<State>::new(&engine);
         let mut wasmstore = Store::<State>::new(&engine, State::new());
+        wasmstore.data_mut().limits.max_memory = options.indexer_max_memory;
         let context = Arc::<Mutex<MetashrewRuntimeContext<T>>>::new(Mutex::<
             MetashrewRuntimeContext<T>,
         >::new(
@@ -238,13 +1130,37 @@ where
         {
             wasmstore.limiter(|state| &mut state.limits)
         }
-        {
-            Self::setup_linker(context.clone(), &mut linker)
-                .context("Failed to setup basic linker")?;
-            Self::setup_linker_indexer(context.clone(), &mut linker)
-                .context("Failed to setup indexer linker")?;
+        Self::setup_linker(context.clone(), &mut linker)
+            .context("Failed to setup basic linker")?;
+        Self::setup_linker_indexer(context.clone(), &mut linker, options.state_proofs)
+            .context("Failed to setup indexer linker")?;
+        // Host functions reached through a `view_pre` act on the context each
+        // view attaches to its store; this one only stands in for it
+        let placeholder = Arc::new(Mutex::new(context.lock().map_err(lock_err)?.clone()));
+        let cache_dir = options.module_cache.as_deref();
+        let mut schedule = vec![];
+        for upgrade in upgrades {
+            let wasm = std::fs::read(&upgrade.indexer)
+                .with_context(|| format!("Failed to read WASM module {}", upgrade.indexer.display()))?;
+            let module = load_module(&engine, &wasm, cache_dir).context("Failed to load WASM module")?;
+            let async_module = load_module(&async_engine, &wasm, cache_dir).context("Failed to load WASM module")?;
             linker.define_unknown_imports_as_traps(&module)?;
+            let mut view_linker = Linker::<State>::new(&async_engine);
+            Self::setup_linker(placeholder.clone(), &mut view_linker)
+                .context("Failed to setup basic linker for views")?;
+            Self::setup_linker_view(placeholder.clone(), &mut view_linker)
+                .context("Failed to setup view linker")?;
+            view_linker.define_unknown_imports_as_traps(&async_module)?;
+            schedule.push(IndexerModule {
+                activation_height: upgrade.height,
+                hash: sha256::Hash::hash(&wasm).to_byte_array(),
+                view_pre: view_linker.instantiate_pre(&async_module)?,
+                module,
+                async_module,
+            });
         }
+        let module = schedule[0].module.clone();
+        let async_module = schedule[0].async_module.clone();
         let instance = linker.instantiate(&mut wasmstore, &module)
             .context("Failed to instantiate WASM module")?;
         Ok(MetashrewRuntime {
@@ -256,146 +1172,72 @@ where
             linker,
             context,
             instance,
+            memory_refreshes: 0,
+            options,
+            schedule,
+            active: None,
+            epoch_ticker,
         })
     }
 
-    pub fn preview(
+    pub async fn preview_async(
         &self,
-        block: &Vec<u8>,
+        block: &[u8],
         symbol: String,
-        input: &Vec<u8>,
+        input: &[u8],
         height: u32,
-    ) -> Result<Vec<u8>> {
-        // Create preview context with wrapped DB
-        let preview_db = {
-            let guard = self.context.lock().map_err(lock_err)?;
-            PreviewDBWrapper {
-                underlying_db: guard.db.clone(),
-                overlay: std::collections::HashMap::new(),
-            }
-        };
-
-        // Create a new runtime with preview db
-        let mut runtime = Self::new_with_db(preview_db, height, self.async_engine.clone(), self.async_module.clone())?;
-        runtime.context.lock().map_err(lock_err)?.block = block.clone();
-
-        // Execute block via _start to populate preview db
-        let start = runtime.instance.get_typed_func::<(), ()>(&mut runtime.wasmstore, "_start")
-            .context("Failed to get _start function for preview")?;
-            
-        match start.call(&mut runtime.wasmstore, ()) {
-            Ok(_) => {
-                let context_guard = runtime.context.lock().map_err(lock_err)?;
-                if context_guard.state != 1 && !runtime.wasmstore.data().had_failure {
-                    return Err(anyhow!("indexer exited unexpectedly during preview"));
-                }
-            }
-            Err(e) => return Err(e).context("Error executing _start in preview"),
-        }
+    ) -> Result<Vec<u8>, ViewError> {
+        self.view_handle()
+            .map_err(ViewError::internal)?
+            .preview_async(block, symbol, input, height)
+            .await
+    }
 
-        // Create new runtime just for the view using the same wrapped DB
-        let mut view_runtime = {
-            let context = runtime.context.lock().map_err(lock_err)?;
-            Self::new_with_db(context.db.clone(), height, self.async_engine.clone(), self.async_module.clone())?
-        };
-        
-        // Set block to input for view
-        view_runtime.context.lock().map_err(lock_err)?.block = input.clone();
-        
-        // Execute view function
-        let func = view_runtime.instance
-            .get_typed_func::<(), i32>(&mut view_runtime.wasmstore, symbol.as_str())
-            .context("Failed to get view function")?;
-        
-        let result = func.call(&mut view_runtime.wasmstore, ())
-            .context("Failed to execute view function")?;
-        
-        let memory = view_runtime.instance
-            .get_memory(&mut view_runtime.wasmstore, "memory")
-            .ok_or_else(|| anyhow!("Failed to get memory for view result"))?;
-        
-        // Get the final result
-        Ok(read_arraybuffer_as_vec(
-            memory.data(&mut view_runtime.wasmstore),
-            result,
-        ))
+    pub async fn view(&self, symbol: String, input: &[u8], height: u32) -> Result<Vec<u8>, ViewError> {
+        self.view_handle()
+            .map_err(ViewError::internal)?
+            .view(symbol, input, height)
+            .await
     }
-    
-    // Async version of preview for use with the view server
-    pub async fn preview_async(
+
+    /// Runs a view like [`view`](Self::view) and also returns the `__log`
+    /// output it produced, up to [`MAX_CALL_LOG_BYTES`].
+    pub async fn view_with_log(
         &self,
-        block: &Vec<u8>,
         symbol: String,
-        input: &Vec<u8>,
+        input: &[u8],
         height: u32,
-    ) -> Result<Vec<u8>> {
-        // For now, just use the synchronous version
-        // In the future, we can implement a fully async version if needed
-        self.preview(block, symbol, input, height)
-    }
-    
-    pub async fn view(&self, symbol: String, input: &Vec<u8>, height: u32) -> Result<Vec<u8>> {
-        let mut linker = Linker::<State>::new(&self.async_engine);
-        let mut wasmstore = Store::<State>::new(&self.async_engine, State::new());
-        
-        let context = {
-            let guard = self.context.lock().map_err(lock_err)?;
-            Arc::<Mutex<MetashrewRuntimeContext<T>>>::new(Mutex::new(guard.clone()))
-        };
-        
-        {
-            let mut guard = context.lock().map_err(lock_err)?;
-            guard.height = height;
-            guard.block = input.clone();
-        }
-        
-        // Set fuel for cooperative yielding
-        wasmstore.set_fuel(u64::MAX)?;
-        wasmstore.fuel_async_yield_interval(Some(10000))?;
-        
-        {
-            wasmstore.limiter(|state| &mut state.limits)
-        }
-        
-        {
-            Self::setup_linker(context.clone(), &mut linker)
-                .context("Failed to setup basic linker for view")?;
-            Self::setup_linker_view(context.clone(), &mut linker)
-                .context("Failed to setup view linker")?;
-            linker.define_unknown_imports_as_traps(&self.module)?;
-        }
-        
-        // Use async instantiation
-        let instance = linker.instantiate_async(&mut wasmstore, &self.async_module)
-            .await
-            .context("Failed to instantiate module for view")?;
-            
-        let func = instance
-            .get_typed_func::<(), i32>(&mut wasmstore, symbol.as_str())
-            .with_context(|| format!("Failed to get view function '{}'", symbol))?;
-            
-        // Use async call
-        let result = func.call_async(&mut wasmstore, ())
+    ) -> Result<(Vec<u8>, Vec<String>), ViewError> {
+        self.view_handle()
+            .map_err(ViewError::internal)?
+            .view_with_log(symbol, input, height)
             .await
-            .with_context(|| format!("Failed to execute view function '{}'", symbol))?;
-            
-        let memory = instance
-            .get_memory(&mut wasmstore, "memory")
-            .ok_or_else(|| anyhow!("Failed to get memory for view result"))?;
-            
-        Ok(read_arraybuffer_as_vec(
-            memory.data(&mut wasmstore),
-            result,
-        ))
     }
+
+    /// A handle that runs views and previews over this runtime's database
+    /// and compiled module, without borrowing the runtime itself.
+    pub fn view_handle(&self) -> Result<ViewHandle<T>> {
+        Ok(ViewHandle {
+            db: self.context.lock().map_err(lock_err)?.db.clone(),
+            engine: self.async_engine.clone(),
+            schedule: self.schedule.clone(),
+            options: self.options.clone(),
+            epoch_ticker: self.epoch_ticker.clone(),
+        })
+    }
+
     pub fn refresh_memory(&mut self) -> Result<()> {
         let mut wasmstore = Store::<State>::new(&self.engine, State::new());
+        wasmstore.data_mut().limits.max_memory = self.options.indexer_max_memory;
         wasmstore.limiter(|state| &mut state.limits);
         self.instance = self
             .linker
             .instantiate(&mut wasmstore, &self.module)
             .context("Failed to instantiate module during memory refresh")?;
+        // An indexer hash not yet committed still belongs to the next block
+        wasmstore.data_mut().wasm_hash = self.wasmstore.data().wasm_hash;
         self.wasmstore = wasmstore;
+        self.memory_refreshes += 1;
         Ok(())
     }
 
@@ -408,18 +1250,44 @@ where
         Ok(())
     }
     pub fn run(&mut self) -> Result<(), anyhow::Error> {
-        self.context.lock().map_err(lock_err)?.state = 0;
+        let height = {
+            let mut guard = self.context.lock().map_err(lock_err)?;
+            guard.state = 0;
+            guard.height
+        };
+        self.activate(height)?;
+        self.wasmstore.data_mut().label = format!("block {}", height);
+        self.wasmstore.data_mut().scans.clear();
+        self.wasmstore.data_mut().flushed = 0;
         let start = self
             .instance
             .get_typed_func::<(), ()>(&mut self.wasmstore, "_start")
             .context("Failed to get _start function")?;
         
         self.handle_reorg()?;
+        let previous = {
+            let mut guard = self.context.lock().map_err(lock_err)?;
+            previous_state_root(&mut guard.db, height)?
+        };
+        self.wasmstore.data_mut().root = Some(previous);
         
         match start.call(&mut self.wasmstore, ()) {
             Ok(_) => {
-                if self.context.lock().map_err(lock_err)?.state != 1 && !self.wasmstore.data().had_failure {
-                    return Err(anyhow!("indexer exited unexpectedly"));
+                let mut guard = self.context.lock().map_err(lock_err)?;
+                if guard.state != 1 {
+                    if !self.wasmstore.data().had_failure {
+                        return Err(anyhow!("indexer exited unexpectedly"));
+                    }
+                    // Nothing was flushed, so the block keeps the root before it
+                    let mut batch = T::Batch::default();
+                    batch.put(state_root_key(height), previous);
+                    if let Some((start, hash)) = self.wasmstore.data_mut().wasm_hash.take() {
+                        batch.put(wasm_hash_key(start), hash);
+                    }
+                    guard
+                        .db
+                        .write(batch)
+                        .map_err(|e| anyhow!("Failed to write state root: {:?}", e))?;
                 }
                 Ok(())
             }
@@ -427,6 +1295,70 @@ where
         }
     }
 
+    /// Checks this runtime's indexers against the hashes recorded in its
+    /// database, as [`verify_wasm`] does.
+    pub fn verify_wasm(&self, allow_mismatch: bool) -> Result<()> {
+        let expected = self
+            .schedule
+            .iter()
+            .map(|indexer| (indexer.activation_height, indexer.hash))
+            .collect::<Vec<_>>();
+        let mut db = self.context.lock().map_err(lock_err)?.db.clone();
+        verify_wasm(&mut db, &expected, allow_mismatch)
+    }
+
+    /// Switches to the indexer scheduled for `height` when another one is
+    /// running, and has the block's batch record its hash unless the range
+    /// covering `height` already does. Blocks indexed with other WASM, as
+    /// `--allow-wasm-mismatch` permits, keep their record and a new range
+    /// starts at `height`.
+    fn activate(&mut self, height: u32) -> Result<()> {
+        let index = scheduled_at(&self.schedule, height);
+        if self.active == Some(index) {
+            return Ok(());
+        }
+        let indexer = match self.schedule.get(index) {
+            Some(indexer) => indexer.clone(),
+            None => return Ok(()),
+        };
+        // `load_with_options` instantiates the first indexer
+        if index != self.active.unwrap_or(0) {
+            info!(
+                "switching to the indexer activated at block {} for block {}",
+                indexer.activation_height, height
+            );
+            let mut wasmstore = Store::<State>::new(&self.engine, State::new());
+            wasmstore.data_mut().limits.max_memory = self.options.indexer_max_memory;
+            wasmstore.limiter(|state| &mut state.limits);
+            self.instance = self
+                .linker
+                .instantiate(&mut wasmstore, &indexer.module)
+                .context("Failed to instantiate upgraded WASM module")?;
+            self.wasmstore = wasmstore;
+            self.module = indexer.module;
+            self.async_module = indexer.async_module;
+        }
+        let covering = {
+            let mut guard = self.context.lock().map_err(lock_err)?;
+            recorded_wasm(&mut guard.db)?
+                .into_iter()
+                .take_while(|(start, _)| *start <= height)
+                .last()
+        };
+        self.wasmstore.data_mut().wasm_hash = match covering {
+            Some((_, hash)) if hash == indexer.hash => None,
+            Some(_) => Some((height, indexer.hash)),
+            None => Some((indexer.activation_height, indexer.hash)),
+        };
+        self.active = Some(index);
+        Ok(())
+    }
+
+    /// Number of keys the last block wrote in `__flush`.
+    pub fn flushed_keys(&self) -> u32 {
+        self.wasmstore.data().flushed
+    }
+
     pub fn check_latest_block_for_reorg(
         context: Arc<Mutex<MetashrewRuntimeContext<T>>>,
         height: u32,
@@ -475,7 +1407,8 @@ where
     ) -> Result<HashSet<Vec<u8>>> {
         let key = u32_to_vec(height)?;
         let updated_key = db_make_updated_key(&key);
-        let length = Self::db_length_at_key(context.clone(), &updated_key)? as i32;
+        let length_key = db_make_length_key(&updated_key)?;
+        let length = Self::db_length_at_key(context.clone(), &length_key)? as i32;
         let mut i: i32 = 0;
         let mut set: HashSet<Vec<u8>> = HashSet::<Vec<u8>>::new();
         
@@ -494,46 +1427,163 @@ where
         }
         Ok(set)
     }
+    fn db_list_entry(
+        context: Arc<Mutex<MetashrewRuntimeContext<T>>>,
+        key: &Vec<u8>,
+        index: u32,
+    ) -> Result<Vec<u8>> {
+        let list_key = db_make_list_key(key, index)?;
+        match context
+            .lock()
+            .map_err(lock_err)?
+            .db
+            .get(&list_key)
+            .map_err(|e| anyhow!("Database error: {:?}", e))?
+        {
+            Some(v) => Ok(v),
+            None => db_make_list_key(&Vec::<u8>::new(), 0),
+        }
+    }
+
+    /// Looks up the value of `key` as of `height`.
+    ///
+    /// A key's append list is written in block order and truncated on
+    /// rollback, so entry heights are non-decreasing and the list can be
+    /// binary searched for the last entry at or below `height`.
     pub fn db_value_at_block(
         context: Arc<Mutex<MetashrewRuntimeContext<T>>>,
         key: &Vec<u8>,
         height: u32,
     ) -> Result<Vec<u8>> {
+        Ok(Self::db_entry_at_block(context, key, height)?
+            .map(|(_, value)| value)
+            .unwrap_or_default())
+    }
+
+    /// The value of `key` at `height` with the height it was written at, or
+    /// `None` when the key had not been written by then.
+    pub fn db_entry_at_block(
+        context: Arc<Mutex<MetashrewRuntimeContext<T>>>,
+        key: &Vec<u8>,
+        height: u32,
+    ) -> Result<Option<(u32, Vec<u8>)>> {
         let length_key = db_make_length_key(key)?;
         let length = Self::db_length_at_key(context.clone(), &length_key)?;
-        let mut index = length as i32 - 1;
+        if length == 0 {
+            return Ok(None);
+        }
 
-        while index >= 0 {
-            let list_key = db_make_list_key(key, index as u32)?;
-            let value = match context
-                .lock()
-                .map_err(lock_err)?
-                .db
-                .get(&list_key)
-                .map_err(|e| anyhow!("Database error: {:?}", e))? {
-                    Some(v) => v,
-                    None => db_make_list_key(&Vec::<u8>::new(), 0)?,
-                };
+        // Most lookups are made at the tip, so try the latest entry first
+        let latest = Self::db_list_entry(context.clone(), key, length - 1)?;
+        let latest_height = db_entry_height(&latest)?;
+        if height >= latest_height {
+            return Ok(Some((latest_height, db_entry_value(latest))));
+        }
 
-            if value.len() < 4 {
-                return Err(anyhow!("Invalid value length: {}", value.len()));
+        let mut low: u32 = 0;
+        let mut high: u32 = length - 1;
+        let mut found: Option<Vec<u8>> = None;
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let value = Self::db_list_entry(context.clone(), key, mid)?;
+            if height >= db_entry_height(&value)? {
+                found = Some(value);
+                low = mid + 1;
+            } else {
+                high = mid;
             }
+        }
+        match found {
+            Some(entry) => Ok(Some((db_entry_height(&entry)?, db_entry_value(entry)))),
+            None => Ok(None),
+        }
+    }
 
-            let value_height = {
-                let bytes: [u8; 4] = value.as_slice()[(value.len() - 4)..]
-                    .try_into()
-                    .map_err(|e| anyhow!("Invalid value height bytes: {:?}", e))?;
-                u32::from_le_bytes(bytes)
-            };
+    /// Every value written to `key`, oldest first, with the height it was
+    /// written at.
+    pub fn db_key_history(
+        context: Arc<Mutex<MetashrewRuntimeContext<T>>>,
+        key: &Vec<u8>,
+    ) -> Result<Vec<(u32, Vec<u8>)>> {
+        let length = Self::db_length_at_key(context.clone(), &db_make_length_key(key)?)?;
+        (0..length)
+            .map(|index| {
+                let entry = Self::db_list_entry(context.clone(), key, index)?;
+                Ok((db_entry_height(&entry)?, db_entry_value(entry)))
+            })
+            .collect()
+    }
+
+    /// Lists the logical keys stored under `prefix` whose lists begin among
+    /// at most `limit` raw entries from `start` on, along with the raw key
+    /// the next page starts at (`None` once the prefix is exhausted). A key
+    /// is found at its first entry, which sorts where the key itself does,
+    /// so keys come out in key order. Keys are returned regardless of the
+    /// height they were first written at.
+    pub fn db_keys_with_prefix(
+        context: Arc<Mutex<MetashrewRuntimeContext<T>>>,
+        prefix: &Vec<u8>,
+        start: &Vec<u8>,
+        limit: usize,
+    ) -> Result<KeyPage> {
+        let first_suffix = u32_to_vec(0)?;
+        let entries = context
+            .lock()
+            .map_err(lock_err)?
+            .db
+            .scan_prefix_from(prefix, start, limit)
+            .map_err(|e| anyhow!("Database error: {:?}", e))?;
+        let next = match entries.last() {
+            Some((k, _)) if entries.len() >= limit => Some([k.as_slice(), &[0]].concat()),
+            _ => None,
+        };
+        let mut keys = vec![];
+        for (k, _) in entries {
+            if k.len() < prefix.len() + 4 || !k.ends_with(&first_suffix) {
+                continue;
+            }
+            let key = k[..k.len() - 4].to_vec();
+            if Self::db_length_at_key(context.clone(), &db_make_length_key(&key)?)? > 0 {
+                keys.push(key);
+            }
+        }
+        Ok((keys, next))
+    }
 
-            if height >= value_height {
-                let mut result = value.clone();
-                result.truncate(value.len().saturating_sub(4));
-                return Ok(result);
+    /// Encodes a scanned entry as `key_len (u32 LE) || key || value`.
+    pub fn db_encode_scan_entry(key: &Vec<u8>, value: &Vec<u8>) -> Result<Vec<u8>> {
+        let mut entry = u32_to_vec(key.len() as u32)?;
+        entry.extend(key);
+        entry.extend(value);
+        Ok(entry)
+    }
+
+    fn db_scan_fill_pending(
+        context: Arc<Mutex<MetashrewRuntimeContext<T>>>,
+        cursor: &mut ScanCursor,
+        height: u32,
+    ) -> Result<()> {
+        while cursor.pending.is_none() {
+            let key = match cursor.keys.pop_front() {
+                Some(k) => k,
+                None => match cursor.next.take() {
+                    Some(start) => {
+                        let (keys, next) =
+                            Self::db_keys_with_prefix(context.clone(), &cursor.prefix, &start, SCAN_PAGE_SIZE)?;
+                        cursor.keys = keys.into();
+                        cursor.next = next;
+                        continue;
+                    }
+                    None => return Ok(()),
+                },
+            };
+            let value = Self::db_value_at_block(context.clone(), &key, height)?;
+            // Keys with no value as of this height are not visible yet
+            if !value.is_empty() {
+                cursor.pending = Some(Self::db_encode_scan_entry(&key, &value)?);
             }
-            index -= 1;
         }
-        Ok(vec![])
+        Ok(())
     }
 
     pub fn db_updated_keys_for_block_range(
@@ -552,10 +1602,12 @@ where
 
     pub fn db_rollback_key(
         context: Arc<Mutex<MetashrewRuntimeContext<T>>>,
+        batch: &mut T::Batch,
         key: &Vec<u8>,
         to_block: u32,
     ) -> Result<()> {
-        let length = Self::db_length_at_key(context.clone(), key)?;
+        let length_key = db_make_length_key(key)?;
+        let length = Self::db_length_at_key(context.clone(), &length_key)?;
         let mut index = length as i32 - 1;
         let mut end_length = length as i32;
         
@@ -570,24 +1622,10 @@ where
 
             match db_value {
                 Some(value) => {
-                    if value.len() < 4 {
-                        return Err(anyhow!("Invalid value length: {}", value.len()));
-                    }
-                    
-                    let value_height = {
-                        let bytes: [u8; 4] = value.as_slice()[(value.len() - 4)..]
-                            .try_into()
-                            .map_err(|e| anyhow!("Invalid value height bytes: {:?}", e))?;
-                        u32::from_le_bytes(bytes)
-                    };
-                    
+                    let value_height = db_entry_height(&value)?;
+
                     if to_block <= value_height {
-                        context
-                            .lock()
-                            .map_err(lock_err)?
-                            .db
-                            .delete(&list_key)
-                            .map_err(|e| anyhow!("Database delete error: {:?}", e))?;
+                        batch.delete(&list_key);
                         end_length -= 1;
                     } else {
                         break;
@@ -599,34 +1637,42 @@ where
         }
         
         if end_length != length as i32 {
-            Self::db_set_length(context.clone(), key, end_length as u32)?;
+            Self::db_set_length(batch, key, end_length as u32)?;
         }
         
         Ok(())
     }
 
-    pub fn db_set_length(
-        context: Arc<Mutex<MetashrewRuntimeContext<T>>>,
-        key: &Vec<u8>,
-        length: u32,
-    ) -> Result<()> {
+    pub fn db_set_length(batch: &mut T::Batch, key: &Vec<u8>, length: u32) -> Result<()> {
         let length_key = db_make_length_key(key)?;
-        let mut guard = context.lock().map_err(lock_err)?;
         
         if length == 0 {
-            guard.db.delete(&length_key)
-                .map_err(|e| anyhow!("Failed to delete length key: {:?}", e))?;
+            batch.delete(&length_key);
             return Ok(());
         }
         
-        let new_length_bits = u32_to_vec(length + 1)?;
-        guard.db
-            .put(&length_key, &new_length_bits)
-            .map_err(|e| anyhow!("Failed to update length: {:?}", e))?;
+        let new_length_bits = u32_to_vec(length)?;
+        batch.put(&length_key, &new_length_bits);
             
         Ok(())
     }
 
+    /// Removes the list of keys updated at `height`, including its length key.
+    pub fn db_delete_update_list(
+        context: Arc<Mutex<MetashrewRuntimeContext<T>>>,
+        batch: &mut T::Batch,
+        height: u32,
+    ) -> Result<()> {
+        let updated_key = db_make_updated_key(&u32_to_vec(height)?);
+        let length_key = db_make_length_key(&updated_key)?;
+        let length = Self::db_length_at_key(context.clone(), &length_key)?;
+        for i in 0..length {
+            batch.delete(db_make_list_key(&updated_key, i)?);
+        }
+        batch.delete(&length_key);
+        Ok(())
+    }
+
     pub fn handle_reorg(&mut self) -> Result<()> {
         let context = self.context.clone();
         let height = { context.lock().map_err(lock_err)?.height };
@@ -636,16 +1682,57 @@ where
             return Ok(());
         }
         
-        let set = Self::db_updated_keys_for_block_range(context.clone(), height, latest)?;
+        let mut batch = T::Batch::default();
+        let set = Self::db_rollback_blocks(context.clone(), &mut batch, height, latest)?;
         if !set.is_empty() {
             self.refresh_memory()?;
         }
         
+        // Commit with the tip still on this block, so that a crash before its
+        // own flush reprocesses it against the rolled back state
+        let mut guard = context.lock().map_err(lock_err)?;
+        guard.db.set_height(height.wrapping_sub(1));
+        let result = guard.db.write(batch);
+        guard.db.set_height(height);
+        result.map_err(|e| anyhow!("Failed to write rollback batch: {:?}", e))?;
+        
+        Ok(())
+    }
+
+    /// Accumulates into `batch` the rollback of every key updated in blocks
+    /// `from..=to` to its state before `from`, along with the deletion of those
+    /// blocks' update lists and state roots. Returns the keys that were rolled
+    /// back.
+    pub fn db_rollback_blocks(
+        context: Arc<Mutex<MetashrewRuntimeContext<T>>>,
+        batch: &mut T::Batch,
+        from: u32,
+        to: u32,
+    ) -> Result<HashSet<Vec<u8>>> {
+        let set = Self::db_updated_keys_for_block_range(context.clone(), from, to)?;
         for key in &set {
-            Self::db_rollback_key(context.clone(), key, height)?;
+            Self::db_rollback_key(context.clone(), batch, key, from)?;
+        }
+        for height in from..=to {
+            Self::db_delete_update_list(context.clone(), batch, height)?;
+            batch.delete(state_root_key(height));
         }
-        
-        Ok(())
+        Ok(set)
+    }
+
+    /// The context a host function acts on: the one attached to the
+    /// caller's store when the instance came from the shared view
+    /// `InstancePre`, otherwise the one the linker was built with.
+    fn call_context(
+        caller: &Caller<'_, State>,
+        fallback: &Arc<Mutex<MetashrewRuntimeContext<T>>>,
+    ) -> Arc<Mutex<MetashrewRuntimeContext<T>>> {
+        caller
+            .data()
+            .call_context
+            .clone()
+            .and_then(|context| context.downcast::<Mutex<MetashrewRuntimeContext<T>>>().ok())
+            .unwrap_or_else(|| fallback.clone())
     }
 
     pub fn setup_linker(
@@ -660,7 +1747,7 @@ where
                 "env",
                 "__host_len",
                 move |mut _caller: Caller<'_, State>| -> i32 {
-                    match context_ref_len.lock() {
+                    match Self::call_context(&_caller, &context_ref_len).lock() {
                         Ok(ctx) => ctx.block.len() as i32 + 4,
                         Err(_) => i32::MAX, // Signal error
                     }
@@ -687,7 +1774,7 @@ where
                         }
                     };
 
-                    let (input, height) = match context_ref_input.lock() {
+                    let (input, height) = match Self::call_context(&caller, &context_ref_input).lock() {
                         Ok(ctx) => (ctx.block.clone(), ctx.height),
                         Err(_) => {
                             caller.data_mut().had_failure = true;
@@ -739,7 +1826,11 @@ where
                     };
 
                     if let Ok(text) = std::str::from_utf8(&bytes) {
-                        print!("{}", text);
+                        let state = caller.data_mut();
+                        info!(target: "metashrew::guest", "[{}] {}", state.label, text.trim_end_matches('\n'));
+                        if let Some(log) = state.log.as_mut() {
+                            log.push(text);
+                        }
                     }
                 },
             )
@@ -757,6 +1848,136 @@ where
 
         Ok(())
     }
+    pub fn setup_linker_scan(
+        context: Arc<Mutex<MetashrewRuntimeContext<T>>>,
+        linker: &mut Linker<State>,
+    ) -> Result<()> {
+        let context_scan = context.clone();
+        let context_next_len = context.clone();
+        let context_next = context.clone();
+
+        linker
+            .func_wrap(
+                "env",
+                "__scan_prefix",
+                move |mut caller: Caller<'_, State>, prefix: i32| -> i32 {
+                    let mem = match caller.get_export("memory") {
+                        Some(export) => match export.into_memory() {
+                            Some(memory) => memory,
+                            None => return i32::MAX,
+                        },
+                        None => return i32::MAX,
+                    };
+
+                    let data = mem.data(&caller);
+                    let prefix_vec = match try_read_arraybuffer_as_vec(data, prefix) {
+                        Ok(v) => v,
+                        Err(_) => return i32::MAX,
+                    };
+
+                    let context = Self::call_context(&caller, &context_scan);
+                    let height = match context.lock() {
+                        Ok(ctx) => ctx.height,
+                        Err(_) => return i32::MAX,
+                    };
+
+                    debug!("scanning keys under prefix {} at height {}", hex::encode(&prefix_vec), height);
+                    let scans = &mut caller.data_mut().scans;
+                    scans.push(ScanCursor {
+                        prefix: prefix_vec.clone(),
+                        next: Some(prefix_vec),
+                        ..Default::default()
+                    });
+                    let handle = scans.len() - 1;
+                    to_signed_or_trap(&mut caller, handle)
+                },
+            )
+            .map_err(|e| anyhow!("Failed to wrap __scan_prefix: {:?}", e))?;
+
+        linker
+            .func_wrap(
+                "env",
+                "__iter_next_len",
+                move |mut caller: Caller<'_, State>, handle: i32| -> i32 {
+                    let context = Self::call_context(&caller, &context_next_len);
+                    let height = match context.lock() {
+                        Ok(ctx) => ctx.height,
+                        Err(_) => return i32::MAX,
+                    };
+
+                    let cursor = match caller.data_mut().scans.get_mut(handle as usize) {
+                        Some(cursor) => cursor,
+                        None => return i32::MAX,
+                    };
+
+                    if Self::db_scan_fill_pending(context, cursor, height).is_err() {
+                        caller.data_mut().had_failure = true;
+                        return i32::MAX;
+                    }
+
+                    // An encoded entry is never shorter than its key length header,
+                    // so 0 unambiguously signals the end of the scan
+                    let len = cursor.pending.as_ref().map(|v| v.len()).unwrap_or(0);
+                    to_signed_or_trap(&mut caller, len)
+                },
+            )
+            .map_err(|e| anyhow!("Failed to wrap __iter_next_len: {:?}", e))?;
+
+        linker
+            .func_wrap(
+                "env",
+                "__iter_next",
+                move |mut caller: Caller<'_, State>, handle: i32, value: i32| {
+                    let mem = match caller.get_export("memory") {
+                        Some(export) => match export.into_memory() {
+                            Some(memory) => memory,
+                            None => {
+                                caller.data_mut().had_failure = true;
+                                return;
+                            }
+                        },
+                        None => {
+                            caller.data_mut().had_failure = true;
+                            return;
+                        }
+                    };
+
+                    let context = Self::call_context(&caller, &context_next);
+                    let height = match context.lock() {
+                        Ok(ctx) => ctx.height,
+                        Err(_) => {
+                            caller.data_mut().had_failure = true;
+                            return;
+                        }
+                    };
+
+                    let entry = match caller.data_mut().scans.get_mut(handle as usize) {
+                        Some(cursor) => {
+                            match Self::db_scan_fill_pending(context, cursor, height) {
+                                Ok(_) => cursor.pending.take(),
+                                Err(_) => {
+                                    caller.data_mut().had_failure = true;
+                                    return;
+                                }
+                            }
+                        }
+                        None => {
+                            caller.data_mut().had_failure = true;
+                            return;
+                        }
+                    };
+
+                    if let Some(entry) = entry {
+                        if mem.write(&mut caller, value as usize, entry.as_slice()).is_err() {
+                            caller.data_mut().had_failure = true;
+                        }
+                    }
+                },
+            )
+            .map_err(|e| anyhow!("Failed to wrap __iter_next: {:?}", e))?;
+
+        Ok(())
+    }
     pub fn db_append_annotated(
         context: Arc<Mutex<MetashrewRuntimeContext<T>>>,
         batch: &mut T::Batch,
@@ -776,6 +1997,30 @@ where
         
         Ok(())
     }
+    /// Accumulates into `batch` the state tree nodes changed by writing
+    /// `pairs` at `height`. The nodes are appended to the block's update
+    /// list after its first `listed` entries, so a rollback truncates them
+    /// with the keys they commit to; returns the new length of the list.
+    fn db_update_state_tree<'a>(
+        context: Arc<Mutex<MetashrewRuntimeContext<T>>>,
+        batch: &mut T::Batch,
+        update_key: &Vec<u8>,
+        mut listed: u32,
+        pairs: impl Iterator<Item = (&'a Vec<u8>, &'a Vec<u8>)>,
+        height: u32,
+    ) -> Result<u32> {
+        let mut tree = TreeUpdate::new(|key: &Vec<u8>| Self::db_value_at_block(context.clone(), key, height));
+        for (key, value) in pairs {
+            tree.insert(key, value)?;
+        }
+        for (key, node) in tree.into_changes() {
+            Self::db_append_annotated(context.clone(), batch, &key, &node, height)?;
+            batch.put(&db_make_list_key(update_key, listed)?, &key);
+            listed += 1;
+        }
+        Ok(listed)
+    }
+
     pub fn db_append(
         context: Arc<Mutex<MetashrewRuntimeContext<T>>>,
         batch: &mut T::Batch,
@@ -827,8 +2072,9 @@ where
                         }
                     };
 
+                    let context = Self::call_context(&caller, &context_get);
                     let data = mem.data(&caller);
-                    let height = match context_get.clone().lock() {
+                    let height = match context.lock() {
                         Ok(ctx) => ctx.height,
                         Err(_) => {
                             caller.data_mut().had_failure = true;
@@ -838,7 +2084,7 @@ where
 
                     match try_read_arraybuffer_as_vec(data, key) {
                         Ok(key_vec) => {
-                            match Self::db_value_at_block(context_get.clone(), &key_vec, height) {
+                            match Self::db_value_at_block(context, &key_vec, height) {
                                 Ok(lookup) => {
                                     if let Err(_) = mem.write(&mut caller, value as usize, lookup.as_slice()) {
                                         caller.data_mut().had_failure = true;
@@ -880,15 +2126,16 @@ where
                         None => return i32::MAX,
                     };
 
+                    let context = Self::call_context(&caller, &context_get_len);
                     let data = mem.data(&caller);
-                    let height = match context_get_len.clone().lock() {
+                    let height = match context.lock() {
                         Ok(ctx) => ctx.height,
                         Err(_) => return i32::MAX,
                     };
 
                     match try_read_arraybuffer_as_vec(data, key) {
                         Ok(key_vec) => {
-                            match Self::db_value_at_block(context_get_len.clone(), &key_vec, height) {
+                            match Self::db_value_at_block(context, &key_vec, height) {
                                 Ok(value) => value.len() as i32,
                                 Err(_) => i32::MAX,
                             }
@@ -899,16 +2146,22 @@ where
             )
             .map_err(|e| anyhow!("Failed to wrap __get_len: {:?}", e))?;
 
+        Self::setup_linker_scan(context.clone(), linker)?;
+
         Ok(())
     }
-    fn new_with_db<U: KeyValueStoreLike + Clone + Sync + Send + 'static>(
+    async fn new_with_db<U: KeyValueStoreLike + Clone + Sync + Send + 'static>(
         db: U,
         height: u32,
-        engine: wasmtime::Engine,
-        module: wasmtime::Module,
+        engine: &wasmtime::Engine,
+        module: &wasmtime::Module,
+        limits: &CallLimits,
+        epochs: bool,
+        label: String,
     ) -> Result<MetashrewRuntime<U>> {
-        let mut linker = Linker::<State>::new(&engine);
-        let mut wasmstore = Store::<State>::new(&engine, State::new());
+        let mut linker = Linker::<State>::new(engine);
+        let mut wasmstore = Store::<State>::new(engine, State::for_call(label, false));
+        limits.apply(&mut wasmstore, epochs)?;
         let context = Arc::<Mutex<MetashrewRuntimeContext<U>>>::new(Mutex::<
             MetashrewRuntimeContext<U>,
         >::new(
@@ -922,19 +2175,26 @@ where
                 .context("Failed to setup basic linker")?;
             MetashrewRuntime::<U>::setup_linker_preview(context.clone(), &mut linker)
                 .context("Failed to setup preview linker")?;
-            linker.define_unknown_imports_as_traps(&module)?;
+            linker.define_unknown_imports_as_traps(module)?;
         }
-        let instance = linker.instantiate(&mut wasmstore, &module)
+        let instance = linker.instantiate_async(&mut wasmstore, module)
+            .await
             .context("Failed to instantiate WASM module")?;
         Ok(MetashrewRuntime {
             wasmstore,
             engine: engine.clone(),
-            async_engine: engine,
+            async_engine: engine.clone(),
             module: module.clone(),
             async_module: module.clone(),
             linker,
             context,
             instance,
+            memory_refreshes: 0,
+            options: RuntimeOptions::default(),
+            schedule: vec![],
+            active: None,
+            // Previews run on their view handle's engine, which it keeps ticking
+            epoch_ticker: None,
         })
     }
 
@@ -1114,12 +2374,15 @@ where
             )
             .map_err(|e| anyhow!("Failed to wrap __get_len: {:?}", e))?;
 
+        Self::setup_linker_scan(context.clone(), linker)?;
+
         Ok(())
     }
 
     pub fn setup_linker_indexer(
         context: Arc<Mutex<MetashrewRuntimeContext<T>>>,
         linker: &mut Linker<State>,
+        state_proofs: bool,
     ) -> Result<()> {
         let context_ref = context.clone();
         let context_get = context.clone();
@@ -1162,10 +2425,6 @@ where
                     };
 
                     let mut batch = T::Batch::default();
-                    if let Err(_) = Self::db_create_empty_update_list(&mut batch, height as u32) {
-                        caller.data_mut().had_failure = true;
-                        return;
-                    }
 
                     let decoded = match KeyValueFlush::parse_from_bytes(&encoded_vec) {
                         Ok(d) => d,
@@ -1175,6 +2434,28 @@ where
                         }
                     };
 
+                    let update_key = match u32_to_vec(height) {
+                        Ok(v) => db_make_updated_key(&v),
+                        Err(_) => {
+                            caller.data_mut().had_failure = true;
+                            return;
+                        }
+                    };
+                    // A block may flush more than once, so carry on from the
+                    // entries earlier flushes added to its update list
+                    let mut updated = match db_make_length_key(&update_key)
+                        .and_then(|length_key| Self::db_length_at_key(context_ref.clone(), &length_key))
+                    {
+                        Ok(length) => length,
+                        Err(_) => {
+                            caller.data_mut().had_failure = true;
+                            return;
+                        }
+                    };
+                    if updated == 0 && Self::db_create_empty_update_list(&mut batch, height).is_err() {
+                        caller.data_mut().had_failure = true;
+                        return;
+                    }
                     for (k, v) in decoded.list.iter().tuples() {
                         let k_owned = <Vec<u8> as Clone>::clone(k);
                         let v_owned = <Vec<u8> as Clone>::clone(v);
@@ -1190,18 +2471,38 @@ where
                             return;
                         }
 
-                        match u32_to_vec(height) {
-                            Ok(update_key) => {
-                                if let Err(_) = Self::db_append(context_ref.clone(), &mut batch, &update_key, &k_owned) {
-                                    caller.data_mut().had_failure = true;
-                                    return;
-                                }
+                        // The update list is written within this batch, so index it
+                        // locally rather than reading its length back each time
+                        match db_make_list_key(&update_key, updated) {
+                            Ok(entry_key) => batch.put(&entry_key, &k_owned),
+                            Err(_) => {
+                                caller.data_mut().had_failure = true;
+                                return;
                             }
+                        }
+                        updated += 1;
+                    }
+                    let listed = if state_proofs {
+                        match Self::db_update_state_tree(
+                            context_ref.clone(),
+                            &mut batch,
+                            &update_key,
+                            updated,
+                            decoded.list.iter().tuples(),
+                            height,
+                        ) {
+                            Ok(listed) => listed,
                             Err(_) => {
                                 caller.data_mut().had_failure = true;
                                 return;
                             }
                         }
+                    } else {
+                        updated
+                    };
+                    if listed > 0 && Self::db_set_length(&mut batch, &update_key, listed).is_err() {
+                        caller.data_mut().had_failure = true;
+                        return;
                     }
 
                     debug!(
@@ -1210,6 +2511,25 @@ where
                         height
                     );
 
+                    let pairs = decoded
+                        .list
+                        .iter()
+                        .tuples()
+                        .map(|(k, v)| (k.clone(), v.clone()))
+                        .collect::<Vec<_>>();
+                    let previous = match caller.data().root {
+                        Some(root) => root,
+                        None => {
+                            caller.data_mut().had_failure = true;
+                            return;
+                        }
+                    };
+                    let root = chain_state_root(&previous, &block_digest(&pairs));
+                    batch.put(state_root_key(height), root);
+                    if let Some((start, hash)) = caller.data().wasm_hash {
+                        batch.put(wasm_hash_key(start), hash);
+                    }
+
                     match context_ref.clone().lock() {
                         Ok(mut ctx) => {
                             ctx.state = 1;
@@ -1217,6 +2537,9 @@ where
                                 caller.data_mut().had_failure = true;
                                 return;
                             }
+                            caller.data_mut().flushed = updated;
+                            caller.data_mut().root = Some(root);
+                            caller.data_mut().wasm_hash = None;
                         }
                         Err(_) => {
                             caller.data_mut().had_failure = true;
@@ -1320,6 +2643,684 @@ where
             )
             .map_err(|e| anyhow!("Failed to wrap __get_len: {:?}", e))?;
 
+        Self::setup_linker_scan(context.clone(), linker)?;
+
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{Budget, ViewErrorKind};
+    use metashrew_support::proof::{verify_proof, EMPTY};
+    use std::collections::BTreeMap;
+
+    /// An in-memory store, along with the tip height each of its batches
+    /// was committed at.
+    #[derive(Clone, Default)]
+    struct MemoryStore(Arc<Mutex<BTreeMap<Vec<u8>, Vec<u8>>>>, u32, Arc<Mutex<Vec<u32>>>);
+
+    struct MemoryBatch(Vec<(Vec<u8>, Option<Vec<u8>>)>);
+
+    impl BatchLike for MemoryBatch {
+        fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) {
+            self.0.push((key.as_ref().to_vec(), Some(value.as_ref().to_vec())));
+        }
+        fn delete<K: AsRef<[u8]>>(&mut self, key: K) {
+            self.0.push((key.as_ref().to_vec(), None));
+        }
+        fn default() -> Self {
+            MemoryBatch(vec![])
+        }
+    }
+
+    impl KeyValueStoreLike for MemoryStore {
+        type Error = std::convert::Infallible;
+        type Batch = MemoryBatch;
+        fn write(&mut self, batch: MemoryBatch) -> Result<(), Self::Error> {
+            self.2.lock().unwrap().push(self.1);
+            let mut map = self.0.lock().unwrap();
+            for (key, value) in batch.0 {
+                match value {
+                    Some(value) => map.insert(key, value),
+                    None => map.remove(&key),
+                };
+            }
+            Ok(())
+        }
+        fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+            Ok(self.0.lock().unwrap().get(key.as_ref()).cloned())
+        }
+        fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
+            self.0.lock().unwrap().remove(key.as_ref());
+            Ok(())
+        }
+        fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<(), Self::Error> {
+            self.0.lock().unwrap().insert(key.as_ref().to_vec(), value.as_ref().to_vec());
+            Ok(())
+        }
+        fn scan_prefix<K: AsRef<[u8]>>(&mut self, prefix: K) -> Result<KeyValuePairs, Self::Error> {
+            let map = self.0.lock().unwrap();
+            Ok(map
+                .range(prefix.as_ref().to_vec()..)
+                .take_while(|(key, _)| key.starts_with(prefix.as_ref()))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect())
+        }
+        fn set_height(&mut self, height: u32) {
+            self.1 = height;
+        }
+        fn snapshot(&self) -> Result<Self, Self::Error> {
+            Ok(MemoryStore(Arc::new(Mutex::new(self.0.lock().unwrap().clone())), self.1, Default::default()))
+        }
+    }
+
+    /// Writes `entries` as the append list of `key`, as `__flush` would have.
+    fn write_list(db: &mut MemoryStore, key: &[u8], entries: &[(u32, &[u8])]) {
+        let key = key.to_vec();
+        for (index, (height, value)) in entries.iter().enumerate() {
+            db.put(
+                db_make_list_key(&key, index as u32).unwrap(),
+                db_annotate_value(&value.to_vec(), *height).unwrap(),
+            )
+            .unwrap();
+        }
+        db.put(db_make_length_key(&key).unwrap(), u32_to_vec(entries.len() as u32).unwrap())
+            .unwrap();
+    }
+
+    /// An indexer whose `_start` flushes nothing, with an `ok` view returning
+    /// "ok", a `spin` view that never returns, a `grow` view that grows
+    /// memory by 100 pages, a `probe` view returning the length of the
+    /// value stored under "k" and a `scan` view counting the entries under
+    /// the prefix "k".
+    const INDEXER: &str = r#"
+        (module
+          (import "env" "__flush" (func $flush (param i32)))
+          (import "env" "__get_len" (func $get_len (param i32) (result i32)))
+          (import "env" "__scan_prefix" (func $scan (param i32) (result i32)))
+          (import "env" "__iter_next_len" (func $next_len (param i32) (result i32)))
+          (import "env" "__iter_next" (func $next (param i32 i32)))
+          (import "env" "__log" (func $log (param i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 8) "\02\00\00\00ok")
+          (data (i32.const 16) "\01\00\00\00k")
+          (data (i32.const 28) "\04\00\00\00")
+          (func (export "probe") (result i32)
+            (i32.store (i32.const 32) (call $get_len (i32.const 20)))
+            (i32.const 32))
+          (func (export "scan") (result i32) (local $handle i32) (local $len i32) (local $count i32)
+            (local.set $handle (call $scan (i32.const 20)))
+            (block $done
+              (loop $more
+                (local.set $len (call $next_len (local.get $handle)))
+                (br_if $done (i32.eqz (local.get $len)))
+                (br_if $done (i32.eq (local.get $len) (i32.const 0x7fffffff)))
+                (call $next (local.get $handle) (i32.const 1024))
+                (local.set $count (i32.add (local.get $count) (i32.const 1)))
+                (br $more)))
+            (i32.store (i32.const 32) (local.get $count))
+            (i32.const 32))
+          (func (export "_start") (call $flush (i32.const 40)))
+          (func (export "ok") (result i32) (i32.const 12))
+          (func (export "chatty") (result i32) (local $count i32)
+            (loop $more
+              (call $log (i32.const 12))
+              (local.set $count (i32.add (local.get $count) (i32.const 1)))
+              (br_if $more (i32.lt_u (local.get $count) (i32.const 40000))))
+            (i32.const 12))
+          (func (export "spin") (result i32) (loop $forever (br $forever)) (i32.const 0))
+          (func (export "grow") (result i32) (drop (memory.grow (i32.const 100))) (i32.const 12)))
+    "#;
+
+    fn load(options: RuntimeOptions) -> (tempdir::TempDir, MetashrewRuntime<MemoryStore>) {
+        let dir = tempdir::TempDir::new("metashrew-runtime").unwrap();
+        let path = dir.path().join("indexer.wat");
+        std::fs::write(&path, INDEXER).unwrap();
+        let runtime = MetashrewRuntime::load_with_options(path, MemoryStore::default(), options).unwrap();
+        (dir, runtime)
+    }
+
+    fn budget(err: ViewError) -> Option<Budget> {
+        match err.kind {
+            ViewErrorKind::BudgetExceeded(budget) => Some(budget),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_views_and_previews() {
+        let (_dir, runtime) = load(RuntimeOptions::default());
+        assert_eq!(runtime.view("ok".to_string(), &[], 0).await.unwrap(), b"ok");
+        assert_eq!(runtime.preview_async(&[], "ok".to_string(), &[], 0).await.unwrap(), b"ok");
+        let err = runtime.view("missing".to_string(), &[], 0).await.unwrap_err();
+        assert!(matches!(err.kind, ViewErrorKind::UnknownSymbol(_)));
+    }
+
+    #[tokio::test]
+    async fn runs_views_from_a_detached_handle() {
+        let (_dir, runtime) = load(RuntimeOptions::default());
+        let views = runtime.view_handle().unwrap();
+        drop(runtime);
+        assert_eq!(views.view("ok".to_string(), &[], 0).await.unwrap(), b"ok");
+        assert_eq!(views.preview_async(&[], "ok".to_string(), &[], 0).await.unwrap(), b"ok");
+    }
+
+    #[tokio::test]
+    async fn captures_view_logs_only_on_request() {
+        let (_dir, runtime) = load(RuntimeOptions::default());
+        let (result, log) = runtime.view_with_log("chatty".to_string(), &[], 0).await.unwrap();
+        assert_eq!(result, b"ok");
+        assert_eq!(log[0], "ok");
+        assert_eq!(log.last().unwrap(), "[log truncated]\n");
+        let kept: usize = log[..log.len() - 1].iter().map(String::len).sum();
+        assert!(kept <= MAX_CALL_LOG_BYTES && kept > MAX_CALL_LOG_BYTES - 2);
+
+        let (_, log) = runtime.view_with_log("ok".to_string(), &[], 0).await.unwrap();
+        assert!(log.is_empty());
+        assert_eq!(runtime.view("chatty".to_string(), &[], 0).await.unwrap(), b"ok");
+    }
+
+    #[tokio::test]
+    async fn views_read_the_snapshot_they_started_from() {
+        let (_dir, runtime) = load(RuntimeOptions::default());
+        let views = runtime.view_handle().unwrap();
+        let pinned = views.snapshot().unwrap();
+        let key = b"k".to_vec();
+        let mut db = views.db.clone();
+        db.put(db_make_list_key(&key, 0).unwrap(), db_annotate_value(&b"abc".to_vec(), 0).unwrap())
+            .unwrap();
+        db.put(db_make_length_key(&key).unwrap(), u32_to_vec(1).unwrap()).unwrap();
+        assert_eq!(pinned.view("probe".to_string(), &[], 0).await.unwrap(), 0u32.to_le_bytes());
+        assert_eq!(views.view("probe".to_string(), &[], 0).await.unwrap(), 3u32.to_le_bytes());
+    }
+
+    #[test]
+    fn reads_key_entries_and_history() {
+        let (_dir, runtime) = load(RuntimeOptions::default());
+        let views = runtime.view_handle().unwrap();
+        let key = b"k".to_vec();
+        let mut db = views.db.clone();
+        for (index, (height, value)) in [(2, b"a"), (5, b"b")].into_iter().enumerate() {
+            db.put(
+                db_make_list_key(&key, index as u32).unwrap(),
+                db_annotate_value(&value.to_vec(), height).unwrap(),
+            )
+            .unwrap();
+        }
+        db.put(db_make_length_key(&key).unwrap(), u32_to_vec(2).unwrap()).unwrap();
+
+        assert_eq!(views.entry_at(&key, 1).unwrap(), None);
+        assert_eq!(views.entry_at(&key, 3).unwrap(), Some((2, b"a".to_vec())));
+        assert_eq!(views.entry_at(&key, 9).unwrap(), Some((5, b"b".to_vec())));
+        assert_eq!(
+            views.key_history(&key).unwrap(),
+            vec![(2, b"a".to_vec()), (5, b"b".to_vec())]
+        );
+        assert_eq!(views.key_history(&b"x".to_vec()).unwrap(), vec![]);
+
+        assert_eq!(
+            views.get_key(&key, 3).unwrap(),
+            KeyResult { height: 3, updated: Some(2), value: Some("0x61".to_string()) }
+        );
+        assert_eq!(views.get_key(&key, 1).unwrap(), KeyResult { height: 1, updated: None, value: None });
+        assert_eq!(
+            views.get_key_history(&key, 4).unwrap(),
+            vec![KeyHistoryEntry { height: 2, value: "0x61".to_string() }]
+        );
+        assert_eq!(views.get_key_history(&key, 5).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn proves_flushed_keys() {
+        let dir = tempdir::TempDir::new("metashrew-runtime").unwrap();
+        let path = dir.path().join("indexer.wat");
+        // Flushes "k" = "a" and "j" = "b"
+        let writer = INDEXER
+            .replace(
+                "(memory (export \"memory\") 1)",
+                "(memory (export \"memory\") 1)\n          (data (i32.const 64) \"\\0c\\00\\00\\00\\0a\\01k\\0a\\01a\\0a\\01j\\0a\\01b\")",
+            )
+            .replace("(call $flush (i32.const 40))", "(call $flush (i32.const 68))");
+        std::fs::write(&path, writer).unwrap();
+        let options = RuntimeOptions {
+            state_proofs: true,
+            ..Default::default()
+        };
+        let mut runtime = MetashrewRuntime::load_with_options(path.clone(), MemoryStore::default(), options).unwrap();
+        for height in [0, 1] {
+            runtime.context.lock().unwrap().height = height;
+            runtime.run().unwrap();
+        }
+
+        let views = runtime.view_handle().unwrap();
+        let found = views.prove(&b"k".to_vec(), 1).unwrap();
+        assert_ne!(found.root, EMPTY);
+        assert_eq!(found.value, Some(b"a".to_vec()));
+        assert!(verify_proof(&found.root, b"k", Some(b"a"), &found.proof));
+        let missing = views.prove(&b"x".to_vec(), 1).unwrap();
+        assert_eq!(missing.value, None);
+        assert!(verify_proof(&missing.root, b"x", None, &missing.proof));
+        assert_eq!(views.prove(&b"j".to_vec(), 0).unwrap().root, found.root);
+
+        let mut plain = MetashrewRuntime::load_with_options(path, MemoryStore::default(), RuntimeOptions::default()).unwrap();
+        plain.run().unwrap();
+        assert_eq!(plain.view_handle().unwrap().prove(&b"k".to_vec(), 0).unwrap().root, EMPTY);
+    }
+
+    #[test]
+    fn rolls_back_every_flush_of_a_block() {
+        let dir = tempdir::TempDir::new("metashrew-runtime").unwrap();
+        let path = dir.path().join("indexer.wat");
+        // Flushes "k" = "a" and "j" = "b", then "m" = "c"
+        let writer = INDEXER
+            .replace(
+                "(memory (export \"memory\") 1)",
+                "(memory (export \"memory\") 1)\n          (data (i32.const 64) \"\\0c\\00\\00\\00\\0a\\01k\\0a\\01a\\0a\\01j\\0a\\01b\")\n          (data (i32.const 128) \"\\06\\00\\00\\00\\0a\\01m\\0a\\01c\")",
+            )
+            .replace(
+                "(call $flush (i32.const 40))",
+                "(call $flush (i32.const 68)) (call $flush (i32.const 132))",
+            );
+        std::fs::write(&path, writer).unwrap();
+        let options = RuntimeOptions {
+            state_proofs: true,
+            ..Default::default()
+        };
+        let mut runtime = MetashrewRuntime::load_with_options(path, MemoryStore::default(), options).unwrap();
+        for height in [0, 1] {
+            runtime.context.lock().unwrap().height = height;
+            runtime.run().unwrap();
+        }
+        let context = runtime.context.clone();
+        let updated = MetashrewRuntime::db_updated_keys_for_block(context.clone(), 1).unwrap();
+        for key in [b"k", b"j", b"m"] {
+            assert!(updated.contains(key.as_slice()));
+        }
+        assert!(updated.iter().any(|key| key.starts_with(crate::proof::STATE_TREE_PREFIX)));
+        let views = runtime.view_handle().unwrap();
+        let root = views.prove(&b"m".to_vec(), 0).unwrap().root;
+
+        // Reprocessing block 1 rolls back what both of its flushes wrote
+        runtime.handle_reorg().unwrap();
+        for key in &updated {
+            let entry = MetashrewRuntime::db_entry_at_block(context.clone(), key, u32::MAX - 1).unwrap();
+            assert_eq!(entry.map(|(height, _)| height), Some(0), "{:?}", key);
+        }
+        let proof = views.prove(&b"m".to_vec(), 1).unwrap();
+        assert_eq!(proof.root, root);
+        assert!(verify_proof(&root, b"m", Some(b"c"), &proof.proof));
+    }
+
+    #[tokio::test]
+    async fn scans_visible_keys_under_a_prefix() {
+        let (_dir, runtime) = load(RuntimeOptions::default());
+        let views = runtime.view_handle().unwrap();
+        let mut db = views.db.clone();
+        write_list(&mut db, b"k", &[(0, b"a"), (3, b"b")]);
+        // Nested under "k", so its entries sort among those of "k"
+        write_list(&mut db, b"k/a", &[(0, b"c")]);
+        write_list(&mut db, b"ka", &[(0, b"")]);
+        write_list(&mut db, b"kb", &[(5, b"d")]);
+        write_list(&mut db, b"j", &[(0, b"e")]);
+        assert_eq!(views.view("scan".to_string(), &[], 0).await.unwrap(), 2u32.to_le_bytes());
+        assert_eq!(views.view("scan".to_string(), &[], 5).await.unwrap(), 3u32.to_le_bytes());
+    }
+
+    #[test]
+    fn finds_the_entry_at_a_height_by_binary_search() {
+        let mut db = MemoryStore::default();
+        write_list(&mut db, b"k", &[(2, b"a"), (4, b"b"), (4, b"c"), (7, b"d"), (9, b"e")]);
+        write_list(&mut db, b"empty", &[]);
+        let context = Arc::new(Mutex::new(MetashrewRuntimeContext::new(db, 0, vec![])));
+        let at = |key: &[u8], height: u32| {
+            MetashrewRuntime::db_entry_at_block(context.clone(), &key.to_vec(), height).unwrap()
+        };
+        assert_eq!(at(b"k", 0), None);
+        assert_eq!(at(b"k", 1), None);
+        assert_eq!(at(b"k", 2), Some((2, b"a".to_vec())));
+        assert_eq!(at(b"k", 3), Some((2, b"a".to_vec())));
+        // The last write at a height wins
+        assert_eq!(at(b"k", 4), Some((4, b"c".to_vec())));
+        assert_eq!(at(b"k", 6), Some((4, b"c".to_vec())));
+        assert_eq!(at(b"k", 8), Some((7, b"d".to_vec())));
+        assert_eq!(at(b"k", 9), Some((9, b"e".to_vec())));
+        assert_eq!(at(b"k", u32::MAX - 1), Some((9, b"e".to_vec())));
+        assert_eq!(at(b"empty", 5), None);
+        assert_eq!(at(b"missing", 5), None);
+    }
+
+    #[test]
+    fn commits_a_reorg_rollback_with_the_tip_in_one_batch() {
+        let (_dir, mut runtime) = load(RuntimeOptions::default());
+        let mut db = runtime.context.lock().unwrap().db.clone();
+        write_list(&mut db, b"k", &[(1, b"a"), (3, b"b"), (4, b"c")]);
+        write_list(&mut db, b"new", &[(4, b"d")]);
+        for (height, keys) in [(3, vec![&b"k"[..]]), (4, vec![&b"k"[..], &b"new"[..]])] {
+            let updated = u32_to_vec(height).unwrap();
+            for (index, key) in keys.iter().enumerate() {
+                db.put(db_make_list_key(&updated, index as u32).unwrap(), key).unwrap();
+            }
+            db.put(db_make_length_key(&updated).unwrap(), u32_to_vec(keys.len() as u32).unwrap())
+                .unwrap();
+        }
+        runtime.context.lock().unwrap().height = 3;
+
+        runtime.handle_reorg().unwrap();
+
+        // A single batch, committed with the tip still below block 3
+        assert_eq!(*db.2.lock().unwrap(), vec![2]);
+        let context = runtime.context.clone();
+        assert_eq!(
+            MetashrewRuntime::db_entry_at_block(context.clone(), &b"k".to_vec(), 9).unwrap(),
+            Some((1, b"a".to_vec()))
+        );
+        let length = |key: &[u8]| db.clone().get(db_make_length_key(&key.to_vec()).unwrap()).unwrap();
+        assert_eq!(length(b"k"), Some(u32_to_vec(1).unwrap()));
+        assert_eq!(length(b"new"), None);
+        assert!(db.clone().get(db_make_list_key(&b"k".to_vec(), 1).unwrap()).unwrap().is_none());
+        for height in [3, 4] {
+            assert_eq!(length(&u32_to_vec(height).unwrap()), None);
+        }
+        assert_eq!(MetashrewRuntime::check_latest_block_for_reorg(context, 3).unwrap(), 3);
+    }
+
+    #[test]
+    fn pages_through_keys_under_a_prefix() {
+        let mut db = MemoryStore::default();
+        write_list(&mut db, b"k", &[(0, b"a"), (1, b"b"), (2, b"c")]);
+        write_list(&mut db, b"k/a", &[(0, b"d")]);
+        write_list(&mut db, b"kb", &[(0, b"e"), (1, b"f")]);
+        write_list(&mut db, b"l", &[(0, b"g")]);
+        let context = Arc::new(Mutex::new(MetashrewRuntimeContext::new(db, 0, vec![])));
+        let prefix = b"k".to_vec();
+        let (all, next) =
+            MetashrewRuntime::db_keys_with_prefix(context.clone(), &prefix, &prefix, usize::MAX).unwrap();
+        assert_eq!(all, vec![b"k".to_vec(), b"k/a".to_vec(), b"kb".to_vec()]);
+        assert_eq!(next, None);
+
+        let mut paged = vec![];
+        let mut start = Some(prefix.clone());
+        let mut pages = 0;
+        while let Some(from) = start {
+            let (keys, next) = MetashrewRuntime::db_keys_with_prefix(context.clone(), &prefix, &from, 2).unwrap();
+            paged.extend(keys);
+            start = next;
+            pages += 1;
+        }
+        assert_eq!(paged, all);
+        // Nine raw entries under "k", two per page
+        assert_eq!(pages, 5);
+    }
+
+    #[test]
+    fn previews_scan_the_overlay_with_the_db() {
+        let mut db = MemoryStore::default();
+        for key in [b"a1", b"a3", b"a5", b"b1"] {
+            db.put(key, b"db").unwrap();
+        }
+        let mut preview = PreviewDBWrapper {
+            underlying_db: db,
+            overlay: [(b"a2".to_vec(), b"overlay".to_vec()), (b"a3".to_vec(), b"overlay".to_vec())]
+                .into_iter()
+                .collect(),
+        };
+        let scanned = preview.scan_prefix(b"a").unwrap();
+        assert_eq!(
+            scanned,
+            vec![
+                (b"a1".to_vec(), b"db".to_vec()),
+                (b"a2".to_vec(), b"overlay".to_vec()),
+                (b"a3".to_vec(), b"overlay".to_vec()),
+                (b"a5".to_vec(), b"db".to_vec()),
+            ]
+        );
+        assert_eq!(preview.scan_prefix_from(&b"a"[..], &b"a2"[..], 2).unwrap(), scanned[1..3].to_vec());
+        assert_eq!(preview.scan_prefix_from(&b"a"[..], &b"a4"[..], 5).unwrap(), scanned[3..].to_vec());
+    }
+
+    #[tokio::test]
+    async fn switches_indexers_at_activation_height() {
+        let dir = tempdir::TempDir::new("metashrew-runtime").unwrap();
+        let indexer = dir.path().join("indexer.wat");
+        let upgraded = dir.path().join("upgraded.wat");
+        std::fs::write(&indexer, INDEXER).unwrap();
+        std::fs::write(&upgraded, INDEXER.replace("00ok\"", "00up\"")).unwrap();
+        let upgrade: Upgrade = format!("5:{}", upgraded.display()).parse().unwrap();
+        assert_eq!(upgrade.height, 5);
+        let store = MemoryStore::default();
+        let mut runtime = MetashrewRuntime::load_with_options(
+            indexer,
+            store.clone(),
+            RuntimeOptions {
+                upgrades: vec![upgrade],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        for height in [4, 5] {
+            runtime.context.lock().unwrap().height = height;
+            runtime.run().unwrap();
+        }
+        assert_eq!(runtime.active, Some(1));
+        let hash = |height| store.clone().get(wasm_hash_key(height)).unwrap().unwrap();
+        assert_eq!(hash(0), runtime.schedule[0].hash);
+        assert_eq!(hash(5), runtime.schedule[1].hash);
+        assert_ne!(hash(0), hash(5));
+
+        let views = runtime.view_handle().unwrap();
+        assert_eq!(views.view("ok".to_string(), &[], 4).await.unwrap(), b"ok");
+        assert_eq!(views.view("ok".to_string(), &[], 5).await.unwrap(), b"up");
+    }
+
+    #[test]
+    fn refuses_mismatched_wasm() {
+        let dir = tempdir::TempDir::new("metashrew-runtime").unwrap();
+        let indexer = dir.path().join("indexer.wat");
+        let other = dir.path().join("other.wat");
+        std::fs::write(&indexer, INDEXER).unwrap();
+        std::fs::write(&other, INDEXER.replace("00ok\"", "00up\"")).unwrap();
+        let store = MemoryStore::default();
+        let mut runtime = MetashrewRuntime::load(indexer.clone(), store.clone()).unwrap();
+        runtime.verify_wasm(false).unwrap();
+        runtime.run().unwrap();
+        runtime.verify_wasm(false).unwrap();
+
+        let mut runtime = MetashrewRuntime::load(other.clone(), store.clone()).unwrap();
+        let err = runtime.verify_wasm(false).unwrap_err();
+        assert!(err.to_string().contains("--allow-wasm-mismatch"), "{}", err);
+        runtime.verify_wasm(true).unwrap();
+
+        let expected = schedule_hashes(&indexer, &[]).unwrap();
+        assert!(wasm_mismatches(&mut store.clone(), &expected).unwrap().is_empty());
+        let upgrades = [Upgrade { height: 5, indexer: other.clone() }];
+        let expected = schedule_hashes(&indexer, &upgrades).unwrap();
+        assert!(wasm_mismatches(&mut store.clone(), &expected).unwrap().is_empty());
+
+        // Resuming with other WASM starts a new range instead of rewriting
+        // the one block 0 was indexed in
+        runtime.context.lock().unwrap().height = 1;
+        runtime.run().unwrap();
+        let recorded = recorded_wasm(&mut store.clone()).unwrap();
+        assert_eq!(recorded, vec![(0, expected[0].1), (1, expected[1].1)]);
+        assert!(runtime.verify_wasm(false).is_err());
+        let upgrades = [Upgrade { height: 1, indexer: other.clone() }];
+        let expected = schedule_hashes(&indexer, &upgrades).unwrap();
+        assert!(wasm_mismatches(&mut store.clone(), &expected).unwrap().is_empty());
+        let upgrades = [Upgrade { height: 2, indexer: other }];
+        let expected = schedule_hashes(&indexer, &upgrades).unwrap();
+        assert_eq!(wasm_mismatches(&mut store.clone(), &expected).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn records_the_wasm_hash_with_the_first_committed_block() {
+        let dir = tempdir::TempDir::new("metashrew-runtime").unwrap();
+        let indexer = dir.path().join("indexer.wat");
+        std::fs::write(&indexer, INDEXER.replace("(call $flush (i32.const 40))", "(unreachable)")).unwrap();
+        let store = MemoryStore::default();
+        let mut runtime = MetashrewRuntime::load(indexer, store.clone()).unwrap();
+        assert!(runtime.run().is_err());
+        assert!(recorded_wasm(&mut store.clone()).unwrap().is_empty());
+        runtime.refresh_memory().unwrap();
+        assert_eq!(runtime.wasmstore.data().wasm_hash, Some((0, runtime.schedule[0].hash)));
+    }
+
+    #[test]
+    fn chains_state_roots_across_blocks() {
+        let (dir, mut runtime) = load(RuntimeOptions::default());
+        let mut store = runtime.context.lock().unwrap().db.clone();
+        for height in 0..2 {
+            runtime.context.lock().unwrap().height = height;
+            runtime.run().unwrap();
+        }
+        let empty = block_digest(&[]);
+        let first = chain_state_root(&[0; 32], &empty);
+        assert_eq!(state_root(&mut store, 0).unwrap(), Some(first));
+        assert_eq!(state_root(&mut store, 1).unwrap(), Some(chain_state_root(&first, &empty)));
+        assert_eq!(state_root(&mut store, 2).unwrap(), None);
+
+        let pair = |key: &[u8], value: &[u8]| (key.to_vec(), value.to_vec());
+        assert_eq!(
+            block_digest(&[pair(b"a", b"1"), pair(b"b", b"2")]),
+            block_digest(&[pair(b"b", b"2"), pair(b"a", b"1")])
+        );
+        assert_ne!(block_digest(&[pair(b"ab", b"c")]), block_digest(&[pair(b"a", b"bc")]));
+
+        // A block whose flush fails keeps the root before it
+        let failing = dir.path().join("failing.wat");
+        std::fs::write(&failing, INDEXER.replace("(call $flush (i32.const 40))", "(call $flush (i32.const 0))"))
+            .unwrap();
+        let mut broken = MetashrewRuntime::load(failing, store.clone()).unwrap();
+        broken.context.lock().unwrap().height = 2;
+        broken.run().unwrap();
+        let second = state_root(&mut store, 1).unwrap();
+        assert_eq!(state_root(&mut store, 2).unwrap(), second);
+
+        // Reprocessing a block drops the roots from it up
+        runtime.context.lock().unwrap().height = 1;
+        runtime.handle_reorg().unwrap();
+        assert_eq!(state_root(&mut store, 0).unwrap(), Some(first));
+        assert_eq!(state_root(&mut store, 1).unwrap(), None);
+        assert_eq!(state_root(&mut store, 2).unwrap(), None);
+
+        // Once the chain has started, a gap in it is an error
+        runtime.context.lock().unwrap().height = 3;
+        assert!(runtime.run().is_err());
+        assert_eq!(state_root(&mut store, 3).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn stops_calls_over_budget() {
+        let limits = CallLimits {
+            fuel: Some(100_000),
+            ..Default::default()
+        };
+        let (_dir, runtime) = load(RuntimeOptions {
+            view: limits.clone(),
+            preview: limits,
+            ..Default::default()
+        });
+        let err = runtime.view("spin".to_string(), &[], 0).await.unwrap_err();
+        assert_eq!(budget(err), Some(Budget::Fuel));
+        let err = runtime.preview_async(&[], "spin".to_string(), &[], 0).await.unwrap_err();
+        assert_eq!(budget(err), Some(Budget::Fuel));
+
+        let (_dir, runtime) = load(RuntimeOptions {
+            view: CallLimits {
+                timeout: Some(Duration::from_millis(50)),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let started = Instant::now();
+        let err = runtime.view("spin".to_string(), &[], 0).await.unwrap_err();
+        assert_eq!(budget(err), Some(Budget::Time));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(runtime.view("ok".to_string(), &[], 0).await.unwrap(), b"ok");
+    }
+
+    #[test]
+    fn stops_the_epoch_ticker_with_the_last_holder() {
+        let (_dir, runtime) = load(RuntimeOptions {
+            view: CallLimits {
+                timeout: Some(Duration::from_millis(50)),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let stop = runtime.epoch_ticker.as_ref().unwrap().stop.clone();
+        let views = runtime.view_handle().unwrap();
+        drop(runtime);
+        assert!(!stop.load(Ordering::Relaxed));
+        drop(views);
+        assert!(stop.load(Ordering::Relaxed));
+        // The thread drops its flag as it exits
+        let started = Instant::now();
+        while Arc::strong_count(&stop) > 1 {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(EPOCH_TICK);
+        }
+        assert!(load(RuntimeOptions::default()).1.epoch_ticker.is_none());
+    }
+
+    #[tokio::test]
+    async fn caps_view_memory() {
+        let (_dir, runtime) = load(RuntimeOptions {
+            view: CallLimits {
+                max_memory: Some(1024 * 1024),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let err = runtime.view("grow".to_string(), &[], 0).await.unwrap_err();
+        assert!(matches!(err.kind, ViewErrorKind::MemoryLimit(_)), "{}", err);
+        assert_eq!(err.code(), -32003);
+
+        let (_dir, runtime) = load(RuntimeOptions::default());
+        assert_eq!(runtime.view("grow".to_string(), &[], 0).await.unwrap(), b"ok");
+    }
+
+    #[tokio::test]
+    async fn reuses_pooled_instances() {
+        let (_dir, runtime) = load(RuntimeOptions {
+            view: CallLimits {
+                max_memory: Some(1024 * 1024),
+                ..Default::default()
+            },
+            instance_pool: Some(2),
+            ..Default::default()
+        });
+        for _ in 0..4 {
+            assert_eq!(runtime.view("ok".to_string(), &[], 0).await.unwrap(), b"ok");
+            assert_eq!(runtime.preview_async(&[], "ok".to_string(), &[], 0).await.unwrap(), b"ok");
+        }
+        let err = runtime.view("grow".to_string(), &[], 0).await.unwrap_err();
+        assert!(matches!(err.kind, ViewErrorKind::MemoryLimit(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn caches_compiled_modules() {
+        let cache = tempdir::TempDir::new("metashrew-module-cache").unwrap();
+        let options = RuntimeOptions {
+            module_cache: Some(cache.path().join("modules")),
+            ..Default::default()
+        };
+        let (_dir, runtime) = load(options.clone());
+        drop(runtime);
+        let cached = || {
+            std::fs::read_dir(cache.path().join("modules"))
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect::<HashSet<_>>()
+        };
+        let written = cached();
+        // One artifact each for the block and the view engine
+        assert_eq!(written.len(), 2);
+        assert!(written.iter().all(|path| path.extension().unwrap() == "cwasm"));
+
+        let (_dir, runtime) = load(options);
+        assert_eq!(cached(), written);
+        assert_eq!(runtime.view("ok".to_string(), &[], 0).await.unwrap(), b"ok");
+    }
+}